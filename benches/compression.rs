@@ -0,0 +1,68 @@
+//! Size and load time of a compiled script for every compression.
+//!
+//! ```bash
+//! cargo bench --bench compression --features brotli,snappy
+//! ```
+//!
+//! Load time is the time to read, verify and decompress the binary, not to evaluate it.
+
+use std::time::{Duration, Instant};
+
+use shards_embed::{BinaryInfo, BuildOptions, Compression};
+
+const ITERATIONS: u32 = 200;
+
+fn main() {
+    shards_embed::init();
+
+    let dir = std::env::temp_dir().join(format!("shards-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("create the bench directory");
+
+    // A script large enough for the compression to matter
+    let mut source = String::from("@wire(main {\n");
+    for i in 0..2000 {
+        source.push_str(&format!(
+            "  \"line {} of a fairly repetitive script\" | Log\n",
+            i
+        ));
+    }
+    source.push_str("})\n");
+    let script = dir.join("bench.shs");
+    std::fs::write(&script, source).expect("write the bench script");
+
+    println!("{:<8} {:>12} {:>8} {:>12}", "", "size", "ratio", "load");
+    let mut uncompressed = 0;
+    for compression in [Compression::None, Compression::Brotli, Compression::Snappy] {
+        let output = dir.join(format!("bench-{}.shb", compression));
+        let options = BuildOptions::new().compression(compression);
+        let info = match shards_embed::binary::build(&script, &output, &options) {
+            Ok(info) => info,
+            Err(e) => {
+                eprintln!("{}: {}", compression, e);
+                continue;
+            }
+        };
+        let size = std::fs::metadata(&output).map(|m| m.len()).unwrap_or(0);
+        if compression == Compression::None {
+            uncompressed = size;
+        }
+
+        let mut total = Duration::ZERO;
+        for _ in 0..ITERATIONS {
+            let start = Instant::now();
+            let read = BinaryInfo::read_unchecked(&output).expect("read the binary");
+            total += start.elapsed();
+            assert_eq!(read.content_hash, info.content_hash);
+        }
+
+        println!(
+            "{:<8} {:>12} {:>7.2}x {:>12.1?}",
+            compression.to_string(),
+            format!("{} bytes", size),
+            uncompressed as f64 / size.max(1) as f64,
+            total / ITERATIONS
+        );
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -1,8 +1,226 @@
 use cmake::Config;
+use sha2::{Digest, Sha256};
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+#[path = "build/manifest.rs"]
+mod manifest;
+
+use manifest::{declared_features, pinned_revision};
+
+// Bump when the layout of cached builds changes
+const CACHE_VERSION: u32 = 1;
+
+// Core submodules needed for CMake build
+const SUBMODULES: &[&str] = &[
+    "deps/stb",
+    "deps/json",
+    "deps/magic_enum",
+    "deps/cpp-taskflow",
+    "deps/nameof",
+    "deps/pdqsort",
+    "deps/filesystem",
+    "deps/xxHash",
+    "deps/linalg",
+    "deps/spdlog",
+    "deps/brotli",
+    "deps/tracy",
+    "deps/oneTBB",
+    "deps/crdt-lite",
+    "deps/utf8.h",
+    "deps/entt",
+    "deps/kcp",
+    "deps/tinygltf",
+    "deps/draco",
+    "deps/sqlite/cr-sqlite",
+    "deps/miniaudio",
+    "deps/kissfft",
+    "deps/snappy",
+];
+
+// Static libraries every build links, after the main C++ union (must come first)
+const CORE_LIBS: &[&str] = &[
+    "shards-cpp-union",
+    // Core shards libraries
+    "shards-core",
+    "shards-logging",
+    "shards-fast-string",
+    // Boost libraries
+    "boost_filesystem",
+    "boost_container",
+    "boost_context",
+    "boost_thread",
+    "boost_atomic",
+    "boost_chrono",
+    "boost_date_time",
+    "boost_random",
+];
+
+// Core modules (always enabled)
+const ALWAYS_ON: &[&str] = &["SHARDS_WITH_CORE", "SHARDS_WITH_LANGFFI", "SHARDS_WITH_RUN"];
+
+// Disabled modules (no feature flags for these)
+const ALWAYS_OFF: &[&str] = &[
+    "SHARDS_WITH_CLIPBOARD",
+    "SHARDS_WITH_DEBUGGER",
+    "SHARDS_WITH_DESKTOP",
+    "SHARDS_WITH_GFX",
+    "SHARDS_WITH_INPUTS",
+    "SHARDS_WITH_PHYSICS",
+    "SHARDS_WITH_WASM",
+    "SHARDS_WITH_EGUI",
+    // SSH feature disabled - requires OPENSSL_DIR env vars to be set
+    "SHARDS_WITH_SSH",
+    // Python shards, no shards-py crate to build them with
+    "SHARDS_WITH_PY",
+    "ENABLE_PYTHON_SHARDS",
+    "ENABLE_RUSTPYTHON_EMBEDDED",
+];
+
+/// A shards module enabled by a cargo feature.
+struct Module {
+    /// Cargo feature, must be declared in Cargo.toml.
+    feature: &'static str,
+    /// CMake options turned ON with the feature, OFF without.
+    cmake: &'static [&'static str],
+    /// Rust crate of the module, the feature must enable it (`dep:`) and lib.rs re-exports it
+    /// under `cfg(shards_module = "<feature>")`.
+    rust_crate: Option<&'static str>,
+    /// Static libraries built by CMake to link.
+    libs: &'static [&'static str],
+    /// Directories of `libs` outside the usual ones, relative to the CMake build directory.
+    lib_dirs: &'static [&'static str],
+    /// Extra libraries to link on Apple platforms.
+    apple_libs: &'static [&'static str],
+    /// Extra linker arguments on Apple platforms.
+    apple_link_args: &'static [&'static str],
+    /// Ninja targets not built by `shards-cpp-union`, built after it.
+    cmake_targets: &'static [&'static str],
+    /// Appended to the profile directory corrosion builds the Rust libraries of CMake into.
+    corrosion_profile_suffix: Option<&'static str>,
+}
+
+impl Module {
+    const fn new(feature: &'static str, cmake: &'static [&'static str]) -> Self {
+        Module {
+            feature,
+            cmake,
+            rust_crate: None,
+            libs: &[],
+            lib_dirs: &[],
+            apple_libs: &[],
+            apple_link_args: &[],
+            cmake_targets: &[],
+            corrosion_profile_suffix: None,
+        }
+    }
+
+    const fn rust_crate(self, rust_crate: &'static str) -> Self {
+        Module {
+            rust_crate: Some(rust_crate),
+            ..self
+        }
+    }
+
+    const fn libs(self, libs: &'static [&'static str]) -> Self {
+        Module { libs, ..self }
+    }
+
+    const fn lib_dirs(self, lib_dirs: &'static [&'static str]) -> Self {
+        Module { lib_dirs, ..self }
+    }
+
+    const fn apple_libs(self, apple_libs: &'static [&'static str]) -> Self {
+        Module { apple_libs, ..self }
+    }
+
+    const fn apple_link_args(self, apple_link_args: &'static [&'static str]) -> Self {
+        Module {
+            apple_link_args,
+            ..self
+        }
+    }
+
+    const fn cmake_targets(self, cmake_targets: &'static [&'static str]) -> Self {
+        Module {
+            cmake_targets,
+            ..self
+        }
+    }
+
+    const fn corrosion_profile_suffix(self, suffix: &'static str) -> Self {
+        Module {
+            corrosion_profile_suffix: Some(suffix),
+            ..self
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        feature_enabled(self.feature)
+    }
+}
+
+// Every feature-gated module, in link order of their libraries
+const MODULES: &[Module] = &[
+    // Core modules (built into C++ union)
+    Module::new("anim", &["SHARDS_WITH_ANIM"]),
+    Module::new("assert", &["SHARDS_WITH_ASSERT"]),
+    Module::new("bigint", &["SHARDS_WITH_BIGINT"]),
+    Module::new("channels", &["SHARDS_WITH_CHANNELS"]),
+    Module::new("crdts", &["SHARDS_WITH_CRDTS"]),
+    Module::new("debug", &["SHARDS_WITH_DEBUG"]),
+    Module::new("fileops", &["SHARDS_WITH_FILEOPS"]),
+    Module::new("geo", &["SHARDS_WITH_GEO"]).rust_crate("shards-geo"),
+    Module::new("json", &["SHARDS_WITH_JSON"]),
+    Module::new("os", &["SHARDS_WITH_OS"]),
+    Module::new("reflection", &["SHARDS_WITH_REFLECTION"]),
+    Module::new("struct", &["SHARDS_WITH_STRUCT"]),
+    Module::new("network", &["SHARDS_WITH_NETWORK"])
+        .rust_crate("shards-network")
+        .libs(&["kcp"]),
+    // Compression libraries
+    Module::new("brotli", &["SHARDS_WITH_BROTLI"]).libs(&["brotlicommon", "brotlidec", "brotlienc"]),
+    Module::new("snappy", &["SHARDS_WITH_SNAPPY"]).libs(&["snappy"]),
+    // crsql_bundle-rust (SQLite CRDT support) is not part of the union
+    Module::new("sqlite", &["SHARDS_WITH_SQLITE"])
+        .libs(&["sqlite-static", "sqlite-vec", "crsql_bundle"])
+        .cmake_targets(&["cargo-crsql_bundle-rust"]),
+    Module::new("audio", &["SHARDS_WITH_AUDIO"])
+        .libs(&["opus", "kissfft-float"])
+        .lib_dirs(&["deps/kissfft_a/src/kissfft_a-build"]),
+    Module::new("imaging", &["SHARDS_WITH_IMAGING"])
+        .libs(&["jpeg"])
+        .lib_dirs(&["deps/mozjpeg_a/src/mozjpeg_a-build"]),
+    // ML/LLM (llama.cpp and ggml). ___isPlatformVersionAtLeast is a weak symbol used by
+    // Metal, allowed to be undefined and resolved at runtime
+    Module::new("ml", &["SHARDS_WITH_ML", "SHARDS_WITH_LLM"])
+        .rust_crate("shards-ml")
+        .libs(&["llama", "ggml", "ggml-base", "ggml-cpu", "ggml-blas"])
+        .apple_libs(&["ggml-metal"])
+        .apple_link_args(&["-Wl,-U,___isPlatformVersionAtLeast"]),
+    // OpenSSL/LibreSSL (needed by HTTP module via boost::beast)
+    Module::new("http", &["SHARDS_WITH_HTTP"])
+        .rust_crate("shards-http")
+        .libs(&["ssl", "crypto"]),
+    // External modules (Rust crates)
+    Module::new("core", &[]).rust_crate("shards-core"),
+    Module::new("crypto", &["SHARDS_WITH_CRYPTO"]).rust_crate("shards-crypto"),
+    Module::new("csv", &["SHARDS_WITH_CSV"]).rust_crate("shards-csv"),
+    Module::new("fs", &["SHARDS_WITH_FS"]).rust_crate("shards-fs"),
+    Module::new("pdf", &["SHARDS_WITH_PDF"]).rust_crate("shards-pdf"),
+    Module::new("svg", &["SHARDS_WITH_SVG"]).rust_crate("shards-svg"),
+    Module::new("random", &["SHARDS_WITH_RANDOM"]).rust_crate("shards-random"),
+    Module::new("markdown", &["SHARDS_WITH_MARKDOWN"]).rust_crate("shards-markdown"),
+    Module::new("localshell", &["SHARDS_WITH_LOCALSHELL"]).rust_crate("shards-localshell"),
+    Module::new("langffi", &[]).rust_crate("shards-langffi"),
+    // Tracy zones in the C++ core, the client itself is linked from tracy-client-sys, there is
+    // no C++ client library. Zones only reach the profiler if the Tracy release vendored by
+    // tracy-client-sys matches the headers of the core, which nothing checks: the version is
+    // pinned in Cargo.toml by hand, keep it in sync when bumping the shards revision
+    Module::new("tracy", &["TRACY_ENABLE", "SHARDS_WITH_TRACY"]).corrosion_profile_suffix("-tracy"),
+];
+
 fn main() {
     let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
     let target_vendor = env::var("CARGO_CFG_TARGET_VENDOR").unwrap_or_default();
@@ -10,23 +228,30 @@ fn main() {
     let is_apple = target_vendor == "apple";
 
     // Find shards source - either local or Cargo-cached
-    let shards_dir = find_shards_source();
+    // The C++ core must match the shards crates Cargo builds
+    let (manifest_path, manifest) = read_manifest();
+    let revision = pinned_revision(&manifest_path, &manifest);
+    let shards_dir = find_shards_source(&revision);
 
-    let mut config = Config::new(&shards_dir);
+    // For `shards-embed vendor`
+    let source_dir = Path::new(&shards_dir).canonicalize().unwrap_or_else(|_| shards_dir.clone().into());
+    println!("cargo:rustc-env=SHARDS_EMBED_SOURCE_DIR={}", source_dir.display());
+    println!("cargo:rustc-env=SHARDS_EMBED_REVISION={}", revision);
+    println!("cargo:rustc-env=SHARDS_EMBED_SUBMODULES={}", SUBMODULES.join(","));
 
-    // Use Ninja generator (required for Swift support)
-    config.generator("Ninja");
+    // CMake options, they are also part of the key of cached builds
+    let mut defines: Vec<(&str, &str)> = Vec::new();
 
     // We ARE the Rust union, so skip CMake's Rust build
-    config.define("SHARDS_NO_RUST_UNION", "ON");
+    defines.push(("SHARDS_NO_RUST_UNION", "ON"));
 
     // We don't need gfx and sdl things
-    config.define("SHARDS_NO_SDL", "ON");
+    defines.push(("SHARDS_NO_SDL", "ON"));
 
     // On Windows, force release CRT (/MD) even in debug builds
     // Rust always uses release CRT, so C++ code must match
     if target_os == "windows" {
-        config.define("CMAKE_MSVC_RUNTIME_LIBRARY", "MultiThreadedDLL");
+        defines.push(("CMAKE_MSVC_RUNTIME_LIBRARY", "MultiThreadedDLL"));
     }
 
     // Set build type based on Cargo profile
@@ -35,395 +260,96 @@ fn main() {
     } else {
         "Debug"
     };
-    config.define("CMAKE_BUILD_TYPE", cmake_build_type);
-
-    // Map Cargo features to CMake options
-    config.define("SHARDS_WITH_EVERYTHING", "OFF");
-    config.define("SHARDS_WITH_DEFAULT", "OFF");
-
-    // Core modules (always enabled)
-    config.define("SHARDS_WITH_CORE", "ON");
-    config.define("SHARDS_WITH_LANGFFI", "ON");
-    config.define("SHARDS_WITH_RUN", "ON");
-
-    // Feature-gated core modules
-    if cfg!(feature = "anim") {
-        config.define("SHARDS_WITH_ANIM", "ON");
-    } else {
-        config.define("SHARDS_WITH_ANIM", "OFF");
-    }
-    if cfg!(feature = "assert") {
-        config.define("SHARDS_WITH_ASSERT", "ON");
-    } else {
-        config.define("SHARDS_WITH_ASSERT", "OFF");
-    }
-    if cfg!(feature = "audio") {
-        config.define("SHARDS_WITH_AUDIO", "ON");
-    } else {
-        config.define("SHARDS_WITH_AUDIO", "OFF");
-    }
-    if cfg!(feature = "bigint") {
-        config.define("SHARDS_WITH_BIGINT", "ON");
-    } else {
-        config.define("SHARDS_WITH_BIGINT", "OFF");
-    }
-    if cfg!(feature = "brotli") {
-        config.define("SHARDS_WITH_BROTLI", "ON");
-    } else {
-        config.define("SHARDS_WITH_BROTLI", "OFF");
-    }
-    if cfg!(feature = "channels") {
-        config.define("SHARDS_WITH_CHANNELS", "ON");
-    } else {
-        config.define("SHARDS_WITH_CHANNELS", "OFF");
-    }
-    if cfg!(feature = "crdts") {
-        config.define("SHARDS_WITH_CRDTS", "ON");
-    } else {
-        config.define("SHARDS_WITH_CRDTS", "OFF");
-    }
-    if cfg!(feature = "debug") {
-        config.define("SHARDS_WITH_DEBUG", "ON");
-    } else {
-        config.define("SHARDS_WITH_DEBUG", "OFF");
-    }
-    if cfg!(feature = "fileops") {
-        config.define("SHARDS_WITH_FILEOPS", "ON");
-    } else {
-        config.define("SHARDS_WITH_FILEOPS", "OFF");
-    }
-    if cfg!(feature = "geo") {
-        config.define("SHARDS_WITH_GEO", "ON");
-    } else {
-        config.define("SHARDS_WITH_GEO", "OFF");
-    }
-    if cfg!(feature = "imaging") {
-        config.define("SHARDS_WITH_IMAGING", "ON");
-    } else {
-        config.define("SHARDS_WITH_IMAGING", "OFF");
-    }
-    if cfg!(feature = "json") {
-        config.define("SHARDS_WITH_JSON", "ON");
-    } else {
-        config.define("SHARDS_WITH_JSON", "OFF");
-    }
-    if cfg!(feature = "os") {
-        config.define("SHARDS_WITH_OS", "ON");
-    } else {
-        config.define("SHARDS_WITH_OS", "OFF");
-    }
-    if cfg!(feature = "reflection") {
-        config.define("SHARDS_WITH_REFLECTION", "ON");
-    } else {
-        config.define("SHARDS_WITH_REFLECTION", "OFF");
-    }
-    if cfg!(feature = "snappy") {
-        config.define("SHARDS_WITH_SNAPPY", "ON");
-    } else {
-        config.define("SHARDS_WITH_SNAPPY", "OFF");
-    }
-    if cfg!(feature = "sqlite") {
-        config.define("SHARDS_WITH_SQLITE", "ON");
-    } else {
-        config.define("SHARDS_WITH_SQLITE", "OFF");
-    }
-    if cfg!(feature = "struct") {
-        config.define("SHARDS_WITH_STRUCT", "ON");
-    } else {
-        config.define("SHARDS_WITH_STRUCT", "OFF");
-    }
-
-    // Disabled modules (no feature flags for these)
-    config.define("SHARDS_WITH_CLIPBOARD", "OFF");
-    config.define("SHARDS_WITH_DEBUGGER", "OFF");
-    config.define("SHARDS_WITH_DESKTOP", "OFF");
-    config.define("SHARDS_WITH_GFX", "OFF");
-    config.define("SHARDS_WITH_INPUTS", "OFF");
-    config.define("SHARDS_WITH_PHYSICS", "OFF");
-    config.define("SHARDS_WITH_WASM", "OFF");
-    config.define("SHARDS_WITH_EGUI", "OFF");
-
-    // Feature-gated modules
-    if cfg!(feature = "ml") {
-        config.define("SHARDS_WITH_ML", "ON");
-        config.define("SHARDS_WITH_LLM", "ON");
-    } else {
-        config.define("SHARDS_WITH_ML", "OFF");
-        config.define("SHARDS_WITH_LLM", "OFF");
-    }
-    if cfg!(feature = "crypto") {
-        config.define("SHARDS_WITH_CRYPTO", "ON");
-    } else {
-        config.define("SHARDS_WITH_CRYPTO", "OFF");
-    }
-    if cfg!(feature = "csv") {
-        config.define("SHARDS_WITH_CSV", "ON");
-    } else {
-        config.define("SHARDS_WITH_CSV", "OFF");
-    }
-    if cfg!(feature = "fs") {
-        config.define("SHARDS_WITH_FS", "ON");
-    } else {
-        config.define("SHARDS_WITH_FS", "OFF");
-    }
-    if cfg!(feature = "http") {
-        config.define("SHARDS_WITH_HTTP", "ON");
-    } else {
-        config.define("SHARDS_WITH_HTTP", "OFF");
-    }
-    if cfg!(feature = "network") {
-        config.define("SHARDS_WITH_NETWORK", "ON");
-    } else {
-        config.define("SHARDS_WITH_NETWORK", "OFF");
-    }
-    if cfg!(feature = "pdf") {
-        config.define("SHARDS_WITH_PDF", "ON");
-    } else {
-        config.define("SHARDS_WITH_PDF", "OFF");
-    }
-    // SSH feature disabled - requires OPENSSL_DIR env vars to be set
-    config.define("SHARDS_WITH_SSH", "OFF");
-    if cfg!(feature = "svg") {
-        config.define("SHARDS_WITH_SVG", "ON");
-    } else {
-        config.define("SHARDS_WITH_SVG", "OFF");
-    }
-    if cfg!(feature = "random") {
-        config.define("SHARDS_WITH_RANDOM", "ON");
-    } else {
-        config.define("SHARDS_WITH_RANDOM", "OFF");
-    }
-    if cfg!(feature = "markdown") {
-        config.define("SHARDS_WITH_MARKDOWN", "ON");
-    } else {
-        config.define("SHARDS_WITH_MARKDOWN", "OFF");
-    }
-    if cfg!(feature = "localshell") {
-        config.define("SHARDS_WITH_LOCALSHELL", "ON");
-    } else {
-        config.define("SHARDS_WITH_LOCALSHELL", "OFF");
-    }
-    if cfg!(feature = "py") {
-        config.define("SHARDS_WITH_PY", "ON");
-        config.define("ENABLE_PYTHON_SHARDS", "ON");
-        config.define("ENABLE_RUSTPYTHON_EMBEDDED", "ON");
-    } else {
-        config.define("SHARDS_WITH_PY", "OFF");
-        config.define("ENABLE_PYTHON_SHARDS", "OFF");
-        config.define("ENABLE_RUSTPYTHON_EMBEDDED", "OFF");
-    }
-    // Tracy feature disabled - requires GFX modules
-    config.define("TRACY_ENABLE", "OFF");
-    config.define("SHARDS_WITH_TRACY", "OFF");
-
-    // Build the C++ union target
-    config.build_target("shards-cpp-union");
-
-    let dst = config.build();
-
-    // Build crsql_bundle-rust separately (for SQLite CRDT support)
-    // This must be built after the main configure step
-    if cfg!(feature = "sqlite") {
-        let cmake_build_dir = dst.join("build");
-        let status = std::process::Command::new("ninja")
-            .arg("-C")
-            .arg(&cmake_build_dir)
-            .arg("cargo-crsql_bundle-rust")
-            .status()
-            .expect("Failed to build crsql_bundle-rust");
-        if !status.success() {
-            panic!("Failed to build crsql_bundle-rust");
+    defines.push(("CMAKE_BUILD_TYPE", cmake_build_type));
+
+    // Map Cargo features to CMake options, see MODULES
+    validate_modules(&manifest_path, &manifest);
+    defines.push(("SHARDS_WITH_EVERYTHING", "OFF"));
+    defines.push(("SHARDS_WITH_DEFAULT", "OFF"));
+    for option in ALWAYS_ON {
+        defines.push((option, "ON"));
+    }
+    for option in ALWAYS_OFF {
+        defines.push((option, "OFF"));
+    }
+    let modules: Vec<&Module> = MODULES.iter().filter(|module| module.enabled()).collect();
+    for module in MODULES {
+        let value = if module.enabled() { "ON" } else { "OFF" };
+        for option in module.cmake {
+            defines.push((option, value));
         }
     }
 
-    // Link paths
-    let build_dir = dst.join("build");
-    let lib_dir = build_dir.join("lib");
-
-    println!("cargo:rustc-link-search=native={}", build_dir.display());
-    println!("cargo:rustc-link-search=native={}", lib_dir.display());
-
-    // Helper function to find libraries in subdirectories
-    // If not found, prints debug info on Windows
-    fn find_and_add_lib_dir(
-        build_dir: &std::path::Path,
-        lib_patterns: &[&str],
-        lib_name: &str,
-    ) -> bool {
-        let mut found = false;
-
-        if let Ok(entries) = std::fs::read_dir(build_dir) {
-            for entry in entries.flatten() {
-                let path = entry.path();
-                if path.is_dir() {
-                    // Check if this directory contains any of the library patterns
-                    let has_lib = lib_patterns.iter().any(|pattern| path.join(pattern).exists());
-                    if has_lib {
-                        println!("cargo:rustc-link-search=native={}", path.display());
-                        found = true;
-                    }
-                }
-            }
-        }
-
-        // If not found, print debug info
-        if !found {
-            println!("cargo:warning=Library '{}' not found, scanning build directory: {}", lib_name, build_dir.display());
-            if let Ok(entries) = std::fs::read_dir(build_dir) {
-                for entry in entries.flatten() {
-                    let path = entry.path();
-                    if path.is_dir() {
-                        println!("cargo:warning=Checking directory: {}", path.display());
-                        if let Ok(lib_entries) = std::fs::read_dir(&path) {
-                            for lib_entry in lib_entries.flatten() {
-                                let lib_path = lib_entry.path();
-                                if let Some(ext) = lib_path.extension() {
-                                    if ext == "lib" || ext == "a" || ext == "so" || ext == "dylib" {
-                                        if let Some(name) = lib_path.file_name() {
-                                            println!("cargo:warning=  Found library: {}", name.to_string_lossy());
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+    // `shards_module = "<feature>"` for the enabled modules with a Rust crate, for lib.rs
+    let crate_modules: Vec<String> = MODULES
+        .iter()
+        .filter(|module| module.rust_crate.is_some())
+        .map(|module| format!("\"{}\"", module.feature))
+        .collect();
+    println!("cargo:rustc-check-cfg=cfg(shards_module, values({}))", crate_modules.join(", "));
+    for module in &modules {
+        if module.rust_crate.is_some() {
+            println!("cargo:rustc-cfg=shards_module=\"{}\"", module.feature);
         }
-
-        found
     }
 
-    // TBB puts libraries in compiler-specific directories
-    let tbb_patterns = vec!["libtbb.a", "libtbb_debug.a", "tbb.lib", "tbb_debug.lib", "tbb12.lib", "tbb12_debug.lib"];
-    find_and_add_lib_dir(&build_dir, &tbb_patterns, "TBB");
-
-    // External dependencies in nested build directories
-    if cfg!(feature = "audio") {
-        let kissfft_dir = build_dir.join("deps/kissfft_a/src/kissfft_a-build");
-        if kissfft_dir.exists() {
-            println!("cargo:rustc-link-search=native={}", kissfft_dir.display());
+    // Reuse the libraries of an identical build when cached, skipping CMake entirely
+    let target = env::var("TARGET").unwrap_or_else(|_| "aarch64-apple-darwin".to_string());
+    let cache = artifact_cache(&shards_dir, &defines, &target, &profile);
+    let lib_dirs = match &cache {
+        Some(cache) if cache.is_dir() => {
+            println!("cargo:warning=Using cached shards libraries from {}", cache.display());
+            vec![cache.clone()]
         }
-    }
-    if cfg!(feature = "imaging") {
-        let mozjpeg_dir = build_dir.join("deps/mozjpeg_a/src/mozjpeg_a-build");
-        if mozjpeg_dir.exists() {
-            println!("cargo:rustc-link-search=native={}", mozjpeg_dir.display());
+        _ => {
+            let lib_dirs = build_shards(&shards_dir, &defines, &modules, &target, &profile);
+            if let Some(cache) = &cache {
+                store_artifacts(&lib_dirs, cache);
+            }
+            lib_dirs
         }
-    }
-
-    // Rust libraries built by CMake's corrosion (crsql, etc)
-    let target_arch = env::var("TARGET").unwrap_or_else(|_| "aarch64-apple-darwin".to_string());
-
-    // Tracy feature disabled - use standard profile names
-    let profile_suffix = profile.as_str();
+    };
 
-    let rust_target_dir = build_dir.join("target").join(&target_arch).join(profile_suffix);
-    if rust_target_dir.exists() {
-        println!("cargo:rustc-link-search=native={}", rust_target_dir.display());
-        println!("cargo:rustc-link-search=native={}", rust_target_dir.join("deps").display());
+    // Link paths
+    for dir in &lib_dirs {
+        println!("cargo:rustc-link-search=native={}", dir.display());
     }
 
-    // Link the main C++ union (must come first)
-    println!("cargo:rustc-link-lib=static=shards-cpp-union");
-
-    // Core shards libraries
-    println!("cargo:rustc-link-lib=static=shards-core");
-    println!("cargo:rustc-link-lib=static=shards-logging");
-    println!("cargo:rustc-link-lib=static=shards-fast-string");
-
-    // Boost libraries
-    println!("cargo:rustc-link-lib=static=boost_filesystem");
-    println!("cargo:rustc-link-lib=static=boost_container");
-    println!("cargo:rustc-link-lib=static=boost_context");
-    println!("cargo:rustc-link-lib=static=boost_thread");
-    println!("cargo:rustc-link-lib=static=boost_atomic");
-    println!("cargo:rustc-link-lib=static=boost_chrono");
-    println!("cargo:rustc-link-lib=static=boost_date_time");
-    println!("cargo:rustc-link-lib=static=boost_random");
+    // Static libraries built by CMake, with the module needing them
+    let mut libs: Vec<(&str, &str)> = CORE_LIBS.iter().map(|lib| ("core", *lib)).collect();
     // boost_stacktrace variant differs by platform
-    if is_apple {
-        println!("cargo:rustc-link-lib=static=boost_stacktrace_basic");
+    let stacktrace = if is_apple {
+        "boost_stacktrace_basic"
     } else if target_os == "windows" {
         // Windows uses windbg for debug, basic for release
         if profile == "release" {
-            println!("cargo:rustc-link-lib=static=boost_stacktrace_basic");
+            "boost_stacktrace_basic"
         } else {
-            println!("cargo:rustc-link-lib=static=boost_stacktrace_windbg");
+            "boost_stacktrace_windbg"
         }
     } else {
-        println!("cargo:rustc-link-lib=static=boost_stacktrace_addr2line");
-    }
-
+        "boost_stacktrace_addr2line"
+    };
     // Third-party libraries
-    if profile == "release" {
-        println!("cargo:rustc-link-lib=static=spdlog");
-    } else {
-        println!("cargo:rustc-link-lib=static=spdlogd");
-    }
+    let spdlog = if profile == "release" { "spdlog" } else { "spdlogd" };
     // TBB has different naming on Windows (tbb12 vs tbb)
-    if target_os == "windows" {
-        if profile == "release" {
-            println!("cargo:rustc-link-lib=static=tbb12");
-        } else {
-            println!("cargo:rustc-link-lib=static=tbb12_debug");
-        }
-    } else if profile == "release" {
-        println!("cargo:rustc-link-lib=static=tbb");
-    } else {
-        println!("cargo:rustc-link-lib=static=tbb_debug");
-    }
-
-    if cfg!(feature = "network") {
-        println!("cargo:rustc-link-lib=static=kcp");
-    }
-
-    // Tracy feature disabled - requires GFX modules
-
-    // Compression libraries
-    if cfg!(feature = "brotli") {
-        println!("cargo:rustc-link-lib=static=brotlicommon");
-        println!("cargo:rustc-link-lib=static=brotlidec");
-        println!("cargo:rustc-link-lib=static=brotlienc");
-    }
-    if cfg!(feature = "snappy") {
-        println!("cargo:rustc-link-lib=static=snappy");
-    }
-
-    // SQLite
-    if cfg!(feature = "sqlite") {
-        println!("cargo:rustc-link-lib=static=sqlite-static");
-        println!("cargo:rustc-link-lib=static=sqlite-vec");
-        println!("cargo:rustc-link-lib=static=crsql_bundle");
-    }
-
-    // Audio
-    if cfg!(feature = "audio") {
-        println!("cargo:rustc-link-lib=static=opus");
-        println!("cargo:rustc-link-lib=static=kissfft-float");
-    }
-
-    // Imaging
-    if cfg!(feature = "imaging") {
-        println!("cargo:rustc-link-lib=static=jpeg");
-    }
-
-    // ML/LLM (llama.cpp and ggml)
-    if cfg!(feature = "ml") {
-        println!("cargo:rustc-link-lib=static=llama");
-        println!("cargo:rustc-link-lib=static=ggml");
-        println!("cargo:rustc-link-lib=static=ggml-base");
-        println!("cargo:rustc-link-lib=static=ggml-cpu");
-        println!("cargo:rustc-link-lib=static=ggml-blas");
+    let tbb = match (target_os == "windows", profile == "release") {
+        (true, true) => "tbb12",
+        (true, false) => "tbb12_debug",
+        (false, true) => "tbb",
+        (false, false) => "tbb_debug",
+    };
+    libs.extend([("core", stacktrace), ("core", spdlog), ("core", tbb)]);
+    for module in &modules {
+        libs.extend(module.libs.iter().map(|lib| (module.feature, *lib)));
         if is_apple {
-            println!("cargo:rustc-link-lib=static=ggml-metal");
+            libs.extend(module.apple_libs.iter().map(|lib| (module.feature, *lib)));
         }
     }
 
-    // OpenSSL/LibreSSL (needed by HTTP module via boost::beast)
-    if cfg!(feature = "http") {
-        println!("cargo:rustc-link-lib=static=ssl");
-        println!("cargo:rustc-link-lib=static=crypto");
+    check_libs(&lib_dirs, &libs);
+    for (_, lib) in &libs {
+        println!("cargo:rustc-link-lib=static={}", lib);
     }
 
     // // SDL3 is used by core for SDL_getenv etc
@@ -484,10 +410,8 @@ fn main() {
         println!("cargo:rustc-link-arg=-Xlinker");
         println!("cargo:rustc-link-arg=/usr/lib/swift");
 
-        // ___isPlatformVersionAtLeast is a weak symbol used by Metal
-        // Allow it to be undefined and resolved at runtime
-        if cfg!(feature = "ml") {
-            println!("cargo:rustc-link-arg=-Wl,-U,___isPlatformVersionAtLeast");
+        for arg in modules.iter().flat_map(|module| module.apple_link_args) {
+            println!("cargo:rustc-link-arg={}", arg);
         }
     } else if target_os == "linux" {
         println!("cargo:rustc-link-lib=stdc++");
@@ -513,10 +437,280 @@ fn main() {
     println!("cargo:rerun-if-changed=shards/CMakeLists.txt");
 }
 
-fn find_shards_source() -> String {
+/// Whether the cargo feature `feature` is enabled, from the `CARGO_FEATURE_*` variables.
+fn feature_enabled(feature: &str) -> bool {
+    let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+    env::var_os(var).is_some()
+}
+
+/// Whether `dep` is the Rust crate of a shards module, not one of this crate's own.
+fn is_module_crate(dep: &str) -> bool {
+    dep.starts_with("shards-") && !dep.starts_with("shards-embed")
+}
+
+/// Fail the build if MODULES and the features of Cargo.toml disagree: a module feature not
+/// declared, a feature not enabling exactly the Rust crate of its module, or a feature
+/// enabling a module crate without a module.
+fn validate_modules(manifest: &Path, text: &str) {
+    let features = declared_features(text);
+    let mut problems = Vec::new();
+
+    for (name, deps) in &features {
+        let has_module = MODULES.iter().any(|module| module.feature == *name);
+        if !has_module && deps.iter().any(|dep| is_module_crate(dep)) {
+            problems.push(format!("feature `{}` enables {:?}, but has no module", name, deps));
+        }
+    }
+
+    for module in MODULES {
+        let Some(deps) = features.iter().find(|(name, _)| *name == module.feature).map(|(_, deps)| deps) else {
+            problems.push(format!("feature `{}` is not declared", module.feature));
+            continue;
+        };
+        let shards_deps: Vec<&str> = deps.iter().copied().filter(|dep| is_module_crate(dep)).collect();
+        match module.rust_crate {
+            Some(rust_crate) if shards_deps != [rust_crate] => problems.push(format!(
+                "feature `{}` must enable dep:{} (enables {:?})",
+                module.feature, rust_crate, shards_deps
+            )),
+            None if !shards_deps.is_empty() => problems.push(format!(
+                "feature `{}` enables {:?}, but its module has no Rust crate",
+                module.feature, shards_deps
+            )),
+            _ => {}
+        }
+    }
+
+    if !problems.is_empty() {
+        panic!(
+            "The modules of build.rs do not match the features of {}:\n  {}",
+            manifest.display(),
+            problems.join("\n  ")
+        );
+    }
+}
+
+/// Fail the build if a library of `libs` (module, library) is in no directory of `lib_dirs`,
+/// naming the module enabling it rather than leaving it to the linker.
+fn check_libs(lib_dirs: &[PathBuf], libs: &[(&str, &str)]) {
+    let missing: Vec<String> = libs
+        .iter()
+        .filter(|(_, lib)| {
+            let names = [format!("lib{}.a", lib), format!("{}.lib", lib)];
+            !lib_dirs
+                .iter()
+                .any(|dir| names.iter().any(|name| dir.join(name).is_file()))
+        })
+        .map(|(module, lib)| format!("{} (module {})", lib, module))
+        .collect();
+
+    if !missing.is_empty() {
+        panic!(
+            "Libraries not found in the shards build ({}):\n  {}",
+            lib_dirs.iter().map(|dir| dir.display().to_string()).collect::<Vec<_>>().join(", "),
+            missing.join("\n  ")
+        );
+    }
+}
+
+/// Build `shards-cpp-union` with CMake, returns the directories of the built libraries.
+fn build_shards(
+    shards_dir: &str,
+    defines: &[(&str, &str)],
+    modules: &[&Module],
+    target: &str,
+    profile: &str,
+) -> Vec<PathBuf> {
+    // Submodules needed for CMake, only fetched into Cargo's checkouts, never a local clone
+    init_submodules(Path::new(shards_dir), shards_dir != "shards");
+
+    let mut config = Config::new(shards_dir);
+
+    // Use Ninja generator (required for Swift support)
+    config.generator("Ninja");
+
+    for (key, value) in defines {
+        config.define(key, value);
+    }
+
+    // Build the C++ union target
+    config.build_target("shards-cpp-union");
+
+    let dst = config.build();
+
+    // Targets of the modules outside the union, they need the main configure step
+    for target in modules.iter().flat_map(|module| module.cmake_targets) {
+        let status = std::process::Command::new("ninja")
+            .arg("-C")
+            .arg(dst.join("build"))
+            .arg(target)
+            .status()
+            .unwrap_or_else(|e| panic!("Failed to build {}: {}", target, e));
+        if !status.success() {
+            panic!("Failed to build {}", target);
+        }
+    }
+
+    let build_dir = dst.join("build");
+    let lib_dir = build_dir.join("lib");
+    let mut lib_dirs = vec![build_dir.clone(), lib_dir];
+
+    // TBB puts libraries in compiler-specific directories
+    let tbb_patterns = vec!["libtbb.a", "libtbb_debug.a", "tbb.lib", "tbb_debug.lib", "tbb12.lib", "tbb12_debug.lib"];
+    find_lib_dirs(&build_dir, &tbb_patterns, "TBB", &mut lib_dirs);
+
+    // External dependencies in nested build directories
+    for module in modules {
+        for dir in module.lib_dirs {
+            let dir = build_dir.join(dir);
+            if dir.exists() {
+                lib_dirs.push(dir);
+            }
+        }
+    }
+
+    // Rust libraries built by CMake's corrosion (crsql, etc), some modules change its profile
+    let mut profile_suffix = profile.to_string();
+    for module in modules {
+        if let Some(suffix) = module.corrosion_profile_suffix {
+            profile_suffix.push_str(suffix);
+        }
+    }
+
+    let rust_target_dir = build_dir.join("target").join(target).join(profile_suffix);
+    if rust_target_dir.exists() {
+        let deps_dir = rust_target_dir.join("deps");
+        lib_dirs.push(rust_target_dir);
+        lib_dirs.push(deps_dir);
+    }
+
+    lib_dirs
+}
+
+// Helper function to find libraries in subdirectories
+// If not found, prints debug info on Windows
+fn find_lib_dirs(build_dir: &Path, lib_patterns: &[&str], lib_name: &str, lib_dirs: &mut Vec<PathBuf>) -> bool {
+    let mut found = false;
+
+    if let Ok(entries) = std::fs::read_dir(build_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                // Check if this directory contains any of the library patterns
+                let has_lib = lib_patterns.iter().any(|pattern| path.join(pattern).exists());
+                if has_lib {
+                    lib_dirs.push(path);
+                    found = true;
+                }
+            }
+        }
+    }
+
+    // If not found, print debug info
+    if !found {
+        println!("cargo:warning=Library '{}' not found, scanning build directory: {}", lib_name, build_dir.display());
+        if let Ok(entries) = std::fs::read_dir(build_dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    println!("cargo:warning=Checking directory: {}", path.display());
+                    if let Ok(lib_entries) = std::fs::read_dir(&path) {
+                        for lib_entry in lib_entries.flatten() {
+                            let lib_path = lib_entry.path();
+                            if let Some(ext) = lib_path.extension() {
+                                if ext == "lib" || ext == "a" || ext == "so" || ext == "dylib" {
+                                    if let Some(name) = lib_path.file_name() {
+                                        println!("cargo:warning=  Found library: {}", name.to_string_lossy());
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    found
+}
+
+/// Cache entry of the built libraries under `SHARDS_EMBED_CACHE_DIR`, keyed by the shards
+/// revision, the CMake options (the `SHARDS_WITH_*` set and the build type), the target and
+/// the profile. `None` when caching is disabled or the revision is unknown.
+fn artifact_cache(shards_dir: &str, defines: &[(&str, &str)], target: &str, profile: &str) -> Option<PathBuf> {
+    println!("cargo:rerun-if-env-changed=SHARDS_EMBED_CACHE_DIR");
+    let cache_dir = env::var_os("SHARDS_EMBED_CACHE_DIR").filter(|dir| !dir.is_empty())?;
+
+    let Some(revision) = shards_revision(Path::new(shards_dir)) else {
+        println!("cargo:warning=Not caching shards libraries: {} is not a clean git checkout", shards_dir);
+        return None;
+    };
+
+    let mut defines = defines.to_vec();
+    defines.sort();
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}\n{}\n{}\n{}\n", CACHE_VERSION, revision, target, profile));
+    for (key, value) in defines {
+        hasher.update(format!("{}={}\n", key, value));
+    }
+    let key: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    Some(Path::new(&cache_dir).join(key))
+}
+
+/// Commit checked out in `shards_dir`, `None` if it has local changes: they would not be
+/// part of the cache key.
+fn shards_revision(shards_dir: &Path) -> Option<String> {
+    let git = |args: &[&str]| {
+        let output = Command::new("git").current_dir(shards_dir).args(args).output().ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let revision = git(&["rev-parse", "HEAD"])?;
+    let changes = git(&["status", "--porcelain", "--untracked-files=no"])?;
+    changes.is_empty().then_some(revision)
+}
+
+/// Copy the static libraries of `lib_dirs` to the cache entry `cache`. The entry is staged
+/// next to it and renamed once complete, a concurrent build storing the same entry wins.
+fn store_artifacts(lib_dirs: &[PathBuf], cache: &Path) {
+    let staging = cache.with_extension(format!("tmp-{}", std::process::id()));
+    match copy_libs(lib_dirs, &staging).and_then(|_| std::fs::rename(&staging, cache)) {
+        Ok(()) => println!("cargo:warning=Cached shards libraries in {}", cache.display()),
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&staging);
+            if !cache.is_dir() {
+                println!("cargo:warning=Failed to cache shards libraries in {}: {}", cache.display(), e);
+            }
+        }
+    }
+}
+
+fn copy_libs(lib_dirs: &[PathBuf], dest: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dest)?;
+    for dir in lib_dirs {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_lib = path.extension().is_some_and(|ext| ext == "a" || ext == "lib");
+            // The first directory providing a library wins, like in the link search path
+            let target = dest.join(entry.file_name());
+            if is_lib && path.is_file() && !target.exists() {
+                std::fs::copy(&path, &target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn find_shards_source(revision: &str) -> String {
     // Check for local shards directory first (symlink or clone)
     let local_shards = Path::new("shards");
     if local_shards.exists() {
+        verify_revision(local_shards, revision);
         return "shards".to_string();
     }
 
@@ -529,7 +723,7 @@ fn find_shards_source() -> String {
 
     let git_checkouts = Path::new(&cargo_home).join("git").join("checkouts");
 
-    // Look for shards checkout directory
+    // Cargo checks out each revision in `<name>-<url hash>/<abbreviated revision>`
     let mut candidates = Vec::new();
 
     if let Ok(entries) = std::fs::read_dir(&git_checkouts) {
@@ -537,17 +731,15 @@ fn find_shards_source() -> String {
             let name = entry.file_name();
             let name_str = name.to_string_lossy();
             if name_str.starts_with("shards-") {
-                // Find the actual checkout (there may be multiple revisions)
                 if let Ok(revisions) = std::fs::read_dir(entry.path()) {
                     for rev in revisions.flatten() {
+                        let rev_name = rev.file_name().to_string_lossy().to_string();
                         let rev_path = rev.path();
-                        if rev_path.join("CMakeLists.txt").exists() {
-                            // Get modification time to find the most recent checkout
-                            if let Ok(metadata) = rev_path.metadata() {
-                                if let Ok(modified) = metadata.modified() {
-                                    candidates.push((rev_path, modified));
-                                }
-                            }
+                        if rev_name.len() >= 7
+                            && revision.starts_with(&rev_name)
+                            && rev_path.join("CMakeLists.txt").exists()
+                        {
+                            candidates.push(rev_path);
                         }
                     }
                 }
@@ -555,77 +747,207 @@ fn find_shards_source() -> String {
         }
     }
 
-    // Use the most recently modified checkout (should be what Cargo just fetched)
-    if !candidates.is_empty() {
-        candidates.sort_by_key(|(_, time)| *time);
-        let (rev_path, _) = candidates.last().unwrap();
-        let path_str = rev_path.to_string_lossy().to_string();
-        println!("cargo:warning=Using Cargo-cached shards at {}", path_str);
-
-        // Initialize submodules needed for CMake
-        init_submodules(rev_path);
+    // Other repositories named shards-* can share the abbreviated revision, check the full one
+    let checkout = candidates
+        .iter()
+        .find(|path| git_head(path).as_deref() == Some(revision));
 
+    if let Some(rev_path) = checkout {
+        let path_str = rev_path.to_string_lossy().to_string();
+        println!("cargo:warning=Using Cargo-cached shards {} at {}", &revision[..7], path_str);
         return path_str;
     }
 
+    if !candidates.is_empty() {
+        let found: Vec<String> = candidates
+            .iter()
+            .map(|path| {
+                let head = git_head(path).unwrap_or_else(|| "unknown revision".to_string());
+                format!("  {} ({})", path.display(), head)
+            })
+            .collect();
+        panic!(
+            "No Cargo-cached shards checkout is at the pinned revision {}, found:\n{}\n\
+             Remove them and run cargo build again to fetch the pinned revision.",
+            revision,
+            found.join("\n")
+        );
+    }
+
     panic!(
-        "Could not find shards source!\n\
+        "Could not find shards source at revision {}!\n\
          Either:\n\
          - Create a symlink: ln -s /path/to/shards shards\n\
          - Or run: cargo build (Cargo will fetch from git)\n\
          \n\
          Note: The shards repo needs a root Cargo.toml with workspace members.\n\
-         See: https://github.com/fragcolor-xyz/shards"
+         See: https://github.com/fragcolor-xyz/shards",
+        revision
     );
 }
 
-fn init_submodules(shards_dir: &Path) {
-    // Check if submodules already initialized (check for one key dep)
-    if shards_dir.join("deps/spdlog/CMakeLists.txt").exists() {
+/// Path and content of the Cargo.toml of this crate.
+fn read_manifest() -> (PathBuf, String) {
+    let path = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.toml");
+    println!("cargo:rerun-if-changed={}", path.display());
+    let text = std::fs::read_to_string(&path).expect("Failed to read Cargo.toml");
+    (path, text)
+}
+
+/// Fail the build if the shards checkout at `shards_dir` is not at `revision`.
+fn verify_revision(shards_dir: &Path, revision: &str) {
+    println!("cargo:rerun-if-env-changed=SHARDS_EMBED_SKIP_REV_CHECK");
+    if env::var_os("SHARDS_EMBED_SKIP_REV_CHECK").is_some() {
+        println!("cargo:warning=Not checking the revision of {}", shards_dir.display());
         return;
     }
 
-    println!("cargo:warning=Initializing shards submodules for CMake...");
-
-    // Core submodules needed for CMake build
-    let submodules = [
-        "deps/stb",
-        "deps/json",
-        "deps/magic_enum",
-        "deps/cpp-taskflow",
-        "deps/nameof",
-        "deps/pdqsort",
-        "deps/filesystem",
-        "deps/xxHash",
-        "deps/linalg",
-        "deps/spdlog",
-        "deps/brotli",
-        "deps/tracy",
-        "deps/oneTBB",
-        "deps/crdt-lite",
-        "deps/utf8.h",
-        "deps/entt",
-        "deps/kcp",
-        "deps/tinygltf",
-        "deps/draco",
-        "deps/sqlite/cr-sqlite",
-        "deps/miniaudio",
-        "deps/kissfft",
-        "deps/snappy",
-    ];
-
-    let status = Command::new("git")
-        .current_dir(shards_dir)
-        .args(["submodule", "update", "--init", "--depth", "1"])
-        .args(&submodules)
-        .status();
-
-    match status {
-        Ok(s) if s.success() => {
-            println!("cargo:warning=Submodules initialized successfully");
-        }
-        _ => {
-            println!("cargo:warning=Failed to initialize some submodules - CMake may fail");
+    match git_head(shards_dir) {
+        Some(head) if head == revision => {}
+        head => panic!(
+            "{} is at revision {}, but Cargo.toml pins shards at {}.\n\
+             Check it out (git -C {} checkout {}) or set SHARDS_EMBED_SKIP_REV_CHECK=1 to build it anyway.",
+            shards_dir.display(),
+            head.as_deref().unwrap_or("unknown"),
+            revision,
+            shards_dir.display(),
+            revision
+        ),
+    }
+}
+
+/// Commit checked out in the git repository at `dir`, read from `.git` without running git.
+fn git_head(dir: &Path) -> Option<String> {
+    let mut git_dir = dir.join(".git");
+    // Worktrees and submodules have a `.git` file pointing to the git directory
+    if git_dir.is_file() {
+        let link = std::fs::read_to_string(&git_dir).ok()?;
+        git_dir = dir.join(link.trim().strip_prefix("gitdir:")?.trim());
+    }
+
+    let head = std::fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let Some(reference) = head.trim().strip_prefix("ref:") else {
+        // Detached, like Cargo's checkouts
+        return Some(head.trim().to_string());
+    };
+    let reference = reference.trim();
+
+    // Worktrees share the refs of the main repository
+    let common_dir = match std::fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim()),
+        Err(_) => git_dir,
+    };
+    if let Ok(commit) = std::fs::read_to_string(common_dir.join(reference)) {
+        return Some(commit.trim().to_string());
+    }
+    std::fs::read_to_string(common_dir.join("packed-refs"))
+        .ok()?
+        .lines()
+        .find_map(|line| {
+            let (commit, name) = line.split_once(' ')?;
+            (name == reference).then(|| commit.to_string())
+        })
+}
+
+/// Make sure the submodules CMake needs are checked out in `shards_dir`: extracted from the
+/// vendored tarball (`SHARDS_EMBED_VENDOR`) if set, otherwise fetched with git if `fetch`.
+/// Offline builds (`SHARDS_EMBED_OFFLINE=1`) never use git and fail on missing submodules.
+fn init_submodules(shards_dir: &Path, fetch: bool) {
+    println!("cargo:rerun-if-env-changed=SHARDS_EMBED_OFFLINE");
+    println!("cargo:rerun-if-env-changed=SHARDS_EMBED_VENDOR");
+    let offline = env::var("SHARDS_EMBED_OFFLINE").is_ok_and(|value| value != "0")
+        || env::var("CARGO_NET_OFFLINE").is_ok_and(|value| value == "true");
+
+    let missing = missing_submodules(shards_dir);
+    if missing.is_empty() {
+        return;
+    }
+
+    if let Some(tarball) = env::var_os("SHARDS_EMBED_VENDOR") {
+        extract_vendored(Path::new(&tarball), shards_dir, &missing);
+    } else if fetch && !offline {
+        println!("cargo:warning=Initializing shards submodules for CMake...");
+
+        let status = Command::new("git")
+            .current_dir(shards_dir)
+            .args(["submodule", "update", "--init", "--depth", "1"])
+            .args(&missing)
+            .status();
+
+        match status {
+            Ok(s) if s.success() => {
+                println!("cargo:warning=Submodules initialized successfully");
+            }
+            _ => {
+                println!("cargo:warning=Failed to initialize some submodules - CMake may fail");
+            }
         }
     }
+
+    let missing = missing_submodules(shards_dir);
+    if missing.is_empty() {
+        return;
+    }
+    if offline {
+        panic!(
+            "Offline build, missing shards submodules in {}:\n  {}\n\
+             Vendor them on a machine with network access (shards-embed vendor -o shards-vendor.tar.gz)\n\
+             and build with SHARDS_EMBED_VENDOR=/path/to/shards-vendor.tar.gz",
+            shards_dir.display(),
+            missing.join("\n  ")
+        );
+    }
+    println!("cargo:warning=Missing shards submodules - CMake may fail: {}", missing.join(", "));
+}
+
+/// Submodules of `SUBMODULES` not checked out in `shards_dir`, git leaves them empty.
+fn missing_submodules(shards_dir: &Path) -> Vec<&'static str> {
+    SUBMODULES
+        .iter()
+        .copied()
+        .filter(|submodule| {
+            std::fs::read_dir(shards_dir.join(submodule))
+                .map(|mut entries| entries.next().is_none())
+                .unwrap_or(true)
+        })
+        .collect()
+}
+
+/// Extract the `missing` submodules from a tarball written by `shards-embed vendor` into
+/// `shards_dir`, after checking it was made from the revision checked out there.
+fn extract_vendored(tarball: &Path, shards_dir: &Path, missing: &[&str]) {
+    println!("cargo:rerun-if-changed={}", tarball.display());
+    let tar = |args: &[&str]| {
+        Command::new("tar")
+            .arg("-xzf")
+            .arg(tarball)
+            .args(args)
+            .output()
+            .unwrap_or_else(|e| panic!("Failed to run tar to extract {}: {}", tarball.display(), e))
+    };
+
+    let revision = tar(&["-O", "REVISION"]);
+    let revision = String::from_utf8_lossy(&revision.stdout).trim().to_string();
+    let head = git_head(shards_dir);
+    if head.as_deref() != Some(revision.as_str()) {
+        panic!(
+            "{} vendors the submodules of shards {}, but {} is at {}",
+            tarball.display(),
+            if revision.is_empty() { "unknown revision" } else { &revision },
+            shards_dir.display(),
+            head.as_deref().unwrap_or("an unknown revision")
+        );
+    }
+
+    println!("cargo:warning=Extracting shards submodules from {}", tarball.display());
+    let mut args = vec!["-C", shards_dir.to_str().expect("Non UTF-8 shards path")];
+    args.extend(missing);
+    let output = tar(&args);
+    if !output.status.success() {
+        println!(
+            "cargo:warning=Failed to extract some submodules from {}: {}",
+            tarball.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
 }
@@ -0,0 +1,145 @@
+//! Parsing of the Cargo.toml of the crate, shared by build.rs and the tests of the crate.
+
+use std::path::Path;
+
+/// The features of the `[features]` table of Cargo.toml, with their `dep:` dependencies.
+pub(crate) fn declared_features(text: &str) -> Vec<(&str, Vec<&str>)> {
+    let mut features: Vec<(&str, Vec<&str>)> = Vec::new();
+    let mut in_features = false;
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.starts_with('[') && line.ends_with(']') {
+            in_features = line == "[features]";
+            continue;
+        }
+        if !in_features {
+            continue;
+        }
+
+        // `name = [...]`, possibly continued on the following lines
+        let values = match line.split_once('=') {
+            Some((name, values)) => {
+                features.push((name.trim(), Vec::new()));
+                values
+            }
+            None => line,
+        };
+        if let Some((_, deps)) = features.last_mut() {
+            let strings = values.split('"').skip(1).step_by(2);
+            deps.extend(strings.filter_map(|value| value.strip_prefix("dep:")));
+        }
+    }
+    features
+}
+
+/// Revision of the shards repository pinned in Cargo.toml, the shards crates must all agree.
+pub(crate) fn pinned_revision(manifest: &Path, text: &str) -> String {
+    let mut revisions: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#') && line.contains("/fragcolor-xyz/shards.git\""))
+        .filter_map(|line| {
+            let rest = &line[line.find("rev = \"")? + "rev = \"".len()..];
+            Some(&rest[..rest.find('"')?])
+        })
+        .collect();
+    revisions.sort();
+    revisions.dedup();
+
+    match revisions.as_slice() {
+        [revision] if revision.len() == 40 => revision.to_string(),
+        [revision] => panic!(
+            "The shards revision pinned in {} must be a full commit hash, got {}",
+            manifest.display(),
+            revision
+        ),
+        [] => panic!("No shards revision pinned in {}", manifest.display()),
+        _ => panic!(
+            "The shards crates in {} pin different revisions: {}",
+            manifest.display(),
+            revisions.join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REVISION: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn shards_dependency(name: &str, rev: &str) -> String {
+        format!(
+            "{} = {{ git = \"https://github.com/fragcolor-xyz/shards.git\", rev = \"{}\" }}\n",
+            name, rev
+        )
+    }
+
+    #[test]
+    fn reads_features_and_their_dependencies() {
+        let text = r#"
+[dependencies]
+shards-fs = { version = "1", optional = true }
+
+[features]
+default = ["fs"] # "dep:commented"
+fs = ["dep:shards-fs", "other"]
+http = [
+    "dep:shards-http",
+    # "dep:shards-commented",
+    "dep:shards-network",
+]
+
+[dev-dependencies]
+"#;
+        assert_eq!(
+            declared_features(text),
+            [
+                ("default", vec![]),
+                ("fs", vec!["shards-fs"]),
+                ("http", vec!["shards-http", "shards-network"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_the_pinned_revision() {
+        let text = shards_dependency("shards", REVISION)
+            + &shards_dependency("shards-lang", REVISION)
+            + &format!("# {}", shards_dependency("shards-old", "abc"));
+        assert_eq!(pinned_revision(Path::new("Cargo.toml"), &text), REVISION);
+    }
+
+    #[test]
+    fn reads_the_manifest_of_the_crate() {
+        let text = include_str!("../Cargo.toml");
+        assert_eq!(pinned_revision(Path::new("Cargo.toml"), text).len(), 40);
+        assert!(declared_features(text)
+            .iter()
+            .any(|(name, _)| *name == "default"));
+    }
+
+    #[test]
+    #[should_panic(expected = "pin different revisions")]
+    fn rejects_different_revisions() {
+        let other = REVISION.replace('0', "f");
+        let text =
+            shards_dependency("shards", REVISION) + &shards_dependency("shards-lang", &other);
+        pinned_revision(Path::new("Cargo.toml"), &text);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be a full commit hash")]
+    fn rejects_short_revisions() {
+        pinned_revision(
+            Path::new("Cargo.toml"),
+            &shards_dependency("shards", "0123abc"),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "No shards revision")]
+    fn rejects_missing_revisions() {
+        pinned_revision(Path::new("Cargo.toml"), "[dependencies]\n");
+    }
+}
@@ -0,0 +1,66 @@
+//! Procedural macros of shards-embed, use them through the `macros` feature of `shards-embed`.
+
+use std::path::Path;
+
+use proc_macro::TokenStream;
+use proc_macro2::Literal;
+use quote::quote;
+use shards_lang::read::{read_with_env, ReadEnv};
+use syn::{parse_macro_input, LitStr};
+
+/// Embed a script parsed at compile time, as a `shards_embed::EmbeddedScript`.
+///
+/// The path is relative to the manifest directory of the crate using the macro. Parse errors,
+/// in the script or its includes, fail the build. Only the script itself is tracked by cargo,
+/// changing an included file alone does not trigger a rebuild.
+///
+/// ```rust,ignore
+/// static SCRIPT: shards_embed::EmbeddedScript = shards_embed::include_shards!("scripts/main.shs");
+///
+/// shards_embed::Runtime::new().run_embedded(&SCRIPT)?;
+/// ```
+#[proc_macro]
+pub fn include_shards(input: TokenStream) -> TokenStream {
+    let file = parse_macro_input!(input as LitStr);
+    match expand(&file) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(file: &LitStr) -> syn::Result<proc_macro2::TokenStream> {
+    let error = |message: String| syn::Error::new(file.span(), message);
+
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR")
+        .map_err(|_| error("CARGO_MANIFEST_DIR is not set".to_string()))?;
+    let path = Path::new(&manifest_dir).join(file.value());
+    let source = std::fs::read_to_string(&path)
+        .map_err(|e| error(format!("cannot read {}: {}", path.display(), e)))?;
+
+    let name = path.to_string_lossy();
+    let dir = path
+        .parent()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let program = read_with_env(&source, ReadEnv::new(&name, &dir, &dir)).map_err(|e| {
+        error(format!(
+            "{}:{}:{}: {}",
+            path.display(),
+            e.loc.line,
+            e.loc.column,
+            e.message
+        ))
+    })?;
+    let program = flexbuffers::to_vec(&program)
+        .map_err(|e| error(format!("cannot serialize {}: {}", path.display(), e)))?;
+    let program = Literal::byte_string(&program);
+
+    // `include_str!` makes cargo rebuild when the script changes
+    Ok(quote! {
+        ::shards_embed::EmbeddedScript {
+            path: #name,
+            source: include_str!(#name),
+            program: #program,
+        }
+    })
+}
@@ -0,0 +1,124 @@
+//! shards-embed build tooling
+//!
+//! `shards-embed vendor` writes the shards submodules the C++ core build needs to a tarball,
+//! for builds without network access (`SHARDS_EMBED_VENDOR`, see the README).
+
+use std::path::Path;
+use std::process::Command;
+
+use shards_embed::cli::exit_code;
+
+// The shards checkout and submodules of this build, set by build.rs
+const SOURCE_DIR: &str = env!("SHARDS_EMBED_SOURCE_DIR");
+const REVISION: &str = env!("SHARDS_EMBED_REVISION");
+const SUBMODULES: &str = env!("SHARDS_EMBED_SUBMODULES");
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let code = match args.first().map(String::as_str) {
+        Some("vendor") => vendor(&args[1..]),
+        _ => {
+            eprintln!("Usage: shards-embed vendor [-o <tarball>]");
+            exit_code::USAGE
+        }
+    };
+    std::process::exit(code);
+}
+
+fn vendor(args: &[String]) -> i32 {
+    const USAGE: &str = "Usage: shards-embed vendor [-o <tarball>]";
+
+    let mut output = "shards-vendor.tar.gz";
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match (arg.as_str(), iter.next()) {
+            ("-o" | "--output", Some(path)) => output = path,
+            _ => {
+                eprintln!("{}", USAGE);
+                return exit_code::USAGE;
+            }
+        }
+    }
+
+    let source = Path::new(SOURCE_DIR);
+    let submodules: Vec<&str> = SUBMODULES.split(',').collect();
+
+    // Cached builds skip CMake and may never have fetched them
+    let missing = missing_submodules(source, &submodules);
+    if !missing.is_empty() {
+        eprintln!("Fetching {} submodules...", missing.len());
+        let status = Command::new("git")
+            .current_dir(source)
+            .args(["submodule", "update", "--init", "--depth", "1"])
+            .args(&missing)
+            .status();
+        if !status.is_ok_and(|s| s.success()) {
+            eprintln!("Error: git submodule update failed in {}", source.display());
+            return exit_code::FAILURE;
+        }
+    }
+    let missing = missing_submodules(source, &submodules);
+    if !missing.is_empty() {
+        eprintln!("Error: missing submodules in {}:", source.display());
+        for submodule in missing {
+            eprintln!("  {}", submodule);
+        }
+        return exit_code::FAILURE;
+    }
+
+    // The revision is checked when extracting, next to the submodules
+    let staging = std::env::temp_dir().join(format!("shards-vendor-{}", std::process::id()));
+    let written = std::fs::create_dir_all(&staging)
+        .and_then(|_| std::fs::write(staging.join("REVISION"), format!("{}\n", REVISION)))
+        .and_then(|_| std::env::current_dir())
+        .and_then(|cwd| {
+            Command::new("tar")
+                .arg("-czf")
+                .arg(cwd.join(output))
+                .arg("--exclude=.git")
+                .arg("-C")
+                .arg(&staging)
+                .arg("REVISION")
+                .arg("-C")
+                .arg(source)
+                .args(&submodules)
+                .status()
+        });
+    let _ = std::fs::remove_dir_all(&staging);
+    match written {
+        Ok(status) if status.success() => {
+            println!(
+                "Vendored {} submodules of shards {} to {}",
+                submodules.len(),
+                REVISION,
+                output
+            );
+            println!(
+                "Build offline with SHARDS_EMBED_OFFLINE=1 SHARDS_EMBED_VENDOR={}",
+                output
+            );
+            exit_code::SUCCESS
+        }
+        Ok(_) => {
+            eprintln!("Error: tar failed to write {}", output);
+            exit_code::IO
+        }
+        Err(e) => {
+            eprintln!("Error: {}: {}", output, e);
+            exit_code::IO
+        }
+    }
+}
+
+/// `submodules` not checked out in `source`, git leaves them empty.
+fn missing_submodules<'a>(source: &Path, submodules: &[&'a str]) -> Vec<&'a str> {
+    submodules
+        .iter()
+        .copied()
+        .filter(|submodule| {
+            std::fs::read_dir(source.join(submodule))
+                .map(|mut entries| entries.next().is_none())
+                .unwrap_or(true)
+        })
+        .collect()
+}
@@ -3,12 +3,68 @@
 use std::ffi::{c_char, CString};
 use std::env;
 
+// Gives `run --profile` allocation counts
+#[cfg(feature = "profile-allocations")]
+#[global_allocator]
+static ALLOCATOR: shards_embed::profiler::CountingAllocator = shards_embed::profiler::CountingAllocator;
+
 fn main() {
+    let mut raw_args: Vec<String> = env::args().collect();
+
+    // Built by `shards bundle`, runs its script with the arguments forwarded
+    if let Some(code) = shards_embed::cli::run_bundled(raw_args.get(1..).unwrap_or_default()) {
+        std::process::exit(code);
+    }
+
+    let output = match shards_embed::cli::take_output_format(&mut raw_args) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(shards_embed::cli::exit_code::USAGE);
+        }
+    };
+    if output == shards_embed::cli::OutputFormat::Json {
+        std::process::exit(shards_embed::cli::json::run(&raw_args));
+    }
+
+    // Subcommands implemented by this crate, everything else goes to the shards CLI
+    match raw_args.get(1).map(String::as_str) {
+        Some("lsp") => std::process::exit(shards_embed::lsp::run_stdio()),
+        Some("dap") => std::process::exit(shards_embed::dap::run_stdio()),
+        Some("fmt") => std::process::exit(shards_embed::cli::fmt(&raw_args[2..])),
+        Some("build") => std::process::exit(shards_embed::cli::build(&raw_args[2..])),
+        Some("bundle") => std::process::exit(shards_embed::cli::bundle(&raw_args[2..])),
+        Some("pack") => std::process::exit(shards_embed::cli::pack(&raw_args[2..])),
+        Some("load") => std::process::exit(shards_embed::cli::load(&raw_args[2..])),
+        Some("inspect") => std::process::exit(shards_embed::cli::inspect(&raw_args[2..])),
+        Some("run") if has_run_option(&raw_args, "--watch") && has_run_option(&raw_args, "--profile") => {
+            eprintln!("Error: run --watch cannot be combined with --profile");
+            std::process::exit(shards_embed::cli::exit_code::USAGE);
+        }
+        Some("run") if has_run_option(&raw_args, "--watch") => {
+            let run_args = shards_embed::cli::without_run_option(&raw_args[2..], "--watch");
+            std::process::exit(shards_embed::cli::watch(&run_args));
+        }
+        Some("run") if has_run_option(&raw_args, "--profile") => {
+            let run_args = shards_embed::cli::without_run_option(&raw_args[2..], "--profile");
+            std::process::exit(shards_embed::cli::profile(&run_args));
+        }
+        Some("run")
+            if ["--seed", "--virtual-time", "--record"].iter().any(|option| has_run_option(&raw_args, option))
+                || run_script(&raw_args).is_some_and(|file| shards_embed::cli::is_pack(file)) =>
+        {
+            std::process::exit(shards_embed::cli::run(&raw_args[2..]));
+        }
+        Some("replay") => std::process::exit(shards_embed::cli::replay(&raw_args[2..])),
+        _ => {}
+    }
+
     // Initialize runtime
     shards_embed::init();
 
     // Convert args to C strings
-    let args: Vec<CString> = env::args()
+    let args: Vec<CString> = raw_args
+        .into_iter()
         .map(|arg| CString::new(arg).unwrap())
         .collect();
 
@@ -23,3 +79,13 @@ fn main() {
 
     std::process::exit(result);
 }
+
+/// Whether `option` is given to `run` before the script path, after it belongs to the script.
+fn has_run_option(raw_args: &[String], option: &str) -> bool {
+    shards_embed::cli::run_options(&raw_args[2..]).iter().any(|arg| arg == option)
+}
+
+/// The script path given to `run`.
+fn run_script(raw_args: &[String]) -> Option<&String> {
+    raw_args.get(2 + shards_embed::cli::run_options(&raw_args[2..]).len())
+}
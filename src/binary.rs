@@ -0,0 +1,720 @@
+//! Container of the compiled scripts written by [`crate::build_file`].
+//!
+//! The parsed script is serialized like `shards build` does, the container prefixes it with a
+//! header that describes it, so that it can be inspected and checked before it is loaded:
+//!
+//! ```text
+//! magic "SHBIN\0\0\0" | format version: u32 | ABI version: u32 | flags: u32
+//!     | header length: u32 | header | serialized script
+//! ```
+//!
+//! The header lists the wires with their shard counts, the modules and shards the script uses
+//! and the SHA-256 hash of the serialized script. Integers are little-endian. Binaries written
+//! by `shards build` directly have no header, [`crate::load_binary`] still loads them but they
+//! cannot be inspected.
+//!
+//! Compressed binaries ([`BuildOptions::compression`]) have the [`FLAG_BROTLI`] or
+//! [`FLAG_SNAPPY`] flag, the serialized script is stored compressed and decompressed when
+//! loading. The content hash and length are the ones of the uncompressed script, which may
+//! not exceed [`MAX_DECOMPRESSED_LEN`].
+//!
+//! Signed binaries ([`BuildOptions::sign`]) have the [`FLAG_SIGNED`] flag and end with the
+//! Ed25519 public key of the signer (32 bytes) and the signature (64 bytes) of everything
+//! before them. [`crate::load_binary_trusted`] only loads binaries signed by one of its
+//! [`TrustedKeys`]. Signing and verifying need the `crypto` feature.
+
+mod compress;
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use shards::types::Wire;
+
+use crate::codec::{put_bytes, put_str, put_u32, Reader};
+use crate::instrument::{shard_name, shards_of, wires_of};
+use crate::runtime::string_with_len;
+use crate::sandbox::module_of;
+use crate::{Error, Runtime};
+
+/// Version of the container layout written by this crate.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The binary ends with a signature.
+pub const FLAG_SIGNED: u32 = 1;
+/// The serialized script is compressed with brotli.
+pub const FLAG_BROTLI: u32 = 1 << 1;
+/// The serialized script is compressed with snappy.
+pub const FLAG_SNAPPY: u32 = 1 << 2;
+
+/// Largest serialized script a compressed binary may hold, its length is read from the header
+/// before decompressing.
+pub const MAX_DECOMPRESSED_LEN: u64 = 256 << 20;
+
+const MAGIC: &[u8; 8] = b"SHBIN\0\0\0";
+// Public key and signature
+const SIGNATURE_LEN: usize = 32 + 64;
+
+/// Compression of the serialized script. Brotli compresses better, snappy decompresses
+/// faster, see `benches/compression.rs`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Needs the `brotli` feature.
+    Brotli,
+    /// Needs the `snappy` feature.
+    Snappy,
+}
+
+impl Compression {
+    fn flag(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Brotli => FLAG_BROTLI,
+            Compression::Snappy => FLAG_SNAPPY,
+        }
+    }
+
+    fn of_flags(flags: u32) -> Result<Self, String> {
+        match (flags & FLAG_BROTLI != 0, flags & FLAG_SNAPPY != 0) {
+            (false, false) => Ok(Compression::None),
+            (true, false) => Ok(Compression::Brotli),
+            (false, true) => Ok(Compression::Snappy),
+            (true, true) => Err("both brotli and snappy flags are set".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Brotli => "brotli",
+            Compression::Snappy => "snappy",
+        })
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "brotli" => Ok(Compression::Brotli),
+            "snappy" => Ok(Compression::Snappy),
+            _ => Err(format!(
+                "Unknown compression '{}' (expected none, brotli or snappy)",
+                s
+            )),
+        }
+    }
+}
+
+/// How [`build`] writes a binary, uncompressed and unsigned by default.
+#[derive(Clone, Default)]
+pub struct BuildOptions {
+    compression: Compression,
+    secret: Option<[u8; 32]>,
+}
+
+impl BuildOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sign the binary with the Ed25519 secret key `secret`, see [`read_key`].
+    pub fn sign(mut self, secret: [u8; 32]) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+}
+
+// Keeps the secret key out of logs
+impl fmt::Debug for BuildOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BuildOptions")
+            .field("compression", &self.compression)
+            .field("signed", &self.secret.is_some())
+            .finish()
+    }
+}
+
+/// A wire of a compiled script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireSummary {
+    pub name: String,
+    /// Shards directly in the wire, nested ones excluded.
+    pub shards: u32,
+}
+
+/// Header of a compiled script, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryInfo {
+    pub format_version: u32,
+    /// ABI of the shards core that compiled the script.
+    pub abi_version: u32,
+    /// The root wire first, then the wires it references.
+    pub wires: Vec<WireSummary>,
+    /// Modules of the shards used, see [`crate::sandbox::module_of`], sorted.
+    pub modules: Vec<String>,
+    /// Shards used, sorted.
+    pub shards: Vec<String>,
+    /// SHA-256 of the serialized script.
+    pub content_hash: [u8; 32],
+    /// Size of the serialized script, in bytes.
+    pub content_len: u64,
+    pub compression: Compression,
+    /// Size of the serialized script as stored in the binary, in bytes.
+    pub stored_len: u64,
+    /// Public key of the signer, if signed. The signature is verified when reading the binary
+    /// if the `crypto` feature is enabled.
+    pub signer: Option<[u8; 32]>,
+}
+
+impl BinaryInfo {
+    /// Read the header of the compiled script at `path`, verify its content hash and check
+    /// that this build can load it.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let info = Self::read_unchecked(path)?;
+        info.check()?;
+        Ok(info)
+    }
+
+    /// Like [`BinaryInfo::read`], without checking that this build can load the script.
+    pub fn read_unchecked(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        let (info, _) = unpack(&bytes).map_err(|e| invalid(path, e))?;
+        Ok(info)
+    }
+
+    /// Check that the script was compiled for the ABI of this build and only uses shards it
+    /// provides.
+    pub fn check(&self) -> Result<(), Error> {
+        let abi = shards::SHARDS_CURRENT_ABI as u32;
+        if self.abi_version != abi {
+            return Err(Error::Binary(format!(
+                "compiled for shards ABI {:#x}, this build has {:#x}",
+                self.abi_version, abi
+            )));
+        }
+
+        let missing = self.missing_shards();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let mut by_module: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for shard in missing {
+            by_module.entry(module_of(shard)).or_default().push(shard);
+        }
+        let modules: Vec<String> = by_module
+            .iter()
+            .map(|(module, shards)| format!("{} ({})", module, shards.join(", ")))
+            .collect();
+        Err(Error::Binary(format!(
+            "requires modules this build lacks, enable their features: {}",
+            modules.join(", ")
+        )))
+    }
+
+    /// Shards used by the script that this build does not provide.
+    pub fn missing_shards(&self) -> Vec<&str> {
+        let known = crate::docs::shard_names();
+        self.shards
+            .iter()
+            .filter(|shard| known.binary_search(shard).is_err())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// [`BinaryInfo::content_hash`] as lowercase hex.
+    pub fn content_hash_hex(&self) -> String {
+        hex(&self.content_hash)
+    }
+
+    /// [`BinaryInfo::signer`] as lowercase hex.
+    pub fn signer_hex(&self) -> Option<String> {
+        self.signer.as_ref().map(|key| hex(key))
+    }
+
+    fn encode_header(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_u32(&mut out, self.wires.len() as u32);
+        for wire in &self.wires {
+            put_str(&mut out, &wire.name);
+            put_u32(&mut out, wire.shards);
+        }
+        for list in [&self.modules, &self.shards] {
+            put_u32(&mut out, list.len() as u32);
+            for item in list {
+                put_str(&mut out, item);
+            }
+        }
+        put_bytes(&mut out, &self.content_hash);
+        out.extend_from_slice(&self.content_len.to_le_bytes());
+        out
+    }
+}
+
+/// Ed25519 public keys whose signatures [`crate::load_binary_trusted`] accepts.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedKeys(Vec<[u8; 32]>);
+
+impl TrustedKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key(mut self, public: [u8; 32]) -> Self {
+        self.0.push(public);
+        self
+    }
+
+    /// Trust the public key stored at `path`, see [`read_key`].
+    pub fn key_file(self, path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(self.key(read_key(path)?))
+    }
+
+    pub fn trusts(&self, public: &[u8; 32]) -> bool {
+        self.0.contains(public)
+    }
+}
+
+/// Read an Ed25519 key (secret or public) stored as 32 raw bytes or 64 hex digits.
+pub fn read_key(path: impl AsRef<Path>) -> Result<[u8; 32], Error> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)?;
+    if let Ok(key) = <[u8; 32]>::try_from(bytes.as_slice()) {
+        return Ok(key);
+    }
+
+    let text = String::from_utf8_lossy(&bytes);
+    let text = text.trim();
+    let key = (text.len() == 64)
+        .then(|| {
+            (0..32)
+                .map(|i| u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+        })
+        .flatten()
+        .and_then(|key| key.try_into().ok());
+    key.ok_or_else(|| {
+        invalid(
+            path,
+            "expected a 32-byte key, raw or as 64 hex digits".into(),
+        )
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Sign `message` with the Ed25519 secret key `secret`, returns the public key and signature.
+#[cfg(feature = "crypto")]
+fn sign(message: &[u8], secret: &[u8; 32]) -> Result<([u8; 32], [u8; 64]), Error> {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    let key = SigningKey::from_bytes(secret);
+    Ok((key.verifying_key().to_bytes(), key.sign(message).to_bytes()))
+}
+
+#[cfg(not(feature = "crypto"))]
+fn sign(_message: &[u8], _secret: &[u8; 32]) -> Result<([u8; 32], [u8; 64]), Error> {
+    Err(Error::Binary(
+        "signing requires the `crypto` feature".to_string(),
+    ))
+}
+
+#[cfg(feature = "crypto")]
+fn verify(message: &[u8], public: &[u8; 32], signature: &[u8; 64]) -> Result<(), String> {
+    use ed25519_dalek::{Signature, VerifyingKey};
+
+    let key = VerifyingKey::from_bytes(public).map_err(|_| "invalid signer key".to_string())?;
+    key.verify_strict(message, &Signature::from_bytes(signature))
+        .map_err(|_| "invalid signature, the file was modified after signing".to_string())
+}
+
+fn invalid(path: &Path, message: String) -> Error {
+    Error::Binary(format!("{}: {}", path.display(), message))
+}
+
+/// Header and decompressed serialized script of a compiled script, the hash and the signature
+/// verified.
+fn unpack(bytes: &[u8]) -> Result<(BinaryInfo, Cow<'_, [u8]>), String> {
+    let mut input = Reader(bytes);
+    if input.take(MAGIC.len())? != MAGIC {
+        return Err("not a compiled script, or built without a header by `shards build`".into());
+    }
+    let format_version = input.u32()?;
+    if format_version != FORMAT_VERSION {
+        return Err(format!(
+            "unsupported format version {} (expected {})",
+            format_version, FORMAT_VERSION
+        ));
+    }
+    let abi_version = input.u32()?;
+    let flags = input.u32()?;
+    if flags & !(FLAG_SIGNED | FLAG_BROTLI | FLAG_SNAPPY) != 0 {
+        return Err(format!("unsupported flags {:#x}", flags));
+    }
+    let compression = Compression::of_flags(flags)?;
+
+    let mut header = Reader(input.bytes()?);
+    let wires = (0..header.u32()?)
+        .map(|_| {
+            Ok(WireSummary {
+                name: header.string()?,
+                shards: header.u32()?,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    let modules = (0..header.u32()?)
+        .map(|_| header.string())
+        .collect::<Result<_, _>>()?;
+    let shards = (0..header.u32()?)
+        .map(|_| header.string())
+        .collect::<Result<_, _>>()?;
+    let content_hash: [u8; 32] = header
+        .bytes()?
+        .try_into()
+        .map_err(|_| "invalid content hash".to_string())?;
+    let content_len = header.u64()?;
+
+    let mut content = input.0;
+    let mut signer = None;
+    if flags & FLAG_SIGNED != 0 {
+        let split = content
+            .len()
+            .checked_sub(SIGNATURE_LEN)
+            .ok_or("unexpected end of file")?;
+        let (script, signature) = content.split_at(split);
+        let public: [u8; 32] = signature[..32].try_into().unwrap();
+        #[cfg(feature = "crypto")]
+        verify(
+            &bytes[..bytes.len() - SIGNATURE_LEN],
+            &public,
+            signature[32..].try_into().unwrap(),
+        )?;
+        content = script;
+        signer = Some(public);
+    }
+
+    let stored_len = content.len() as u64;
+    let content = match compression {
+        Compression::None => Cow::Borrowed(content),
+        _ => Cow::Owned(compress::decompress(compression, content, content_len)?),
+    };
+    if content.len() as u64 != content_len
+        || <[u8; 32]>::from(Sha256::digest(&content)) != content_hash
+    {
+        return Err("content hash mismatch, the file is corrupted".into());
+    }
+
+    let info = BinaryInfo {
+        format_version,
+        abi_version,
+        wires,
+        modules,
+        shards,
+        content_hash,
+        content_len,
+        compression,
+        stored_len,
+        signer,
+    };
+    Ok((info, content))
+}
+
+/// The binary for `info` and `stored`, the serialized script as stored, signed with the
+/// Ed25519 secret key `secret` if set.
+fn pack(info: &BinaryInfo, stored: &[u8], secret: Option<&[u8; 32]>) -> Result<Vec<u8>, Error> {
+    let mut flags = info.compression.flag();
+    if secret.is_some() {
+        flags |= FLAG_SIGNED;
+    }
+
+    let mut out = MAGIC.to_vec();
+    put_u32(&mut out, info.format_version);
+    put_u32(&mut out, info.abi_version);
+    put_u32(&mut out, flags);
+    put_bytes(&mut out, &info.encode_header());
+    out.extend_from_slice(stored);
+
+    if let Some(secret) = secret {
+        let (public, signature) = sign(&out, secret)?;
+        out.extend_from_slice(&public);
+        out.extend_from_slice(&signature);
+    }
+    Ok(out)
+}
+
+/// Compile the script at `input` like `shards build` and write it with its header to `output`.
+pub fn build(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    options: &BuildOptions,
+) -> Result<BinaryInfo, Error> {
+    let (info, bytes) = build_bytes(input.as_ref(), options)?;
+    std::fs::write(output, &bytes)?;
+    Ok(info)
+}
+
+/// Like [`build`], returns the binary instead of writing it.
+pub(crate) fn build_bytes(
+    input: &Path,
+    options: &BuildOptions,
+) -> Result<(BinaryInfo, Vec<u8>), Error> {
+    crate::init();
+
+    // Evaluated here too, to describe its wires
+    let source = std::fs::read_to_string(input)?;
+    let runtime = Runtime::new();
+    let (program, _) = runtime.parse(&source, input)?;
+    let (wire, _) = runtime.evaluate(&program, "root")?;
+    let (wires, shards) = summarize(&wire);
+    drop(wire);
+
+    // Serialized in memory like `shards build` does, nothing touches the disk before signing
+    let content = flexbuffers::to_vec(&program).map_err(|e| {
+        Error::Binary(format!(
+            "{}: cannot serialize the script: {}",
+            input.display(),
+            e
+        ))
+    })?;
+    if options.compression != Compression::None && content.len() as u64 > MAX_DECOMPRESSED_LEN {
+        return Err(Error::Binary(format!(
+            "{}: the serialized script is larger than {} bytes, it cannot be compressed",
+            input.display(),
+            MAX_DECOMPRESSED_LEN
+        )));
+    }
+    let stored = compress::compress(options.compression, &content)?;
+
+    let mut modules: Vec<String> = shards.iter().map(|shard| module_of(shard)).collect();
+    modules.sort();
+    modules.dedup();
+
+    let mut info = BinaryInfo {
+        format_version: FORMAT_VERSION,
+        abi_version: shards::SHARDS_CURRENT_ABI as u32,
+        wires,
+        modules,
+        shards,
+        content_hash: Sha256::digest(&content).into(),
+        content_len: content.len() as u64,
+        compression: options.compression,
+        stored_len: stored.len() as u64,
+        signer: None,
+    };
+    let bytes = pack(&info, &stored, options.secret.as_ref())?;
+    if options.secret.is_some() {
+        info.signer = Some(
+            bytes[bytes.len() - SIGNATURE_LEN..][..32]
+                .try_into()
+                .unwrap(),
+        );
+    }
+    Ok((info, bytes))
+}
+
+/// Check and run the compiled script at `path` with the script arguments `args` (`key:value`),
+/// returns the exit code.
+///
+/// With `trusted` set, the binary must be signed by one of the keys, checked before the
+/// script is evaluated.
+pub(crate) fn load(
+    path: &Path,
+    trusted: Option<&TrustedKeys>,
+    args: &[&str],
+) -> Result<i32, Error> {
+    let bytes = std::fs::read(path)?;
+    if !bytes.starts_with(MAGIC) {
+        if trusted.is_some() {
+            return Err(invalid(path, "not signed, built without a header".into()));
+        }
+        // Written by `shards build` directly, nothing to check
+        return run(&bytes, path, args);
+    }
+    load_bytes(&bytes, path, trusted, args)
+}
+
+/// Like [`load`] for a binary with a header already in memory, `path` is only used in errors.
+pub(crate) fn load_bytes(
+    bytes: &[u8],
+    path: &Path,
+    trusted: Option<&TrustedKeys>,
+    args: &[&str],
+) -> Result<i32, Error> {
+    let (info, content) = unpack(bytes).map_err(|e| invalid(path, e))?;
+    if let Some(trusted) = trusted {
+        if !cfg!(feature = "crypto") {
+            return Err(Error::Binary(
+                "verifying signatures requires the `crypto` feature".to_string(),
+            ));
+        }
+        match &info.signer {
+            None => return Err(invalid(path, "not signed".into())),
+            Some(signer) if !trusted.trusts(signer) => {
+                return Err(invalid(
+                    path,
+                    format!("signed by untrusted key {}", hex(signer)),
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+    info.check()?;
+
+    // Evaluated from the verified bytes, never written back to disk where they could be
+    // swapped after the checks
+    run(&content, path, args)
+}
+
+/// Evaluate and run the serialized script `content` with the script arguments `args`.
+fn run(content: &[u8], path: &Path, args: &[&str]) -> Result<i32, Error> {
+    let mut runtime = Runtime::new();
+    for arg in args {
+        let Some((key, value)) = arg.split_once(':') else {
+            return Err(Error::Binary(format!(
+                "invalid script argument '{}', expected key:value",
+                arg
+            )));
+        };
+        runtime = runtime.define(key, value);
+    }
+    runtime.run_serialized(content, path)?;
+    Ok(0)
+}
+
+/// The wires of `wire` and the sorted, deduplicated names of their shards.
+fn summarize(wire: &Wire) -> (Vec<WireSummary>, Vec<String>) {
+    let wires = wires_of(wire)
+        .into_iter()
+        .map(|wire| unsafe {
+            let info = (*shards::core::Core).getWireInfo.unwrap()(wire);
+            WireSummary {
+                name: string_with_len(info.name),
+                shards: info.shards.len,
+            }
+        })
+        .collect();
+
+    let mut shards: Vec<String> = shards_of(wire)
+        .into_iter()
+        .map(|shard| unsafe { shard_name(shard) })
+        .collect();
+    shards.sort();
+    shards.dedup();
+    (wires, shards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(content: &[u8]) -> BinaryInfo {
+        BinaryInfo {
+            format_version: FORMAT_VERSION,
+            abi_version: 7,
+            wires: vec![WireSummary {
+                name: "root".to_string(),
+                shards: 3,
+            }],
+            modules: vec!["core".to_string()],
+            shards: vec!["Log".to_string(), "Msg".to_string()],
+            content_hash: Sha256::digest(content).into(),
+            content_len: content.len() as u64,
+            compression: Compression::None,
+            stored_len: content.len() as u64,
+            signer: None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let content = b"serialized script";
+        let bytes = pack(&info(content), content, None).unwrap();
+
+        let (unpacked, script) = unpack(&bytes).unwrap();
+        assert_eq!(unpacked, info(content));
+        assert_eq!(&*script, content);
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn signed_round_trip() {
+        let content = b"serialized script";
+        let mut bytes = pack(&info(content), content, Some(&[7; 32])).unwrap();
+
+        let (unpacked, script) = unpack(&bytes).unwrap();
+        assert!(unpacked.signer.is_some());
+        assert_eq!(&*script, content);
+
+        // The header is covered by the signature too
+        let abi = MAGIC.len() + 4;
+        bytes[abi] ^= 1;
+        assert!(unpack(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(unpack(b"").unwrap_err().contains("not a compiled script"));
+        assert!(unpack(b"SHPACK\0\0....")
+            .unwrap_err()
+            .contains("not a compiled script"));
+    }
+
+    #[test]
+    fn rejects_unsupported_versions_and_flags() {
+        let content = b"script";
+        let bytes = pack(&info(content), content, None).unwrap();
+
+        let mut version = bytes.clone();
+        version[MAGIC.len()] = 99;
+        assert!(unpack(&version).unwrap_err().contains("format version"));
+
+        let mut flags = bytes;
+        flags[MAGIC.len() + 8] = 0x80;
+        assert!(unpack(&flags).unwrap_err().contains("unsupported flags"));
+    }
+
+    #[test]
+    fn rejects_corrupted_content() {
+        let content = b"script";
+        let mut bytes = pack(&info(content), content, None).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(unpack(&bytes).unwrap_err().contains("hash mismatch"));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let content = b"script";
+        let bytes = pack(&info(content), content, None).unwrap();
+        for len in 0..bytes.len() {
+            assert!(unpack(&bytes[..len]).is_err(), "truncated to {}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_lengths_above_the_cap() {
+        // Checked before decompressing, whether the codec is built in or not
+        let content = b"script";
+        let mut header = info(content);
+        header.compression = Compression::Brotli;
+        header.content_len = MAX_DECOMPRESSED_LEN + 1;
+        let bytes = pack(&header, content, None).unwrap();
+        assert!(unpack(&bytes).unwrap_err().contains("more than"));
+    }
+}
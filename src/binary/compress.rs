@@ -0,0 +1,163 @@
+//! Compression of the serialized script, through the brotli and snappy libraries the core links
+//! with the `brotli` and `snappy` features.
+
+use super::{Compression, MAX_DECOMPRESSED_LEN};
+use crate::Error;
+
+#[cfg(feature = "brotli")]
+mod brotli {
+    use std::ffi::c_int;
+
+    // BROTLI_MODE_GENERIC, BROTLI_DEFAULT_WINDOW
+    pub(super) const MODE_GENERIC: c_int = 0;
+    pub(super) const DEFAULT_WINDOW: c_int = 22;
+    pub(super) const MAX_QUALITY: c_int = 11;
+    // BROTLI_DECODER_RESULT_SUCCESS
+    pub(super) const DECODER_SUCCESS: c_int = 1;
+
+    extern "C" {
+        pub(super) fn BrotliEncoderMaxCompressedSize(input_size: usize) -> usize;
+        pub(super) fn BrotliEncoderCompress(
+            quality: c_int,
+            lgwin: c_int,
+            mode: c_int,
+            input_size: usize,
+            input_buffer: *const u8,
+            encoded_size: *mut usize,
+            encoded_buffer: *mut u8,
+        ) -> c_int;
+        pub(super) fn BrotliDecoderDecompress(
+            encoded_size: usize,
+            encoded_buffer: *const u8,
+            decoded_size: *mut usize,
+            decoded_buffer: *mut u8,
+        ) -> c_int;
+    }
+}
+
+#[cfg(feature = "snappy")]
+mod snappy {
+    use std::ffi::{c_char, c_int};
+
+    // SNAPPY_OK
+    pub(super) const OK: c_int = 0;
+
+    extern "C" {
+        pub(super) fn snappy_max_compressed_length(source_length: usize) -> usize;
+        pub(super) fn snappy_compress(
+            input: *const c_char,
+            input_length: usize,
+            compressed: *mut c_char,
+            compressed_length: *mut usize,
+        ) -> c_int;
+        pub(super) fn snappy_uncompress(
+            compressed: *const c_char,
+            compressed_length: usize,
+            uncompressed: *mut c_char,
+            uncompressed_length: *mut usize,
+        ) -> c_int;
+    }
+}
+
+pub(super) fn compress(compression: Compression, data: &[u8]) -> Result<Vec<u8>, Error> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        #[cfg(feature = "brotli")]
+        Compression::Brotli => unsafe {
+            let mut out = vec![0u8; brotli::BrotliEncoderMaxCompressedSize(data.len())];
+            let mut len = out.len();
+            let ok = brotli::BrotliEncoderCompress(
+                brotli::MAX_QUALITY,
+                brotli::DEFAULT_WINDOW,
+                brotli::MODE_GENERIC,
+                data.len(),
+                data.as_ptr(),
+                &mut len,
+                out.as_mut_ptr(),
+            );
+            if ok == 0 || out.is_empty() {
+                return Err(Error::Binary("brotli compression failed".to_string()));
+            }
+            out.truncate(len);
+            Ok(out)
+        },
+        #[cfg(feature = "snappy")]
+        Compression::Snappy => unsafe {
+            let mut out = vec![0u8; snappy::snappy_max_compressed_length(data.len())];
+            let mut len = out.len();
+            let status = snappy::snappy_compress(
+                data.as_ptr() as *const _,
+                data.len(),
+                out.as_mut_ptr() as *mut _,
+                &mut len,
+            );
+            if status != snappy::OK {
+                return Err(Error::Binary("snappy compression failed".to_string()));
+            }
+            out.truncate(len);
+            Ok(out)
+        },
+        #[allow(unreachable_patterns)]
+        other => Err(Error::Binary(unavailable(other))),
+    }
+}
+
+/// Decompress `data` into the `len` bytes it was compressed from. `len` comes from the file,
+/// it is checked against [`MAX_DECOMPRESSED_LEN`] before allocating.
+pub(super) fn decompress(
+    compression: Compression,
+    data: &[u8],
+    len: u64,
+) -> Result<Vec<u8>, String> {
+    if compression == Compression::None {
+        return Ok(data.to_vec());
+    }
+    if len > MAX_DECOMPRESSED_LEN {
+        return Err(format!(
+            "the script decompresses to {} bytes, more than the {} bytes allowed",
+            len, MAX_DECOMPRESSED_LEN
+        ));
+    }
+
+    let len = len as usize;
+    let mut out = vec![0u8; len];
+    let mut out_len = len;
+    let ok = match compression {
+        #[cfg(feature = "brotli")]
+        Compression::Brotli => unsafe {
+            brotli::BrotliDecoderDecompress(
+                data.len(),
+                data.as_ptr(),
+                &mut out_len,
+                out.as_mut_ptr(),
+            ) == brotli::DECODER_SUCCESS
+        },
+        #[cfg(feature = "snappy")]
+        Compression::Snappy => unsafe {
+            snappy::snappy_uncompress(
+                data.as_ptr() as *const _,
+                data.len(),
+                out.as_mut_ptr() as *mut _,
+                &mut out_len,
+            ) == snappy::OK
+        },
+        #[allow(unreachable_patterns)]
+        other => return Err(unavailable(other)),
+    };
+
+    if !ok || out_len != len {
+        return Err(format!(
+            "{} decompression failed, the file is corrupted",
+            compression
+        ));
+    }
+    Ok(out)
+}
+
+#[allow(dead_code)]
+fn unavailable(compression: Compression) -> String {
+    format!(
+        "{} compression requires the `{}` feature",
+        compression, compression
+    )
+}
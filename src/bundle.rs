@@ -0,0 +1,89 @@
+//! Self-contained executables: a compiled script appended to a copy of the `shards` executable.
+//!
+//! Layout: the executable, the compiled script (see [`crate::binary`]), its length (u64, little
+//! endian) and the magic `SHBUNDLE`. On startup the `shards` binary looks for the trailer
+//! ([`embedded`]) and runs the script instead of the CLI, its arguments forwarded to the
+//! script as `key:value` arguments.
+//!
+//! ```bash
+//! shards bundle script.shs -o myapp
+//! ./myapp key:value
+//! ```
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::{BinaryInfo, BuildOptions, Error};
+
+const MAGIC: &[u8; 8] = b"SHBUNDLE";
+const TRAILER_LEN: u64 = 8 + 8;
+
+/// Compile the script at `input` like [`crate::binary::build`] and write the executable `exe`
+/// with the script appended to `output`. A script already bundled into `exe` is replaced.
+pub fn bundle(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    exe: &Path,
+    options: &BuildOptions,
+) -> Result<BinaryInfo, Error> {
+    let (info, script) = crate::binary::build_bytes(input.as_ref(), options)?;
+
+    let mut bytes = std::fs::read(exe)?;
+    if let Some(existing) = trailer(&bytes) {
+        let end = bytes.len() as u64 - TRAILER_LEN;
+        if existing <= end {
+            bytes.truncate((end - existing) as usize);
+        }
+    }
+    bytes.extend_from_slice(&script);
+    bytes.extend_from_slice(&(script.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(MAGIC);
+
+    let output = output.as_ref();
+    std::fs::write(output, &bytes)?;
+    // Executable like the original
+    std::fs::set_permissions(output, std::fs::metadata(exe)?.permissions())?;
+    Ok(info)
+}
+
+/// The compiled script bundled into the executable at `exe`, `None` for a plain executable.
+pub fn embedded(exe: &Path) -> Result<Option<Vec<u8>>, Error> {
+    let mut file = File::open(exe)?;
+    let len = file.metadata()?.len();
+    if len < TRAILER_LEN {
+        return Ok(None);
+    }
+
+    // Only the end of the file is read, executables can be large
+    let mut end = [0u8; TRAILER_LEN as usize];
+    file.seek(SeekFrom::Start(len - TRAILER_LEN))?;
+    file.read_exact(&mut end)?;
+    let Some(script_len) = trailer(&end) else {
+        return Ok(None);
+    };
+    if script_len > len - TRAILER_LEN {
+        return Err(Error::Binary(format!(
+            "{}: truncated bundle",
+            exe.display()
+        )));
+    }
+
+    let mut script = vec![0u8; script_len as usize];
+    file.seek(SeekFrom::Start(len - TRAILER_LEN - script_len))?;
+    file.read_exact(&mut script)?;
+    Ok(Some(script))
+}
+
+/// Check and run a script returned by [`embedded`] with the script arguments `args`, returns
+/// the exit code. `exe` is only used in errors.
+pub fn run(script: &[u8], exe: &Path, args: &[&str]) -> Result<i32, Error> {
+    crate::binary::load_bytes(script, exe, None, args)
+}
+
+/// Length of the bundled script if `bytes` ends with a bundle trailer.
+fn trailer(bytes: &[u8]) -> Option<u64> {
+    let end = bytes.strip_suffix(MAGIC)?;
+    let len = end.get(end.len().checked_sub(8)?..)?;
+    Some(u64::from_le_bytes(len.try_into().ok()?))
+}
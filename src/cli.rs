@@ -0,0 +1,834 @@
+//! Subcommands of the `shards` binary implemented by this crate.
+//!
+//! Each command takes the arguments following its name and returns the process exit code,
+//! one of [`exit_code`].
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+
+use crate::format::{diff_lines, DiffLine};
+use crate::includes::collect_includes;
+use crate::pack::FileKind;
+use crate::{BinaryInfo, BuildOptions, Error, Execution, RunOutcome, Runtime, SourcePack, TrustedKeys};
+
+pub(crate) mod capture;
+pub mod json;
+
+/// Exit codes of the `shards` binary, distinct per failure category.
+///
+/// Commands forwarded to the shards CLI return its own codes (0 on success, non-zero on failure).
+pub mod exit_code {
+    /// Everything succeeded.
+    pub const SUCCESS: i32 = 0;
+    /// A wire failed while running.
+    pub const FAILURE: i32 = 1;
+    /// Invalid command line arguments.
+    pub const USAGE: i32 = 2;
+    /// A file could not be read or written.
+    pub const IO: i32 = 3;
+    /// A source file has syntax errors.
+    pub const PARSE: i32 = 4;
+    /// Evaluating the script failed (unknown shards, invalid parameters, type errors).
+    pub const EVAL: i32 = 5;
+    /// The script uses a shard or accesses a path its sandbox denies.
+    pub const CAPABILITY: i32 = 6;
+    /// The script exceeded one of its resource quotas.
+    pub const QUOTA: i32 = 7;
+    /// A trace file is invalid, or a replay diverged from it.
+    pub const TRACE: i32 = 8;
+    /// A compiled script is invalid, corrupted, untrusted or built for another ABI.
+    pub const BINARY: i32 = 9;
+    /// A source pack is invalid or could not be created.
+    pub const PACK: i32 = 10;
+    /// `fmt --check` found unformatted files.
+    pub const UNFORMATTED: i32 = 11;
+    /// Unexpected internal error.
+    pub const INTERNAL: i32 = 70;
+}
+
+/// Format of the output of the `shards` binary, selected with the global `--output` flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Human,
+    /// Newline-delimited JSON events, see [`json`].
+    Json,
+}
+
+/// Remove the global `--output <format>` (or `--output=<format>`) flag from `args`.
+pub fn take_output_format(args: &mut Vec<String>) -> Result<OutputFormat, String> {
+    let Some(idx) = args
+        .iter()
+        .position(|a| a == "--output" || a.starts_with("--output="))
+    else {
+        return Ok(OutputFormat::Human);
+    };
+
+    let flag = args.remove(idx);
+    let value = match flag.strip_prefix("--output=") {
+        Some(value) => value.to_string(),
+        None if idx < args.len() => args.remove(idx),
+        None => return Err("--output requires a value (human or json)".to_string()),
+    };
+
+    match value.as_str() {
+        "human" => Ok(OutputFormat::Human),
+        "json" => Ok(OutputFormat::Json),
+        other => Err(format!("Unknown output format '{}' (expected human or json)", other)),
+    }
+}
+
+/// Failure category (as reported in JSON output) and exit code of an error.
+pub(crate) fn classify(error: &Error) -> (&'static str, i32) {
+    match error {
+        Error::Io(_) => ("io", exit_code::IO),
+        Error::Format(_) | Error::Parse { .. } => ("parse", exit_code::PARSE),
+        Error::Eval { .. } => ("eval", exit_code::EVAL),
+        Error::CapabilityDenied { .. } => ("capability", exit_code::CAPABILITY),
+        Error::QuotaExceeded { .. } => ("quota", exit_code::QUOTA),
+        Error::WireFailed(_) => ("wire", exit_code::FAILURE),
+        Error::Trace(_) => ("trace", exit_code::TRACE),
+        Error::Binary(_) => ("binary", exit_code::BINARY),
+        Error::Pack(_) => ("pack", exit_code::PACK),
+    }
+}
+
+/// Receives the errors of a command: their category (see [`classify`]), message and source
+/// location. Printed to stderr, or emitted as `error` events with `--output json`.
+pub(crate) type Report<'a> = &'a dyn Fn(&str, &str, Option<(u32, u32)>);
+
+fn print_error(category: &str, message: &str, _location: Option<(u32, u32)>) {
+    match category {
+        "usage" => eprintln!("{}", message),
+        _ => eprintln!("Error: {}", message),
+    }
+}
+
+/// Report `error`, prefixed with the file it concerns if any, returns its exit code.
+pub(crate) fn report_error(report: Report, file: Option<&Path>, error: &Error) -> i32 {
+    let (category, code) = classify(error);
+    let message = match file {
+        Some(file) => format!("{}: {}", file.display(), error),
+        None => error.to_string(),
+    };
+    let location = match error {
+        Error::Parse { line, column, .. } | Error::Eval { line, column, .. } => Some((*line, *column)),
+        _ => None,
+    };
+    report(category, &message, location);
+    code
+}
+
+// Unchanged lines shown around each change in `fmt --check` output
+const DIFF_CONTEXT: usize = 3;
+
+// Quiet period after the last file event before re-running
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+const WATCH_POLL: Duration = Duration::from_millis(50);
+
+/// `shards fmt [--check] <paths>...`
+///
+/// Formats `.shs` files in place, directories are searched recursively.
+/// With `--check` nothing is written, files that would change are reported with a diff
+/// and the command fails with [`exit_code::UNFORMATTED`].
+pub fn fmt(args: &[String]) -> i32 {
+    fmt_reporting(args, &print_error)
+}
+
+pub(crate) fn fmt_reporting(args: &[String], report: Report) -> i32 {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            "-h" | "--help" => {
+                println!("Usage: shards fmt [--check] <paths>...");
+                return exit_code::SUCCESS;
+            }
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+
+    if paths.is_empty() {
+        report("usage", "Usage: shards fmt [--check] <paths>...", None);
+        return exit_code::USAGE;
+    }
+
+    let mut files = Vec::new();
+    for path in &paths {
+        if let Err(e) = collect_sources(path, &mut files) {
+            return report_error(report, Some(path), &e.into());
+        }
+    }
+
+    // Report the most severe failure, but keep going through all files
+    let mut code = exit_code::SUCCESS;
+    let mut unformatted = false;
+    for file in &files {
+        let original = match std::fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(e) => {
+                code = code.max(report_error(report, Some(file), &e.into()));
+                continue;
+            }
+        };
+
+        let formatted = match crate::format_source(&original) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                code = code.max(report_error(report, Some(file), &e));
+                continue;
+            }
+        };
+
+        if formatted == original {
+            continue;
+        }
+
+        if check {
+            print_diff(file, &original, &formatted);
+            unformatted = true;
+        } else if let Err(e) = std::fs::write(file, &formatted) {
+            code = code.max(report_error(report, Some(file), &e.into()));
+        }
+    }
+
+    // Errors take precedence over unformatted files
+    if code == exit_code::SUCCESS && unformatted {
+        exit_code::UNFORMATTED
+    } else {
+        code
+    }
+}
+
+fn collect_sources(path: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+
+    for entry in entries {
+        if entry.is_dir() {
+            collect_sources(&entry, files)?;
+        } else if entry.extension().is_some_and(|ext| ext == "shs") {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+fn print_diff(file: &Path, original: &str, formatted: &str) {
+    let diff = diff_lines(original, formatted);
+    let changed: Vec<usize> = diff
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, DiffLine::Same(_)))
+        .map(|(i, _)| i)
+        .collect();
+
+    println!("--- {}", file.display());
+    println!("+++ {} (formatted)", file.display());
+
+    let mut last_printed = None;
+    for (i, line) in diff.iter().enumerate() {
+        let near_change = changed
+            .iter()
+            .any(|&c| c + DIFF_CONTEXT >= i && c <= i + DIFF_CONTEXT);
+        if !near_change {
+            continue;
+        }
+        if last_printed.is_some_and(|last| last + 1 != i) {
+            println!("...");
+        }
+        match line {
+            DiffLine::Same(l) => println!(" {}", l),
+            DiffLine::Removed(l) => println!("-{}", l),
+            DiffLine::Added(l) => println!("+{}", l),
+        }
+        last_printed = Some(i);
+    }
+}
+
+/// `shards run --profile [--profile-output <trace.json>] [<run options>] <file> [key:value]...`
+///
+/// Runs the script in-process with the profiler enabled, prints a report sorted by self time
+/// to stderr and writes a Chrome trace-event file (`shards-profile.json` by default).
+/// `args` are the `run` arguments with `--profile` already removed.
+pub fn profile(args: &[String]) -> i32 {
+    const USAGE: &str = "Usage: shards run --profile [--profile-output <trace.json>] [--seed <n>] [--virtual-time] [--record <trace>] <file> [key:value]...";
+
+    let RunArgs { runtime, file, profile_output, .. } = match RunArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return exit_code::USAGE;
+        }
+    };
+    let trace_path = profile_output.unwrap_or_else(|| PathBuf::from("shards-profile.json"));
+
+    let outcome = match runtime.profile(true).run_file(file) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return classify(&e).1;
+        }
+    };
+
+    let Some(profile) = outcome.profile else {
+        return exit_code::SUCCESS;
+    };
+
+    eprintln!("{}", profile.report());
+    match profile.write_chrome_trace(&trace_path) {
+        Ok(()) => {
+            eprintln!("Trace written to {}", trace_path.display());
+            exit_code::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error: {}: {}", trace_path.display(), e);
+            exit_code::IO
+        }
+    }
+}
+
+/// `shards run [--seed <n>] [--virtual-time] [--record <trace>] <file> [key:value]...`
+///
+/// Runs the script in-process, for the options the shards CLI does not know: `--seed` and
+/// `--virtual-time` make the run deterministic (see [`crate::deterministic`]), `--record`
+/// writes its inputs to a trace file for `shards replay` (see [`crate::replay`]). A `.shpack`
+/// file is run from the pack (see [`crate::pack`]).
+pub fn run(args: &[String]) -> i32 {
+    const USAGE: &str =
+        "Usage: shards run [--seed <n>] [--virtual-time] [--record <trace>] <file> [key:value]...";
+
+    let RunArgs { runtime, file, .. } = match RunArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return exit_code::USAGE;
+        }
+    };
+
+    match run_path(&runtime, &file) {
+        Ok(_) => exit_code::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            classify(&e).1
+        }
+    }
+}
+
+/// Run a script file, or the entry script of a source pack.
+pub(crate) fn run_path(runtime: &Runtime, file: &str) -> Result<RunOutcome, Error> {
+    runtime.run(start_path(runtime, file)?)
+}
+
+/// Schedule a script file, or the entry script of a source pack, without ticking.
+pub(crate) fn start_path(runtime: &Runtime, file: &str) -> Result<Execution, Error> {
+    if is_pack(file) {
+        runtime.start_pack(Arc::new(SourcePack::read(file)?))
+    } else {
+        runtime.start_file(file)
+    }
+}
+
+/// Whether `file` is a source pack, by its extension.
+pub fn is_pack(file: &str) -> bool {
+    Path::new(file).extension().is_some_and(|ext| ext == "shpack")
+}
+
+/// The options of the `run` arguments `args`: the ones before the script path, with their
+/// values. Whatever follows the script path belongs to the script.
+pub fn run_options(args: &[String]) -> &[String] {
+    let mut len = 0;
+    while let Some(arg) = args.get(len) {
+        match arg.as_str() {
+            "--seed" | "--record" | "--profile-output" => len += 2,
+            option if option.starts_with("--") => len += 1,
+            _ => break,
+        }
+    }
+    &args[..len.min(args.len())]
+}
+
+/// The `run` arguments `args` without the option `option`, kept if it follows the script path.
+pub fn without_run_option(args: &[String], option: &str) -> Vec<String> {
+    let options = run_options(args).len();
+    args.iter()
+        .enumerate()
+        .filter(|&(i, arg)| i >= options || arg != option)
+        .map(|(_, arg)| arg.clone())
+        .collect()
+}
+
+/// The `run` arguments: the options, then the script path, then the `key:value` defines of
+/// the script. Shared by `run` and its `--profile` and `--watch` modes.
+pub(crate) struct RunArgs {
+    /// Runtime set up with the options and the defines.
+    pub(crate) runtime: Runtime,
+    pub(crate) file: String,
+    /// `--profile-output <trace.json>`
+    pub(crate) profile_output: Option<PathBuf>,
+    /// `--clear`, for `--watch`.
+    pub(crate) clear: bool,
+}
+
+impl RunArgs {
+    /// Parse the `run` arguments `args`, with `--watch` already removed. The options stop at
+    /// the script path (see [`run_options`]), the script gets whatever follows as is.
+    pub(crate) fn parse(args: &[String]) -> Result<RunArgs, String> {
+        let options = run_options(args);
+        let mut runtime = Runtime::new();
+        let mut profile_output = None;
+        let mut clear = false;
+
+        let mut iter = options.iter();
+        while let Some(option) = iter.next() {
+            let mut value = || iter.next().ok_or_else(|| format!("Missing value for {}", option));
+            match option.as_str() {
+                "--virtual-time" => runtime = runtime.virtual_time(true),
+                "--seed" => {
+                    let seed = value()?;
+                    let seed = seed
+                        .parse()
+                        .map_err(|_| format!("Invalid seed '{}', expected an unsigned integer", seed))?;
+                    runtime = runtime.seed(seed);
+                }
+                "--record" => runtime = runtime.record(value()?),
+                "--profile" => runtime = runtime.profile(true),
+                "--profile-output" => profile_output = Some(PathBuf::from(value()?)),
+                "--clear" => clear = true,
+                _ => return Err(format!("Unknown option '{}'", option)),
+            }
+        }
+
+        let Some((file, defines)) = args[options.len()..].split_first() else {
+            return Err("Missing script path".to_string());
+        };
+        for define in defines {
+            match define.split_once(':') {
+                Some((key, value)) => runtime = runtime.define(key, value),
+                None => return Err(format!("Invalid argument '{}', expected key:value", define)),
+            }
+        }
+
+        Ok(RunArgs {
+            runtime,
+            file: file.clone(),
+            profile_output,
+            clear,
+        })
+    }
+}
+
+/// `shards replay <trace>`
+///
+/// Runs the script recorded by `shards run --record` again, feeding back the recorded inputs.
+pub fn replay(args: &[String]) -> i32 {
+    let [trace] = args else {
+        eprintln!("Usage: shards replay <trace>");
+        return exit_code::USAGE;
+    };
+
+    match Runtime::new().replay_file(trace) {
+        Ok(_) => exit_code::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            classify(&e).1
+        }
+    }
+}
+
+/// `shards build <file> -o <output> [--compress brotli|snappy] [--sign <key>]`
+///
+/// Compiles the script into a binary with a header, see [`crate::binary`]. With `--sign` the
+/// binary is signed with the Ed25519 secret key stored at `key`.
+pub fn build(args: &[String]) -> i32 {
+    build_reporting(args, &print_error)
+}
+
+pub(crate) fn build_reporting(args: &[String], report: Report) -> i32 {
+    const USAGE: &str = "Usage: shards build <file> -o <output> [--compress brotli|snappy] [--sign <key>]";
+
+    build_command(args, USAGE, report, |input, output, options| {
+        crate::binary::build(input, output, options)
+    })
+}
+
+/// `shards bundle <file> -o <output> [--compress brotli|snappy] [--sign <key>]`
+///
+/// Compiles the script like `build` and appends it to a copy of this executable, which runs it
+/// on startup, see [`crate::bundle`].
+pub fn bundle(args: &[String]) -> i32 {
+    bundle_reporting(args, &print_error)
+}
+
+pub(crate) fn bundle_reporting(args: &[String], report: Report) -> i32 {
+    const USAGE: &str = "Usage: shards bundle <file> -o <output> [--compress brotli|snappy] [--sign <key>]";
+
+    build_command(args, USAGE, report, |input, output, options| {
+        let exe = std::env::current_exe()?;
+        crate::bundle::bundle(input, output, &exe, options)
+    })
+}
+
+/// Parse the arguments shared by `build` and `bundle` and run `build`.
+fn build_command(
+    args: &[String],
+    usage: &str,
+    report: Report,
+    build: impl FnOnce(&str, &str, &BuildOptions) -> Result<BinaryInfo, Error>,
+) -> i32 {
+    let (mut input, mut output, mut key) = (None, None, None);
+    let mut options = BuildOptions::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = iter.next(),
+            "--sign" => key = iter.next(),
+            "--compress" => match iter.next().map(|c| c.parse()) {
+                Some(Ok(compression)) => options = options.compression(compression),
+                Some(Err(e)) => {
+                    report("usage", &format!("{}\n{}", e, usage), None);
+                    return exit_code::USAGE;
+                }
+                None => {
+                    report("usage", usage, None);
+                    return exit_code::USAGE;
+                }
+            },
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg),
+            _ => {
+                report("usage", usage, None);
+                return exit_code::USAGE;
+            }
+        }
+    }
+    let (Some(input), Some(output)) = (input, output) else {
+        report("usage", usage, None);
+        return exit_code::USAGE;
+    };
+
+    let built = match key {
+        Some(key) => crate::binary::read_key(key)
+            .and_then(|secret| build(input, output, &options.sign(secret))),
+        None => build(input, output, &options),
+    };
+    match built {
+        Ok(info) => {
+            if let Some(signer) = info.signer_hex() {
+                eprintln!("Signed with public key {}", signer);
+            }
+            exit_code::SUCCESS
+        }
+        Err(e) => report_error(report, None, &e),
+    }
+}
+
+/// Run the script bundled into this executable by `shards bundle`, with `args` (excluding the
+/// program name) as its arguments. `None` if nothing is bundled.
+pub fn run_bundled(args: &[String]) -> Option<i32> {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("Error: cannot locate the executable: {}", e);
+            return Some(exit_code::IO);
+        }
+    };
+    let script = match crate::bundle::embedded(&exe) {
+        Ok(script) => script?,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return Some(classify(&e).1);
+        }
+    };
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match crate::bundle::run(&script, &exe, &args) {
+        Ok(code) => Some(code),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            Some(classify(&e).1)
+        }
+    }
+}
+
+/// `shards pack <dir> -o <output> [--entry <script>] [--asset <path>]...`
+///
+/// Packs the entry script of `dir` (`main.shs` by default), its includes and the assets it
+/// references into a source pack, see [`crate::pack`].
+pub fn pack(args: &[String]) -> i32 {
+    pack_reporting(args, &print_error)
+}
+
+pub(crate) fn pack_reporting(args: &[String], report: Report) -> i32 {
+    const USAGE: &str =
+        "Usage: shards pack <dir> -o <output> [--entry <script>] [--asset <path>]...";
+
+    let (mut dir, mut output, mut entry) = (None, None, None);
+    let mut assets = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = iter.next(),
+            "--entry" => entry = iter.next(),
+            "--asset" => match iter.next() {
+                Some(asset) => assets.push(asset),
+                None => {
+                    report("usage", USAGE, None);
+                    return exit_code::USAGE;
+                }
+            },
+            _ if dir.is_none() && !arg.starts_with('-') => dir = Some(arg),
+            _ => {
+                report("usage", USAGE, None);
+                return exit_code::USAGE;
+            }
+        }
+    }
+    let (Some(dir), Some(output)) = (dir, output) else {
+        report("usage", USAGE, None);
+        return exit_code::USAGE;
+    };
+
+    let entry = entry.map_or("main.shs", String::as_str);
+    let packed = SourcePack::from_dir(dir, entry).and_then(|mut pack| {
+        for asset in assets {
+            pack.add_asset(dir, asset)?;
+        }
+        pack.write(output)?;
+        Ok(pack)
+    });
+    match packed {
+        Ok(pack) => {
+            for (path, file) in pack.files() {
+                let kind = match file.kind {
+                    FileKind::Source => "source",
+                    FileKind::Asset => "asset",
+                };
+                eprintln!("  {:<6} {} ({} bytes)", kind, path, file.content.len());
+            }
+            exit_code::SUCCESS
+        }
+        Err(e) => report_error(report, None, &e),
+    }
+}
+
+/// `shards load [--trust <public key>]... <file>`
+///
+/// Checks the header of a compiled script (see [`crate::BinaryInfo::check`]) and runs it.
+/// With `--trust` the binary must be signed by one of the given keys.
+pub fn load(args: &[String]) -> i32 {
+    load_reporting(args, &print_error)
+}
+
+pub(crate) fn load_reporting(args: &[String], report: Report) -> i32 {
+    const USAGE: &str = "Usage: shards load [--trust <public key>]... <file>";
+
+    let mut file = None;
+    let mut trusted: Option<TrustedKeys> = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--trust" => {
+                let Some(key) = iter.next() else {
+                    report("usage", USAGE, None);
+                    return exit_code::USAGE;
+                };
+                match trusted.take().unwrap_or_default().key_file(key) {
+                    Ok(keys) => trusted = Some(keys),
+                    Err(e) => return report_error(report, None, &e),
+                }
+            }
+            _ if file.is_none() => file = Some(arg),
+            _ => {
+                report("usage", USAGE, None);
+                return exit_code::USAGE;
+            }
+        }
+    }
+    let Some(file) = file else {
+        report("usage", USAGE, None);
+        return exit_code::USAGE;
+    };
+
+    match crate::binary::load(Path::new(file), trusted.as_ref(), &[]) {
+        Ok(code) => code,
+        Err(e) => report_error(report, None, &e),
+    }
+}
+
+/// `shards inspect <file>`
+///
+/// Prints the header of a compiled script, then fails if this build cannot load it.
+pub fn inspect(args: &[String]) -> i32 {
+    let [file] = args else {
+        eprintln!("Usage: shards inspect <file>");
+        return exit_code::USAGE;
+    };
+
+    let info = match BinaryInfo::read_unchecked(file) {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return classify(&e).1;
+        }
+    };
+
+    println!("Format version: {}", info.format_version);
+    println!("ABI version:    {:#x}", info.abi_version);
+    println!("Content:        {} bytes", info.content_len);
+    if info.compression != crate::Compression::None {
+        println!(
+            "Compression:    {} ({} bytes stored)",
+            info.compression, info.stored_len
+        );
+    }
+    println!("Content hash:   sha256:{}", info.content_hash_hex());
+    println!("Modules:        {}", info.modules.join(", "));
+    if let Some(signer) = info.signer_hex() {
+        println!("Signed by:      {}", signer);
+    }
+    println!("Wires:");
+    for wire in &info.wires {
+        println!("  {} ({} shards)", wire.name, wire.shards);
+    }
+
+    match info.check() {
+        Ok(()) => exit_code::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            classify(&e).1
+        }
+    }
+}
+
+/// `shards run --watch [--clear] <file> [args]...`
+///
+/// Runs the script in a child `shards run` process and restarts it whenever the entry file
+/// or any file it `@include`s changes. Errors are printed and the watcher keeps going.
+/// `args` are the `run` arguments with `--watch` already removed.
+pub fn watch(args: &[String]) -> i32 {
+    const USAGE: &str = "Usage: shards run --watch [--clear] <file> [args]...";
+
+    let RunArgs { file, clear, .. } = match RunArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return exit_code::USAGE;
+        }
+    };
+    let entry = PathBuf::from(file);
+    // Only the option, a `--clear` after the script path is the script's
+    let run_args = without_run_option(args, "--clear");
+
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("Error: cannot locate the shards executable: {}", e);
+            return exit_code::INTERNAL;
+        }
+    };
+
+    loop {
+        let files = watched_files(&entry);
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = match notify::recommended_watcher(tx) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                eprintln!("Error: failed to start file watcher: {}", e);
+                return exit_code::INTERNAL;
+            }
+        };
+
+        // Watch directories rather than files, editors often save by replacing the file
+        let dirs: HashSet<PathBuf> = files
+            .iter()
+            .map(|f| f.parent().map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from(".")))
+            .collect();
+        for dir in &dirs {
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                eprintln!("Warning: cannot watch {}: {}", dir.display(), e);
+            }
+        }
+
+        if clear {
+            print!("\x1b[2J\x1b[H");
+            let _ = std::io::stdout().flush();
+        }
+
+        let mut child = spawn_run(&exe, &run_args);
+
+        // Wait for a relevant change, reporting the run's exit status meanwhile
+        loop {
+            match rx.recv_timeout(WATCH_POLL) {
+                Ok(Ok(event)) if event.paths.iter().any(|p| is_watched(p, &files)) => break,
+                Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return exit_code::INTERNAL,
+            }
+
+            if let Some(running) = child.as_mut() {
+                if let Ok(Some(status)) = running.try_wait() {
+                    eprintln!(
+                        "[watch] {} exited with {}, waiting for changes...",
+                        entry.display(),
+                        status
+                    );
+                    child = None;
+                }
+            }
+        }
+
+        // Debounce bursts of events from a single save
+        while rx.recv_timeout(WATCH_DEBOUNCE).is_ok() {}
+
+        if let Some(mut running) = child {
+            let _ = running.kill();
+            let _ = running.wait();
+        }
+        eprintln!("[watch] change detected, restarting {}", entry.display());
+    }
+}
+
+fn spawn_run(exe: &Path, run_args: &[String]) -> Option<Child> {
+    match Command::new(exe).arg("run").args(run_args).spawn() {
+        Ok(child) => Some(child),
+        Err(e) => {
+            eprintln!("Error: failed to start script: {}", e);
+            None
+        }
+    }
+}
+
+/// The entry file plus everything it currently includes.
+fn watched_files(entry: &Path) -> Vec<PathBuf> {
+    let mut files = vec![entry.to_path_buf()];
+    if let Ok(source) = std::fs::read_to_string(entry) {
+        let dir = entry.parent().unwrap_or(Path::new("."));
+        let mut visited = HashSet::new();
+        visited.insert(entry.to_path_buf());
+        let mut sources = Vec::new();
+        collect_includes(&source, dir, &mut visited, &mut sources);
+        files.extend(sources.into_iter().map(|(path, _)| path));
+    }
+    files
+        .into_iter()
+        .map(|f| f.canonicalize().unwrap_or(f))
+        .collect()
+}
+
+fn is_watched(path: &Path, files: &[PathBuf]) -> bool {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    files.contains(&path)
+}
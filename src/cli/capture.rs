@@ -0,0 +1,113 @@
+//! Redirection of the process stdout/stderr while the runtime runs.
+//!
+//! Used by the front ends whose protocol owns stdout (`--output json`, `shards dap`).
+//! Only implemented on Unix, elsewhere output goes straight to the console.
+
+use std::io::{self, Write};
+
+pub(crate) type Output = Box<dyn Write + Send>;
+
+/// A handle to the real stdout that stays valid while a [`Capture`] is active.
+///
+/// Must be called before starting the capture.
+#[cfg(unix)]
+pub(crate) fn original_stdout() -> io::Result<Output> {
+    use std::os::fd::FromRawFd;
+
+    io::stdout().flush()?;
+    let fd = unsafe { libc::dup(1) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Box::new(unsafe { std::fs::File::from_raw_fd(fd) }))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn original_stdout() -> io::Result<Output> {
+    Ok(Box::new(io::stdout()))
+}
+
+pub(crate) struct Capture {
+    #[cfg(unix)]
+    saved: [std::os::fd::RawFd; 2],
+    #[cfg(unix)]
+    readers: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl Capture {
+    /// Forward every line written to stdout/stderr to `sink(stream, line)` until [`Capture::finish`],
+    /// `stream` being `"stdout"` or `"stderr"`.
+    #[cfg(unix)]
+    pub(crate) fn start<F>(sink: F) -> io::Result<Self>
+    where
+        F: Fn(&'static str, String) + Send + Clone + 'static,
+    {
+        use std::fs::File;
+        use std::io::{BufRead, BufReader};
+        use std::os::fd::FromRawFd;
+
+        io::stdout().flush()?;
+        io::stderr().flush()?;
+
+        unsafe {
+            let saved = [libc::dup(1), libc::dup(2)];
+            if saved.iter().any(|fd| *fd < 0) {
+                let error = io::Error::last_os_error();
+                for fd in saved.into_iter().filter(|fd| *fd >= 0) {
+                    libc::close(fd);
+                }
+                return Err(error);
+            }
+
+            let mut readers = Vec::new();
+            for (fd, stream) in [(1, "stdout"), (2, "stderr")] {
+                let mut pipe = [0; 2];
+                if libc::pipe(pipe.as_mut_ptr()) != 0 {
+                    let error = io::Error::last_os_error();
+                    // Puts back the stream already captured, if any
+                    Capture { saved, readers }.finish();
+                    return Err(error);
+                }
+                libc::dup2(pipe[1], fd);
+                libc::close(pipe[1]);
+
+                let reader = BufReader::new(File::from_raw_fd(pipe[0]));
+                let sink = sink.clone();
+                readers.push(std::thread::spawn(move || {
+                    for line in reader.lines().map_while(Result::ok) {
+                        sink(stream, line);
+                    }
+                }));
+            }
+
+            Ok(Capture { saved, readers })
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn start<F>(_sink: F) -> io::Result<Self>
+    where
+        F: Fn(&'static str, String) + Send + Clone + 'static,
+    {
+        Ok(Capture {})
+    }
+
+    /// Restore stdout/stderr and wait until all captured output went through the sink.
+    pub(crate) fn finish(self) {
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+
+        #[cfg(unix)]
+        unsafe {
+            // Closes the write ends of the pipes, the readers stop at EOF
+            libc::dup2(self.saved[0], 1);
+            libc::dup2(self.saved[1], 2);
+            libc::close(self.saved[0]);
+            libc::close(self.saved[1]);
+
+            for reader in self.readers {
+                let _ = reader.join();
+            }
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! `--output json`: machine-readable output of the `shards` binary.
+//!
+//! Everything is written to stdout as newline-delimited JSON objects, each with an `event` field:
+//!
+//! - `log`: `{"event": "log", "stream": "stdout" | "stderr", "message": "..."}`
+//!   for every line the runtime printed while the command ran.
+//! - `error`: `{"event": "error", "category": "...", "message": "...", "line": 1, "column": 1}`,
+//!   `category` is one of `usage`, `io`, `parse`, `eval`, `capability`, `quota`,
+//!   `wire`, `trace`, `binary`, `pack`, `internal`.
+//!   `line` and `column` are only present for `parse` and `eval` errors. Every command reports
+//!   its errors this way, `fmt` once per failing file.
+//! - `timing`: `{"event": "timing", "phase": "parse" | "eval" | "run", "duration_ms": 1.5}`,
+//!   for `run` and `replay` once the script is evaluated, also when its wire fails.
+//! - `result`: `{"event": "result", "status": "success" | "failure", "exit_code": 0, "duration_ms": 1.5}`,
+//!   always the last event. For `run` and `replay` it also carries the root wire's final
+//!   `output` and the number of `ticks` (and with `--profile`, the `profile_output` trace file
+//!   written), for `inspect` the fields of [`crate::BinaryInfo`] (`content_hash` as hex).
+//!
+//! The process exit code is the same as `exit_code` (see [`super::exit_code`]).
+//! Output of the runtime is only captured on Unix, elsewhere it goes straight to the console.
+
+use std::ffi::{c_char, CString};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Map, Value};
+
+use super::capture::{original_stdout, Capture, Output};
+use super::{classify, exit_code, RunArgs};
+use crate::{BinaryInfo, Error, Execution, RunOutcome, Runtime, Trace};
+
+/// Serializes events to the original stdout, shared with the capture threads.
+#[derive(Clone)]
+struct Emitter(Arc<Mutex<Output>>);
+
+impl Emitter {
+    fn emit(&self, event: Value) {
+        let mut out = self.0.lock().unwrap();
+        let _ = writeln!(out, "{}", event);
+        let _ = out.flush();
+    }
+
+    fn error(&self, category: &str, message: impl Into<String>) {
+        self.error_at(category, &message.into(), None);
+    }
+
+    fn error_at(&self, category: &str, message: &str, location: Option<(u32, u32)>) {
+        let mut event = json!({ "event": "error", "category": category, "message": message });
+        if let Some((line, column)) = location {
+            event["line"] = line.into();
+            event["column"] = column.into();
+        }
+        self.emit(event);
+    }
+
+    fn timing(&self, phase: &str, duration: Duration) {
+        self.emit(json!({ "event": "timing", "phase": phase, "duration_ms": millis(duration) }));
+    }
+}
+
+/// Run the command in `args` (program name first, `--output` already removed) with JSON output.
+pub fn run(args: &[String]) -> i32 {
+    let start = Instant::now();
+
+    let emitter = match original_stdout() {
+        Ok(out) => Emitter(Arc::new(Mutex::new(out))),
+        Err(e) => {
+            eprintln!("Error: failed to capture output: {}", e);
+            return exit_code::INTERNAL;
+        }
+    };
+
+    let sink = emitter.clone();
+    let capture = match Capture::start(move |stream, line| {
+        sink.emit(json!({ "event": "log", "stream": stream, "message": line }));
+    }) {
+        Ok(capture) => capture,
+        Err(e) => {
+            emitter.error("internal", format!("Failed to capture output: {}", e));
+            emitter.emit(result(exit_code::INTERNAL, start.elapsed(), Map::new()));
+            return exit_code::INTERNAL;
+        }
+    };
+
+    let report = |category: &str, message: &str, location| emitter.error_at(category, message, location);
+    let (code, extra) = match args.get(1).map(String::as_str) {
+        Some(command @ ("lsp" | "dap")) => {
+            emitter.error("usage", format!("{} does not support --output json", command));
+            (exit_code::USAGE, Map::new())
+        }
+        Some("run") if super::run_options(&args[2..]).iter().any(|a| a == "--watch") => {
+            emitter.error("usage", "run --watch does not support --output json");
+            (exit_code::USAGE, Map::new())
+        }
+        Some("run") => run_script(&args[2..], &emitter),
+        Some("replay") => replay_script(&args[2..], &emitter),
+        Some("fmt") => (super::fmt_reporting(&args[2..], &report), Map::new()),
+        Some("build") => (super::build_reporting(&args[2..], &report), Map::new()),
+        Some("pack") => (super::pack_reporting(&args[2..], &report), Map::new()),
+        Some("bundle") => (super::bundle_reporting(&args[2..], &report), Map::new()),
+        Some("load") => (super::load_reporting(&args[2..], &report), Map::new()),
+        Some("inspect") => inspect_binary(&args[2..], &emitter),
+        _ => (passthrough(args), Map::new()),
+    };
+
+    capture.finish();
+    emitter.emit(result(code, start.elapsed(), extra));
+    code
+}
+
+fn run_script(args: &[String], emitter: &Emitter) -> (i32, Map<String, Value>) {
+    const USAGE: &str = "Usage: shards run [--profile [--profile-output <trace.json>]] [--seed <n>] [--virtual-time] [--record <trace>] <file> [key:value]...";
+
+    let RunArgs { runtime, file, profile_output, .. } = match RunArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            emitter.error("usage", format!("{}\n{}", e, USAGE));
+            return (exit_code::USAGE, Map::new());
+        }
+    };
+
+    let mut run = run_timed(&runtime, super::start_path(&runtime, &file), emitter);
+    let profile = run.as_mut().ok().and_then(|outcome| outcome.profile.take());
+    let (code, mut extra) = report(run, emitter);
+    if let Some(profile) = profile {
+        let path = profile_output.unwrap_or_else(|| PathBuf::from("shards-profile.json"));
+        if let Err(e) = profile.write_chrome_trace(&path) {
+            emitter.error("io", format!("{}: {}", path.display(), e));
+            return (exit_code::IO, extra);
+        }
+        extra.insert("profile_output".to_string(), path.display().to_string().into());
+    }
+    (code, extra)
+}
+
+fn replay_script(args: &[String], emitter: &Emitter) -> (i32, Map<String, Value>) {
+    let [trace] = args else {
+        emitter.error("usage", "Usage: shards replay <trace>");
+        return (exit_code::USAGE, Map::new());
+    };
+    let run = Trace::read(trace).and_then(|trace| {
+        let (source, script) = (trace.source.clone(), trace.path.clone());
+        let runtime = Runtime::new().replaying(trace);
+        let execution = runtime.start_source(&source, &script);
+        run_timed(&runtime, execution, emitter)
+    });
+    report(run, emitter)
+}
+
+fn inspect_binary(args: &[String], emitter: &Emitter) -> (i32, Map<String, Value>) {
+    let [file] = args else {
+        emitter.error("usage", "Usage: shards inspect <file>");
+        return (exit_code::USAGE, Map::new());
+    };
+
+    let info = match BinaryInfo::read_unchecked(file) {
+        Ok(info) => info,
+        Err(e) => {
+            let (category, code) = classify(&e);
+            emitter.error(category, e.to_string());
+            return (code, Map::new());
+        }
+    };
+
+    let mut extra = Map::new();
+    extra.insert("format_version".to_string(), info.format_version.into());
+    extra.insert("abi_version".to_string(), info.abi_version.into());
+    extra.insert("content_len".to_string(), info.content_len.into());
+    extra.insert("compression".to_string(), info.compression.to_string().into());
+    extra.insert("stored_len".to_string(), info.stored_len.into());
+    extra.insert("content_hash".to_string(), info.content_hash_hex().into());
+    extra.insert("modules".to_string(), info.modules.clone().into());
+    extra.insert("signer".to_string(), info.signer_hex().into());
+    extra.insert(
+        "wires".to_string(),
+        info.wires
+            .iter()
+            .map(|wire| json!({ "name": wire.name, "shards": wire.shards }))
+            .collect(),
+    );
+
+    match info.check() {
+        Ok(()) => (exit_code::SUCCESS, extra),
+        Err(e) => {
+            let (category, code) = classify(&e);
+            emitter.error(category, e.to_string());
+            (code, extra)
+        }
+    }
+}
+
+/// Run `execution` to the end, emitting its timings whether it succeeds or fails.
+fn run_timed(runtime: &Runtime, execution: Result<Execution, Error>, emitter: &Emitter) -> Result<RunOutcome, Error> {
+    let execution = execution?;
+    emitter.timing("parse", execution.parse_time());
+    emitter.timing("eval", execution.eval_time());
+
+    let start = Instant::now();
+    let run = runtime.run(execution);
+    emitter.timing("run", run.as_ref().map_or_else(|_| start.elapsed(), |outcome| outcome.run_time));
+    run
+}
+
+fn report(run: Result<RunOutcome, Error>, emitter: &Emitter) -> (i32, Map<String, Value>) {
+    match run {
+        Ok(outcome) => {
+            let mut extra = Map::new();
+            extra.insert("output".to_string(), outcome.output.into());
+            extra.insert("ticks".to_string(), outcome.ticks.into());
+            (exit_code::SUCCESS, extra)
+        }
+        Err(e) => {
+            let report = |category: &str, message: &str, location| emitter.error_at(category, message, location);
+            (super::report_error(&report, None, &e), Map::new())
+        }
+    }
+}
+
+fn passthrough(args: &[String]) -> i32 {
+    crate::init();
+
+    let args: Vec<CString> = args
+        .iter()
+        .map(|arg| CString::new(arg.as_str()).unwrap())
+        .collect();
+    let argv: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
+
+    shards_lang::cli::process_args(argv.len() as i32, argv.as_ptr(), false)
+}
+
+fn result(code: i32, duration: Duration, extra: Map<String, Value>) -> Value {
+    let mut event = json!({
+        "event": "result",
+        "status": if code == exit_code::SUCCESS { "success" } else { "failure" },
+        "exit_code": code,
+        "duration_ms": millis(duration),
+    });
+    event.as_object_mut().unwrap().extend(extra);
+    event
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
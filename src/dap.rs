@@ -0,0 +1,846 @@
+//! Debug Adapter Protocol server for shards scripts (`shards dap`).
+//!
+//! Runs the launched script in-process and lets a DAP client (e.g. VS Code) pause and
+//! continue it, stop it at line breakpoints, step it shard by shard, list its wires as threads
+//! and inspect the input of the shard it stopped at, the wire states, the variables of the
+//! stopped wire and the defines as variables. Script output is forwarded as `output` events.
+//!
+//! The activations of the shards of every wire reachable from the root are rerouted (see
+//! [`crate::instrument`]). A shard on a breakpoint line, or ending a step, suspends its wire
+//! and the mesh is no longer ticked until the client resumes, other wires reaching a shard
+//! meanwhile are suspended too. `next` stops at the next shard of the same wire that is not
+//! nested in the current one, `stepIn` at the next shard, `stepOut` at the next shard of the
+//! same wire outside of the one nesting the current shard. Breakpoints apply to the lines (and
+//! columns if given) of the shards of the launched file.
+//!
+//! Shards only know their line and column, not the file they come from, so breakpoints can
+//! only be set in the launched file. Breakpoints in included files are reported as unverified,
+//! and a shard of an included file at the same position as a breakpoint stops there too.
+//!
+//! The variables of a wire are the ones its shards write or read through their parameters,
+//! read from the stopped wire when it stops.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use shards::shardsc::{SHContext, SHStringWithLen, SHVar, SHWireRef, Shard};
+
+use crate::cli::capture::{original_stdout, Capture, Output};
+use crate::instrument::{
+    shard_name, shards_by_wire, variables_of, wire_name, Hook, Interposition, Next,
+};
+use crate::protocol::{read_message, write_message};
+use crate::{Execution, Runtime, WireInfo};
+
+// Variables references, the variables of wire `i` are `WIRE_VARIABLES + i`
+const INPUT_VARIABLES: i64 = 1;
+const DEFINE_VARIABLES: i64 = 2;
+const WIRE_VARIABLES: i64 = 3;
+// Stack frames, the frame of wire `i` is `WIRE_FRAME + i`. Thread `i + 1` is wire `i`
+const STOP_FRAME: i64 = 1;
+const WIRE_FRAME: i64 = 2;
+
+// Also the polling interval for client requests while the script runs
+const TICK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Run the debug adapter on stdin/stdout until the client disconnects.
+pub fn run_stdio() -> i32 {
+    crate::init();
+
+    let client = match original_stdout() {
+        Ok(out) => Client::new(out),
+        Err(e) => {
+            eprintln!("shards dap: failed to open stdout: {}", e);
+            return 1;
+        }
+    };
+
+    // The protocol owns stdout, anything the script prints becomes an output event
+    let sink = client.clone();
+    let capture = match Capture::start(move |stream, line| {
+        sink.event(
+            "output",
+            json!({ "category": stream, "output": line + "\n" }),
+        );
+    }) {
+        Ok(capture) => capture,
+        Err(e) => {
+            eprintln!("shards dap: failed to capture output: {}", e);
+            return 1;
+        }
+    };
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut reader = stdin.lock();
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session::new(client);
+    let code = loop {
+        let message = if session.running {
+            match rx.recv_timeout(TICK_INTERVAL) {
+                Ok(message) => Some(message),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break 1,
+            }
+        } else {
+            match rx.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break 1,
+            }
+        };
+
+        if let Some(message) = message {
+            if let Some(code) = session.handle(&message) {
+                break code;
+            }
+        }
+
+        if session.running {
+            session.tick();
+        }
+    };
+
+    capture.finish();
+    code
+}
+
+/// Writes protocol messages, shared with the output capture threads.
+#[derive(Clone)]
+struct Client {
+    out: Arc<Mutex<(Output, i64)>>,
+}
+
+impl Client {
+    fn new(out: Output) -> Self {
+        Self {
+            out: Arc::new(Mutex::new((out, 0))),
+        }
+    }
+
+    fn send(&self, mut message: Value) {
+        let mut out = self.out.lock().unwrap();
+        out.1 += 1;
+        message["seq"] = out.1.into();
+        let _ = write_message(&mut out.0, &message);
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn respond(&self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn fail(&self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn stopped(&self, reason: &str, thread: i64) {
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": thread, "allThreadsStopped": true }),
+        );
+    }
+}
+
+/// A shard whose activations are rerouted, `wire` is the index of its wire.
+#[derive(Clone)]
+struct Site {
+    name: String,
+    line: u32,
+    column: u32,
+    wire: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Step {
+    /// Stop at the next shard.
+    In,
+    /// Stop at the next shard of `context` nested at most `depth` deep.
+    Over { context: usize, depth: u32 },
+    /// Stop at the next shard of `context` nested less than `depth` deep.
+    Out { context: usize, depth: u32 },
+}
+
+/// The shard the script stopped at, before its activation.
+#[derive(Clone)]
+struct Stop {
+    site: Site,
+    input: String,
+    // Variables of the stopped wire and their values
+    variables: Vec<(String, String)>,
+    reason: &'static str,
+    context: usize,
+    depth: u32,
+    reported: bool,
+}
+
+#[derive(Default)]
+struct Debugger {
+    // Lines and columns of the breakpoints of the launched file
+    breakpoints: Vec<(u32, Option<u32>)>,
+    step: Option<Step>,
+    // Wires reaching a shard stay suspended while set
+    stop: Option<Stop>,
+    // Nesting of the running activations, by context
+    depths: HashMap<usize, u32>,
+}
+
+impl Debugger {
+    fn stop_reason(&self, site: &Site, context: usize, depth: u32) -> Option<&'static str> {
+        let stepped = match self.step {
+            Some(Step::In) => true,
+            Some(Step::Over {
+                context: c,
+                depth: d,
+            }) => c == context && depth <= d,
+            Some(Step::Out {
+                context: c,
+                depth: d,
+            }) => c == context && depth < d,
+            None => false,
+        };
+        if stepped {
+            return Some("step");
+        }
+        self.breakpoints
+            .iter()
+            .any(|&(line, column)| {
+                line == site.line && column.map_or(true, |column| column == site.column)
+            })
+            .then_some("breakpoint")
+    }
+}
+
+/// The shards of a launched script routed through the debugger, restored when dropped.
+struct Attached {
+    _interposition: Interposition,
+    debugger: Arc<Mutex<Debugger>>,
+}
+
+impl Attached {
+    /// Reroute the shards of `wires`, as returned by [`shards_by_wire`].
+    fn new(wires: &[(SHWireRef, Vec<*mut Shard>)], debugger: &Arc<Mutex<Debugger>>) -> Self {
+        let hooks = wires.iter().enumerate().flat_map(|(index, (_, shards))| {
+            let variables = Arc::new(unsafe { variables_of(shards) });
+            shards.iter().map(move |&shard| {
+                let site = unsafe {
+                    Site {
+                        name: shard_name(shard),
+                        line: (*shard).line,
+                        column: (*shard).column,
+                        wire: index,
+                    }
+                };
+                let debugged = Debugged {
+                    site,
+                    variables: variables.clone(),
+                    debugger: debugger.clone(),
+                };
+                (shard, debugged)
+            })
+        });
+        Attached {
+            _interposition: Interposition::install(hooks),
+            debugger: debugger.clone(),
+        }
+    }
+}
+
+impl Drop for Attached {
+    // Suspended wires resume when the execution is dropped
+    fn drop(&mut self) {
+        let mut debugger = self.debugger.lock().unwrap();
+        debugger.step = None;
+        debugger.stop = None;
+        debugger.depths.clear();
+    }
+}
+
+/// Restores the nesting of a context when an activation ends, unwinding included.
+struct Nesting<'a> {
+    debugger: &'a Mutex<Debugger>,
+    context: usize,
+    depth: u32,
+}
+
+impl Drop for Nesting<'_> {
+    fn drop(&mut self) {
+        self.debugger
+            .lock()
+            .unwrap()
+            .depths
+            .insert(self.context, self.depth);
+    }
+}
+
+struct Debugged {
+    site: Site,
+    // Names of the variables of its wire
+    variables: Arc<Vec<String>>,
+    debugger: Arc<Mutex<Debugger>>,
+}
+
+impl Debugged {
+    unsafe fn read_variables(&self, context: *mut SHContext) -> Vec<(String, String)> {
+        self.variables
+            .iter()
+            .filter_map(|name| {
+                let value = crate::instrument::variable(
+                    context,
+                    SHStringWithLen {
+                        string: name.as_ptr() as *const _,
+                        len: name.len() as _,
+                    },
+                )?;
+                Some((name.clone(), format!("{:?}", value)))
+            })
+            .collect()
+    }
+}
+
+impl Hook for Debugged {
+    unsafe fn activate(
+        &self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        input: *const SHVar,
+        next: Next,
+    ) -> SHVar {
+        let key = context as usize;
+        let depth = {
+            let mut debugger = self.debugger.lock().unwrap();
+            let depth = debugger.depths.get(&key).copied().unwrap_or(0);
+            if debugger.stop.is_none() {
+                if let Some(reason) = debugger.stop_reason(&self.site, key, depth) {
+                    debugger.step = None;
+                    debugger.stop = Some(Stop {
+                        site: self.site.clone(),
+                        input: format!("{:?}", *input),
+                        variables: self.read_variables(context),
+                        reason,
+                        context: key,
+                        depth,
+                        reported: false,
+                    });
+                }
+            }
+            depth
+        };
+
+        // Not held while suspended or activating, the session and other shards need it
+        while self.debugger.lock().unwrap().stop.is_some() {
+            let state = (*shards::core::Core).suspend.unwrap()(context, 0.0);
+            if state != shards::shardsc::SHWireState_Continue {
+                return SHVar::default();
+            }
+        }
+
+        self.debugger.lock().unwrap().depths.insert(key, depth + 1);
+        let _nesting = Nesting {
+            debugger: &self.debugger,
+            context: key,
+            depth,
+        };
+        next.activate(shard, context, input)
+    }
+}
+
+/// A breakpoint set by the client.
+struct Breakpoint {
+    id: i64,
+    source: PathBuf,
+    line: u32,
+    column: Option<u32>,
+}
+
+/// A wire of the launched script, shown as a thread.
+struct Thread {
+    wire: SHWireRef,
+    name: String,
+    // Of its first shard
+    line: u32,
+}
+
+struct Session {
+    client: Client,
+    program: Option<PathBuf>,
+    defines: Vec<(String, String)>,
+    debugger: Arc<Mutex<Debugger>>,
+    // Declared before the execution, restores the shards while they are still alive
+    attached: Option<Attached>,
+    execution: Option<Execution>,
+    threads: Vec<Thread>,
+    // Lines and columns of the shards of the launched script
+    locations: HashSet<(u32, u32)>,
+    breakpoints: Vec<Breakpoint>,
+    next_breakpoint: i64,
+    stop_on_entry: bool,
+    running: bool,
+}
+
+impl Session {
+    fn new(client: Client) -> Self {
+        Self {
+            client,
+            program: None,
+            defines: Vec::new(),
+            debugger: Arc::default(),
+            attached: None,
+            execution: None,
+            threads: Vec::new(),
+            locations: HashSet::new(),
+            breakpoints: Vec::new(),
+            next_breakpoint: 1,
+            stop_on_entry: false,
+            running: false,
+        }
+    }
+
+    /// Handle one request, returns the exit code once the client disconnects.
+    fn handle(&mut self, request: &Value) -> Option<i32> {
+        let args = &request["arguments"];
+
+        match request["command"].as_str().unwrap_or_default() {
+            "initialize" => {
+                self.client.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsTerminateRequest": true,
+                    }),
+                );
+                self.client.event("initialized", json!({}));
+            }
+            "launch" => self.launch(request),
+            "setBreakpoints" => self.set_breakpoints(request),
+            "setExceptionBreakpoints" => self.client.respond(request, json!({})),
+            "configurationDone" => {
+                self.client.respond(request, json!({}));
+                if self.execution.is_some() {
+                    if self.stop_on_entry {
+                        self.client.stopped("entry", 1);
+                    } else {
+                        self.running = true;
+                    }
+                }
+            }
+            "threads" => {
+                let mut threads: Vec<Value> = self
+                    .threads
+                    .iter()
+                    .enumerate()
+                    .map(|(i, thread)| json!({ "id": i + 1, "name": thread.name }))
+                    .collect();
+                if threads.is_empty() {
+                    threads.push(json!({ "id": 1, "name": "root" }));
+                }
+                self.client.respond(request, json!({ "threads": threads }));
+            }
+            "stackTrace" => {
+                let frames = args["threadId"]
+                    .as_i64()
+                    .map(|thread| self.stack_frames(thread))
+                    .unwrap_or_default();
+                let total = frames.len();
+                self.client.respond(
+                    request,
+                    json!({ "stackFrames": frames, "totalFrames": total }),
+                );
+            }
+            "scopes" => {
+                let scopes = args["frameId"]
+                    .as_i64()
+                    .map(|frame| self.scopes(frame))
+                    .unwrap_or_default();
+                self.client.respond(request, json!({ "scopes": scopes }));
+            }
+            "variables" => {
+                let variables = match args["variablesReference"].as_i64() {
+                    Some(INPUT_VARIABLES) => self
+                        .current_stop()
+                        .map(|stop| {
+                            vec![
+                                variable("shard", &stop.site.name),
+                                variable(
+                                    "location",
+                                    &format!("{}:{}", stop.site.line, stop.site.column),
+                                ),
+                                variable("input", &stop.input),
+                            ]
+                        })
+                        .unwrap_or_default(),
+                    Some(DEFINE_VARIABLES) => self
+                        .defines
+                        .iter()
+                        .map(|(key, value)| variable(key, value))
+                        .collect(),
+                    Some(reference) if reference >= WIRE_VARIABLES => {
+                        self.wire_variables((reference - WIRE_VARIABLES) as usize)
+                    }
+                    _ => Vec::new(),
+                };
+                self.client
+                    .respond(request, json!({ "variables": variables }));
+            }
+            "pause" => {
+                self.client.respond(request, json!({}));
+                self.running = false;
+                self.client.stopped("pause", 1);
+            }
+            "continue" => {
+                self.client
+                    .respond(request, json!({ "allThreadsContinued": true }));
+                self.resume(None);
+                self.running = self.execution.is_some();
+            }
+            command @ ("next" | "stepIn" | "stepOut") => {
+                self.client.respond(request, json!({}));
+                let step = match (command, self.current_stop()) {
+                    ("next", Some(stop)) => Step::Over {
+                        context: stop.context,
+                        depth: stop.depth,
+                    },
+                    ("stepOut", Some(stop)) => Step::Out {
+                        context: stop.context,
+                        depth: stop.depth,
+                    },
+                    _ => Step::In,
+                };
+                self.resume(Some(step));
+                self.running = self.execution.is_some();
+            }
+            "terminate" => {
+                self.client.respond(request, json!({}));
+                self.running = false;
+                self.attached = None;
+                self.execution = None;
+                self.client.event("terminated", json!({}));
+            }
+            "disconnect" => {
+                self.client.respond(request, json!({}));
+                return Some(0);
+            }
+            command => self
+                .client
+                .fail(request, &format!("Unsupported command: {}", command)),
+        }
+
+        None
+    }
+
+    fn launch(&mut self, request: &Value) {
+        let args = &request["arguments"];
+        let Some(program) = args["program"].as_str() else {
+            self.client
+                .fail(request, "Missing 'program' launch argument");
+            return;
+        };
+
+        let mut runtime = Runtime::new();
+        self.defines.clear();
+        for arg in args["args"].as_array().into_iter().flatten() {
+            if let Some((key, value)) = arg.as_str().and_then(|a| a.split_once(':')) {
+                runtime = runtime.define(key, value);
+                self.defines.push((key.to_string(), value.to_string()));
+            }
+        }
+
+        self.attached = None;
+        match runtime.start_file(program) {
+            Ok(execution) => {
+                let wires = shards_by_wire(execution.wire());
+                self.threads = wires
+                    .iter()
+                    .map(|(wire, shards)| Thread {
+                        wire: *wire,
+                        name: unsafe { wire_name(*wire) },
+                        line: shards.first().map_or(1, |shard| unsafe { (**shard).line }),
+                    })
+                    .collect();
+                self.locations = wires
+                    .iter()
+                    .flat_map(|(_, shards)| shards)
+                    .map(|shard| unsafe { ((**shard).line, (**shard).column) })
+                    .collect();
+                self.attached = Some(Attached::new(&wires, &self.debugger));
+                self.program = Some(PathBuf::from(program));
+                self.execution = Some(execution);
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                self.client.respond(request, json!({}));
+
+                // Set before the launch, verified now that the shards are known
+                self.sync_breakpoints();
+                for breakpoint in &self.breakpoints {
+                    self.client.event(
+                        "breakpoint",
+                        json!({ "reason": "changed", "breakpoint": self.breakpoint_body(breakpoint) }),
+                    );
+                }
+            }
+            Err(e) => self.client.fail(request, &e.to_string()),
+        }
+    }
+
+    fn set_breakpoints(&mut self, request: &Value) {
+        let args = &request["arguments"];
+        let source = PathBuf::from(args["source"]["path"].as_str().unwrap_or_default());
+
+        self.breakpoints
+            .retain(|breakpoint| !same_file(&breakpoint.source, &source));
+        let first = self.breakpoints.len();
+        for requested in args["breakpoints"].as_array().into_iter().flatten() {
+            let Some(line) = requested["line"].as_u64() else {
+                continue;
+            };
+            self.breakpoints.push(Breakpoint {
+                id: self.next_breakpoint,
+                source: source.clone(),
+                line: line as u32,
+                column: requested["column"].as_u64().map(|column| column as u32),
+            });
+            self.next_breakpoint += 1;
+        }
+        self.sync_breakpoints();
+
+        let breakpoints: Vec<Value> = self.breakpoints[first..]
+            .iter()
+            .map(|breakpoint| self.breakpoint_body(breakpoint))
+            .collect();
+        self.client
+            .respond(request, json!({ "breakpoints": breakpoints }));
+    }
+
+    fn in_program(&self, breakpoint: &Breakpoint) -> bool {
+        self.program
+            .as_ref()
+            .is_some_and(|program| same_file(program, &breakpoint.source))
+    }
+
+    /// Hand the breakpoints of the launched file to the debugger.
+    fn sync_breakpoints(&self) {
+        self.debugger.lock().unwrap().breakpoints = self
+            .breakpoints
+            .iter()
+            .filter(|breakpoint| self.in_program(breakpoint))
+            .map(|breakpoint| (breakpoint.line, breakpoint.column))
+            .collect();
+    }
+
+    fn breakpoint_body(&self, breakpoint: &Breakpoint) -> Value {
+        let message = if self.execution.is_none() {
+            Some("Verified once the script is launched")
+        } else if !self.in_program(breakpoint) {
+            Some("Only the launched script can have breakpoints, shards do not know their file")
+        } else if !self.locations.iter().any(|&(line, column)| {
+            line == breakpoint.line && breakpoint.column.map_or(true, |c| c == column)
+        }) {
+            Some("No shard on this line")
+        } else {
+            None
+        };
+
+        let mut body = json!({
+            "id": breakpoint.id,
+            "verified": message.is_none(),
+            "line": breakpoint.line,
+        });
+        if let Some(column) = breakpoint.column {
+            body["column"] = column.into();
+        }
+        if let Some(message) = message {
+            body["message"] = message.into();
+        }
+        body
+    }
+
+    fn stack_frames(&self, thread: i64) -> Vec<Value> {
+        let Some(execution) = self.execution.as_ref() else {
+            return Vec::new();
+        };
+        let index = (thread - 1).max(0) as usize;
+        let Some(wire) = self.threads.get(index) else {
+            return Vec::new();
+        };
+
+        let mut frames = Vec::new();
+        if let Some(stop) = self.current_stop().filter(|stop| stop.site.wire == index) {
+            frames.push(json!({
+                "id": STOP_FRAME,
+                "name": stop.site.name,
+                "line": stop.site.line,
+                "column": stop.site.column,
+                "source": { "path": self.program },
+            }));
+        }
+        let name = if index == 0 {
+            format!("{} (tick {})", wire.name, execution.ticks())
+        } else {
+            wire.name.clone()
+        };
+        frames.push(json!({
+            "id": WIRE_FRAME + index as i64,
+            "name": name,
+            "line": wire.line,
+            "column": 1,
+            "source": { "path": self.program },
+        }));
+        frames
+    }
+
+    fn scopes(&self, frame: i64) -> Vec<Value> {
+        let wire = match frame {
+            STOP_FRAME => self.current_stop().map(|stop| stop.site.wire),
+            frame => usize::try_from(frame - WIRE_FRAME).ok(),
+        };
+        let Some(wire) = wire.filter(|wire| *wire < self.threads.len()) else {
+            return Vec::new();
+        };
+
+        let mut scopes = Vec::new();
+        if frame == STOP_FRAME {
+            scopes.push(
+                json!({ "name": "Input", "variablesReference": INPUT_VARIABLES, "expensive": false }),
+            );
+        }
+        scopes.push(json!({
+            "name": "Wire",
+            "variablesReference": WIRE_VARIABLES + wire as i64,
+            "expensive": false,
+        }));
+        scopes.push(
+            json!({ "name": "Defines", "variablesReference": DEFINE_VARIABLES, "expensive": false }),
+        );
+        scopes
+    }
+
+    /// Advance the script by one tick, returns `false` once it finished or stopped at a shard.
+    fn tick(&mut self) -> bool {
+        let Some(execution) = self.execution.as_mut() else {
+            self.running = false;
+            return false;
+        };
+
+        if execution.tick() {
+            let stop = {
+                let mut debugger = self.debugger.lock().unwrap();
+                match debugger.stop.as_mut() {
+                    Some(stop) if !stop.reported => {
+                        stop.reported = true;
+                        Some((stop.reason, stop.site.wire))
+                    }
+                    _ => None,
+                }
+            };
+            if let Some((reason, wire)) = stop {
+                self.running = false;
+                self.client.stopped(reason, wire as i64 + 1);
+                return false;
+            }
+            return true;
+        }
+
+        let failed = execution.failure().is_some();
+        if let Some(failure) = execution.failure() {
+            self.client.event(
+                "output",
+                json!({ "category": "stderr", "output": format!("{}\n", failure) }),
+            );
+        }
+        self.client
+            .event("exited", json!({ "exitCode": if failed { 1 } else { 0 } }));
+        self.client.event("terminated", json!({}));
+        self.running = false;
+        false
+    }
+
+    fn wire_variables(&self, index: usize) -> Vec<Value> {
+        let (Some(execution), Some(thread)) = (self.execution.as_ref(), self.threads.get(index))
+        else {
+            return Vec::new();
+        };
+
+        let info = WireInfo::of_ref(thread.wire);
+        let finished = if index == 0 {
+            execution.is_finished()
+        } else {
+            !info.running && info.final_output.is_some()
+        };
+        let state = if info.failed {
+            "failed"
+        } else if finished {
+            "finished"
+        } else if info.running {
+            "running"
+        } else {
+            "stopped"
+        };
+
+        let mut variables = vec![variable("name", &info.name), variable("state", state)];
+        if index == 0 {
+            variables.push(variable("ticks", &execution.ticks().to_string()));
+        }
+        if let Some(output) = &info.final_output {
+            variables.push(variable("output", output));
+        }
+        if info.failed {
+            variables.push(variable("failure", &info.failure_message));
+        }
+        if let Some(stop) = self.current_stop().filter(|stop| stop.site.wire == index) {
+            variables.extend(
+                stop.variables
+                    .iter()
+                    .map(|(name, value)| variable(name, value)),
+            );
+        }
+        variables
+    }
+
+    /// The shard the script is stopped at, if any.
+    fn current_stop(&self) -> Option<Stop> {
+        self.debugger.lock().unwrap().stop.clone()
+    }
+
+    /// Let the suspended wires go on, until the end of `step` if any.
+    fn resume(&self, step: Option<Step>) {
+        let mut debugger = self.debugger.lock().unwrap();
+        debugger.stop = None;
+        debugger.step = step;
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn variable(name: &str, value: &str) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
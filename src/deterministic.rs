@@ -0,0 +1,225 @@
+//! Deterministic execution, for reproducible runs and golden tests.
+//!
+//! With [`crate::Runtime::seed`] the shards listed in [`RANDOM_SHARDS`] draw from a generator
+//! seeded by the host instead of the entropy of the OS, and scripts using one of the
+//! [`UNSEEDED_SHARDS`] are refused. With [`crate::Runtime::virtual_time`]
+//! the shards listed in [`CLOCK_SHARDS`] read a virtual clock that starts at zero (the Unix
+//! epoch for the `Epoch` shards) and advances by the tick interval after every tick.
+//!
+//! The wires of a mesh always tick in the order they were scheduled. Not covered: shards
+//! running on the worker threads of the core (e.g. `Await`) still complete in real time, and
+//! the core itself (e.g. `Pause`) keeps using the real clock.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use shards::shardsc::{SHContext, SHVar, Shard};
+use shards::types::{Var, Wire};
+
+use crate::instrument::{abort, resolved_param, shard_name, shards_of, Hook, Interposition, Next};
+use crate::Error;
+
+/// Shards drawing from the seeded generator, their `Max` (or `Size` for `RandomBytes`)
+/// parameter is honored, also when set from a variable.
+pub const RANDOM_SHARDS: &[&str] = &["RandomInt", "RandomFloat", "RandomBytes"];
+
+/// Shards drawing from the entropy of the OS that cannot be seeded.
+pub const UNSEEDED_SHARDS: &[&str] = &["UUID", "NanoID"];
+
+/// Shards reading the virtual clock.
+pub const CLOCK_SHARDS: &[&str] = &[
+    "Time.Now",
+    "Time.NowMs",
+    "Time.Delta",
+    "Time.DeltaMs",
+    "Time.Epoch",
+    "Time.EpochMs",
+];
+
+/// SplitMix64, small and stable across platforms and releases.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// In `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+struct DeterministicState {
+    seed: Option<u64>,
+    rng: Mutex<Rng>,
+    // With the virtual time enabled
+    interval: Option<Duration>,
+    ticks: AtomicU64,
+}
+
+impl DeterministicState {
+    fn elapsed(&self) -> Duration {
+        let ticks = self.ticks.load(Ordering::Relaxed);
+        self.interval
+            .unwrap_or_default()
+            .saturating_mul(ticks.min(u32::MAX as u64) as u32)
+    }
+}
+
+struct Replaced {
+    shard: &'static str,
+    state: Arc<DeterministicState>,
+    // The bytes of the last output, alive until the next activation
+    output: Mutex<Vec<u8>>,
+}
+
+/// Random and clock shards of a wire replaced by deterministic ones, restored when dropped.
+pub(crate) struct Determinism {
+    _interposition: Interposition,
+    state: Arc<DeterministicState>,
+}
+
+impl Determinism {
+    /// Replace the random shards of `wire` if `seed` is set and its clock shards if
+    /// `virtual_time` is set, with the interval the clock advances by on every tick. Must
+    /// happen after the wire was composed.
+    pub(crate) fn apply(wire: &Wire, seed: Option<u64>, virtual_time: Option<Duration>) -> Self {
+        let state = Arc::new(DeterministicState {
+            seed,
+            rng: Mutex::new(Rng(seed.unwrap_or_default())),
+            interval: virtual_time,
+            ticks: AtomicU64::new(0),
+        });
+
+        let mut table = Vec::new();
+        if seed.is_some() {
+            table.extend_from_slice(RANDOM_SHARDS);
+        }
+        if virtual_time.is_some() {
+            table.extend_from_slice(CLOCK_SHARDS);
+        }
+
+        let hooks = shards_of(wire).into_iter().filter_map(|shard| {
+            let name = unsafe { shard_name(shard) };
+            let known = table.iter().find(|known| **known == name)?;
+            let replaced = Replaced {
+                shard: *known,
+                state: state.clone(),
+                output: Default::default(),
+            };
+            Some((shard, replaced))
+        });
+
+        Determinism {
+            _interposition: Interposition::install(hooks),
+            state,
+        }
+    }
+
+    /// Advance the virtual clock, call after every tick.
+    pub(crate) fn advance(&self) {
+        self.state.ticks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Start over from the seed and time zero, when the wire is restarted.
+    pub(crate) fn reset(&self) {
+        *self.state.rng.lock().unwrap() = Rng(self.state.seed.unwrap_or_default());
+        self.state.ticks.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Refuse seeding `wire` if it uses one of the [`UNSEEDED_SHARDS`].
+pub(crate) fn check_seedable(wire: &Wire) -> Result<(), Error> {
+    for shard in shards_of(wire) {
+        let name = unsafe { shard_name(shard) };
+        if UNSEEDED_SHARDS.contains(&name.as_str()) {
+            let (line, column) = unsafe { ((*shard).line, (*shard).column) };
+            return Err(Error::Eval {
+                message: format!(
+                    "{} cannot be seeded, the run would not be reproducible",
+                    name
+                ),
+                line,
+                column,
+            });
+        }
+    }
+    Ok(())
+}
+
+impl Hook for Replaced {
+    // Replaces the shard, `next` is not called
+    unsafe fn activate(
+        &self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        _input: *const SHVar,
+        _next: Next,
+    ) -> SHVar {
+        let Replaced {
+            shard: name,
+            state,
+            output,
+        } = self;
+
+        let elapsed = state.elapsed();
+        let interval = state.interval.unwrap_or_default();
+        match *name {
+            "RandomInt" => {
+                let value = state.rng.lock().unwrap().next_u64();
+                match resolved_param(shard, context, "Max").and_then(|max| var_int(&max)) {
+                    Some(max) if max > 0 => Var::from((value % max as u64) as i64),
+                    _ => Var::from((value >> 1) as i64),
+                }
+            }
+            "RandomFloat" => {
+                let value = state.rng.lock().unwrap().next_f64();
+                let max = resolved_param(shard, context, "Max").and_then(|max| var_float(&max));
+                Var::from(value * max.unwrap_or(1.0))
+            }
+            "RandomBytes" => {
+                let size = resolved_param(shard, context, "Size")
+                    .and_then(|size| var_int(&size))
+                    .unwrap_or(0)
+                    .max(0) as usize;
+                let mut output = output.lock().unwrap();
+                output.clear();
+                let mut rng = state.rng.lock().unwrap();
+                while output.len() < size {
+                    let bytes = rng.next_u64().to_le_bytes();
+                    let take = (size - output.len()).min(bytes.len());
+                    output.extend_from_slice(&bytes[..take]);
+                }
+                Var::from(output.as_slice())
+            }
+            "Time.Now" => Var::from(elapsed.as_secs_f64()),
+            "Time.NowMs" => Var::from(elapsed.as_secs_f64() * 1000.0),
+            "Time.Delta" => Var::from(interval.as_secs_f64()),
+            "Time.DeltaMs" => Var::from(interval.as_secs_f64() * 1000.0),
+            "Time.Epoch" => Var::from(elapsed.as_secs() as i64),
+            "Time.EpochMs" => Var::from(elapsed.as_millis() as i64),
+            _ => {
+                abort(context, &format!("not a deterministic shard: {}", name));
+                SHVar::default()
+            }
+        }
+    }
+}
+
+unsafe fn var_int(var: &SHVar) -> Option<i64> {
+    (var.valueType == shards::shardsc::SHType_Int).then(|| var.payload.__bindgen_anon_1.intValue)
+}
+
+unsafe fn var_float(var: &SHVar) -> Option<f64> {
+    match var.valueType {
+        shards::shardsc::SHType_Float => Some(var.payload.__bindgen_anon_1.floatValue),
+        shards::shardsc::SHType_Int => Some(var.payload.__bindgen_anon_1.intValue as f64),
+        _ => None,
+    }
+}
@@ -0,0 +1,104 @@
+//! Static checks of shards sources (parse, evaluation and composition errors).
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use shards::shardsc::{SHInstanceData, SHStringWithLen};
+use shards::types::Wire;
+use shards_lang::read::{read_with_env, ReadEnv};
+
+/// Stage of the pipeline a diagnostic was produced by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// Syntax error reported by the parser.
+    Parse,
+    /// Error raised while evaluating the AST into wires (unknown shards,
+    /// invalid parameters).
+    Eval,
+    /// Error raised while composing the evaluated wire (type mismatches, unknown variables).
+    /// The core does not locate them, they are reported at the start of the file.
+    Compose,
+}
+
+/// A single error found in a source file.
+///
+/// `line` and `column` are 1-based, as reported by `shards_lang`.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub message: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl From<(DiagnosticKind, shards_lang::error::ShardsError)> for Diagnostic {
+    fn from((kind, err): (DiagnosticKind, shards_lang::error::ShardsError)) -> Self {
+        Diagnostic {
+            kind,
+            message: err.message,
+            line: err.loc.line,
+            column: err.loc.column,
+        }
+    }
+}
+
+/// Parse, evaluate and compose `source` as if it was loaded from `path`.
+///
+/// Includes are resolved relative to the directory of `path`.
+/// Returns an empty list when the source is valid.
+pub fn check_source(source: &str, path: &Path) -> Vec<Diagnostic> {
+    crate::init();
+
+    let name = path.to_string_lossy();
+    let dir = path
+        .parent()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let program = match read_with_env(source, ReadEnv::new(&name, &dir, &dir)) {
+        Ok(program) => program,
+        Err(err) => return vec![(DiagnosticKind::Parse, err).into()],
+    };
+
+    let cancellation = Arc::new(AtomicBool::new(false));
+    let wire =
+        match shards_lang::eval::eval(&program.sequence, "root", HashMap::new(), cancellation) {
+            Ok(wire) => wire,
+            Err(err) => return vec![(DiagnosticKind::Eval, err).into()],
+        };
+
+    match unsafe { compose(&wire) } {
+        Ok(()) => Vec::new(),
+        Err(message) => vec![Diagnostic {
+            kind: DiagnosticKind::Compose,
+            message,
+            line: 1,
+            column: 1,
+        }],
+    }
+}
+
+/// Compose `wire` on its own, without scheduling it, returns the failure message.
+unsafe fn compose(wire: &Wire) -> Result<(), String> {
+    let core = &*shards::core::Core;
+    let mut result = core.composeWire.unwrap()(wire.0 .0, SHInstanceData::default());
+
+    let failure = result.failed.then(|| {
+        let message = &result
+            .failureMessage
+            .payload
+            .__bindgen_anon_1
+            .__bindgen_anon_1;
+        crate::runtime::string_with_len(SHStringWithLen {
+            string: message.stringValue,
+            len: message.stringLen as _,
+        })
+    });
+    core.expTypesFree.unwrap()(&mut result.requiredInfo);
+    core.expTypesFree.unwrap()(&mut result.exposedInfo);
+    core.destroyVar.unwrap()(&mut result.failureMessage);
+
+    failure.map_or(Ok(()), Err)
+}
@@ -0,0 +1,113 @@
+//! Shard metadata lookups (names, help text, parameters).
+//!
+//! Help strings are only available after [`crate::init_with_docs`] has been called,
+//! before that the text fields are empty.
+
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+use shards::shardsc::{SHOptionalString, Shard};
+
+/// Documentation for a single shard parameter.
+#[derive(Debug, Clone)]
+pub struct ParameterDoc {
+    pub name: String,
+    pub help: String,
+}
+
+/// Documentation for a single shard.
+#[derive(Debug, Clone)]
+pub struct ShardDoc {
+    pub name: String,
+    pub help: String,
+    pub input_help: String,
+    pub output_help: String,
+    pub parameters: Vec<ParameterDoc>,
+}
+
+/// Names of all shards registered in the runtime, sorted.
+pub fn shard_names() -> &'static [String] {
+    static NAMES: OnceLock<Vec<String>> = OnceLock::new();
+
+    NAMES.get_or_init(|| {
+        crate::init();
+
+        let mut names = Vec::new();
+        unsafe {
+            let shards = (*shards::core::Core).getShards.unwrap()();
+            for i in 0..shards.len {
+                let name = *shards.elements.add(i as usize);
+                if !name.is_null() {
+                    names.push(CStr::from_ptr(name).to_string_lossy().into_owned());
+                }
+            }
+            (*shards::core::Core).stringsFree.unwrap()(&shards as *const _ as *mut _);
+        }
+        names.sort();
+        names
+    })
+}
+
+/// Look up the documentation of a shard by name.
+///
+/// Returns `None` if no shard with this name is registered.
+pub fn shard_doc(name: &str) -> Option<ShardDoc> {
+    if shard_names().binary_search_by(|n| n.as_str().cmp(name)).is_err() {
+        return None;
+    }
+
+    let c_name = std::ffi::CString::new(name).ok()?;
+    unsafe {
+        let shard: *mut Shard = (*shards::core::Core).createShard.unwrap()(c_name.as_ptr());
+        if shard.is_null() {
+            return None;
+        }
+
+        let help = (*shard).help.map(|f| read_string(f(shard))).unwrap_or_default();
+        let input_help = (*shard)
+            .inputHelp
+            .map(|f| read_string(f(shard)))
+            .unwrap_or_default();
+        let output_help = (*shard)
+            .outputHelp
+            .map(|f| read_string(f(shard)))
+            .unwrap_or_default();
+
+        let mut parameters = Vec::new();
+        if let Some(params) = (*shard).parameters {
+            let params = params(shard);
+            for i in 0..params.len {
+                let param = &*params.elements.add(i as usize);
+                parameters.push(ParameterDoc {
+                    name: CStr::from_ptr(param.name).to_string_lossy().into_owned(),
+                    help: read_string(param.help),
+                });
+            }
+        }
+
+        (*shard).destroy.unwrap()(shard);
+
+        Some(ShardDoc {
+            name: name.to_string(),
+            help,
+            input_help,
+            output_help,
+            parameters,
+        })
+    }
+}
+
+// Compressed strings only carry their crc, resolve those through the string cache
+unsafe fn read_string(s: SHOptionalString) -> String {
+    let s = if s.string.is_null() && s.crc != 0 {
+        (*shards::core::Core).readCachedString.unwrap()(s.crc)
+    } else {
+        s
+    };
+
+    if s.string.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(s.string).to_string_lossy().into_owned()
+    }
+}
@@ -0,0 +1,107 @@
+//! Error type for the fallible parts of the embedding API.
+
+use std::fmt;
+
+use crate::quota::Quota;
+use crate::WireFailure;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io(std::io::Error),
+    /// The source could not be formatted (usually a syntax error).
+    Format(String),
+    /// Syntax error, `line` and `column` are 1-based.
+    Parse {
+        message: String,
+        line: u32,
+        column: u32,
+    },
+    /// Evaluating the script into wires failed (unknown shards, invalid parameters, type errors).
+    Eval {
+        message: String,
+        line: u32,
+        column: u32,
+    },
+    /// A shard is not allowed by the [`crate::Sandbox`] of the runtime, or a filesystem shard
+    /// accessed a path outside of its roots.
+    CapabilityDenied {
+        shard: String,
+        line: u32,
+        column: u32,
+        reason: String,
+    },
+    /// The execution exceeded one of the [`crate::Quotas`] of the runtime and was terminated.
+    QuotaExceeded {
+        wire: String,
+        quota: Quota,
+        message: String,
+    },
+    /// A wire stopped with a failure while running.
+    WireFailed(WireFailure),
+    /// A [`crate::Trace`] file is malformed or of an unsupported version, or misses a file the
+    /// replayed script includes.
+    Trace(String),
+    /// A compiled script is malformed, corrupted or cannot be loaded by this build, see
+    /// [`crate::BinaryInfo::check`].
+    Binary(String),
+    /// A [`crate::SourcePack`] is malformed, or a script could not be packed.
+    Pack(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Format(msg) => write!(f, "Format error: {}", msg),
+            Error::Parse {
+                message,
+                line,
+                column,
+            } => write!(f, "Parse error at {}:{}: {}", line, column, message),
+            Error::Eval {
+                message,
+                line,
+                column,
+            } => write!(f, "Evaluation error at {}:{}: {}", line, column, message),
+            Error::CapabilityDenied {
+                shard,
+                line,
+                column,
+                reason,
+            } => write!(
+                f,
+                "Capability denied: {} at {}:{}: {}",
+                shard, line, column, reason
+            ),
+            Error::QuotaExceeded {
+                wire,
+                quota,
+                message,
+            } => write!(
+                f,
+                "Wire '{}' exceeded its {} quota: {}",
+                wire, quota, message
+            ),
+            Error::WireFailed(failure) => write!(f, "{}", failure),
+            Error::Trace(msg) => write!(f, "Invalid trace: {}", msg),
+            Error::Binary(msg) => write!(f, "Invalid binary: {}", msg),
+            Error::Pack(msg) => write!(f, "Invalid pack: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,113 @@
+//! Canonical formatting of shards sources.
+
+use crate::Error;
+
+/// Format shards source code with the canonical layout.
+///
+/// Comments are preserved. Fails if the source does not parse.
+pub fn format_source(source: &str) -> Result<String, Error> {
+    shards_lang::formatter::format_str(source).map_err(|e| Error::Format(format!("{:?}", e)))
+}
+
+/// A line of a diff between an original and a formatted source.
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+// Above this many cells the LCS table gets too big, fall back to replacing the whole middle
+#[cfg(feature = "cli")]
+const MAX_LCS_CELLS: usize = 4_000_000;
+
+/// Line diff between `original` and `formatted`.
+#[cfg(feature = "cli")]
+pub(crate) fn diff_lines<'a>(original: &'a str, formatted: &'a str) -> Vec<DiffLine<'a>> {
+    let a: Vec<&str> = original.lines().collect();
+    let b: Vec<&str> = formatted.lines().collect();
+
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let a_mid = &a[prefix..a.len() - suffix];
+    let b_mid = &b[prefix..b.len() - suffix];
+
+    let mut out: Vec<DiffLine> = a[..prefix].iter().map(|l| DiffLine::Same(l)).collect();
+
+    if a_mid.len() * b_mid.len() > MAX_LCS_CELLS {
+        out.extend(a_mid.iter().map(|l| DiffLine::Removed(l)));
+        out.extend(b_mid.iter().map(|l| DiffLine::Added(l)));
+    } else {
+        // Longest common subsequence table, lcs[i][j] covers a_mid[i..] and b_mid[j..]
+        let (n, m) = (a_mid.len(), b_mid.len());
+        let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i][j] = if a_mid[i] == b_mid[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < n && j < m {
+            if a_mid[i] == b_mid[j] {
+                out.push(DiffLine::Same(a_mid[i]));
+                i += 1;
+                j += 1;
+            } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+                out.push(DiffLine::Removed(a_mid[i]));
+                i += 1;
+            } else {
+                out.push(DiffLine::Added(b_mid[j]));
+                j += 1;
+            }
+        }
+        out.extend(a_mid[i..].iter().map(|l| DiffLine::Removed(l)));
+        out.extend(b_mid[j..].iter().map(|l| DiffLine::Added(l)));
+    }
+
+    out.extend(a[a.len() - suffix..].iter().map(|l| DiffLine::Same(l)));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let formatted = format_source("@wire(main {  1 |   Log ; say it\n})").unwrap();
+        assert!(formatted.contains("; say it"));
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn rejects_invalid_sources() {
+        assert!(matches!(
+            format_source("@wire(main {"),
+            Err(Error::Format(_))
+        ));
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn diffs_lines() {
+        use DiffLine::*;
+        assert_eq!(
+            diff_lines("a\nb\nc\nd", "a\nc\nx\nd"),
+            [Same("a"), Removed("b"), Same("c"), Added("x"), Same("d")]
+        );
+        assert_eq!(diff_lines("a\nb", "a\nb"), [Same("a"), Same("b")]);
+        assert_eq!(diff_lines("", "a"), [Added("a")]);
+    }
+}
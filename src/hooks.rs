@@ -0,0 +1,87 @@
+//! Host callbacks for the lifecycle of the wires run by an [`crate::Execution`] or a
+//! [`crate::Supervisor`].
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Position in a script, 1-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Structured report of a wire that stopped with a failure.
+#[derive(Debug, Clone)]
+pub struct WireFailure {
+    pub wire: String,
+    /// Name of the shard that failed, `None` if it could not be attributed (see below).
+    ///
+    /// Only known when the execution is instrumented, that is when the runtime is profiling or
+    /// has event callbacks. Covers the shards of the root wire and of the wires it references,
+    /// nested ones included, whether they fail by raising an error or by stopping their wire
+    /// with one. The innermost failing shard is reported. Shards running on worker threads of
+    /// the core (e.g. the body of an `Await`) are not attributed.
+    pub shard: Option<String>,
+    /// Location of the failing shard.
+    pub location: Option<SourceLocation>,
+    pub message: String,
+    /// Input the failing shard was activated with, printed.
+    pub input: Option<String>,
+}
+
+impl fmt::Display for WireFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Wire '{}' failed", self.wire)?;
+        match (&self.shard, &self.location) {
+            (Some(shard), Some(loc)) => write!(f, " in {} at {}:{}", shard, loc.line, loc.column)?,
+            (Some(shard), None) => write!(f, " in {}", shard)?,
+            _ => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Lifecycle event passed to [`crate::Runtime::on_wire_event`] callbacks.
+#[derive(Debug)]
+pub enum WireEvent<'a> {
+    /// The wire was scheduled and will run on the next tick.
+    Started { wire: &'a str },
+    /// The wire finished without failure.
+    Stopped { wire: &'a str },
+    /// The wire stopped with a failure.
+    Failed(&'a WireFailure),
+    /// The wire was scheduled again after it finished, `restarts` counts from 1.
+    Restarted { wire: &'a str, restarts: u32 },
+    /// A [`crate::Supervisor`] wire restarted too often within its policy window and is not
+    /// restarted anymore.
+    GaveUp { wire: &'a str, restarts: u32 },
+}
+
+type Callback = Arc<dyn Fn(&WireEvent) + Send + Sync>;
+
+/// Callbacks registered on a [`crate::Runtime`], shared by its executions.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks(Vec<Callback>);
+
+impl Hooks {
+    pub(crate) fn add(&mut self, callback: Callback) {
+        self.0.push(callback);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn emit(&self, event: &WireEvent) {
+        for callback in &self.0 {
+            callback(event);
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hooks({} callbacks)", self.0.len())
+    }
+}
@@ -0,0 +1,403 @@
+//! Lightweight discovery of `@include`d files, without evaluating the script.
+//!
+//! Sources are scanned for directives and string literals only, skipping comments (`;` up to
+//! the end of the line) and the content of strings (`"..."` with `\` escapes, `"""..."""`).
+
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+use crate::Error;
+
+const INCLUDE: &str = "@include(";
+
+/// Paths of all `@include("...")` directives in `text`, as written.
+pub(crate) fn included_paths(text: &str) -> Vec<String> {
+    let mut paths = Vec::new();
+    let mut rest = text;
+    while let Some(idx) = find_include(rest) {
+        rest = &rest[idx + INCLUDE.len()..];
+        if let Some(directive) = directive(rest) {
+            paths.push(directive.path);
+            rest = &rest[directive.len..];
+        }
+    }
+    paths
+}
+
+/// Unescaped content of the string literals of `text` passed to one of `callees`: piped into
+/// it (`"a.txt" | FS.Read`) or among its arguments (`FS.Copy(Destination: "b.txt")`,
+/// `@read("a.txt")`). Comments are excluded.
+pub(crate) fn literals_passed_to(text: &str, callees: &[&str]) -> Vec<String> {
+    let bytes = text.as_bytes();
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-' | b'@');
+    let word_at = |from: usize| {
+        let len = bytes[from..].iter().take_while(|b| is_word(**b)).count();
+        &text[from..from + len]
+    };
+
+    let mut literals = Vec::new();
+    // Callee of every open parenthesis, innermost last
+    let mut calls: Vec<&str> = Vec::new();
+    let mut word_end = None;
+    let mut from = 0;
+    while from < bytes.len() {
+        match bytes[from] {
+            b';' => {
+                from = text[from..]
+                    .find('\n')
+                    .map_or(bytes.len(), |end| from + end)
+            }
+            b'"' => {
+                let (literal, len) = string_literal(&text[from..]);
+                from += len;
+
+                let rest = text[from..].trim_start();
+                let piped_into = rest
+                    .strip_prefix('|')
+                    .map(|rest| rest.trim_start())
+                    .map(|rest| word_at(text.len() - rest.len()));
+                let argument_of = calls.last().copied();
+                if [piped_into, argument_of]
+                    .into_iter()
+                    .flatten()
+                    .any(|callee| callees.contains(&callee))
+                {
+                    literals.push(literal);
+                }
+            }
+            b'(' => {
+                let callee = word_end
+                    .filter(|(_, end)| *end == from)
+                    .map_or("", |(start, end)| &text[start..end]);
+                calls.push(callee);
+                from += 1;
+            }
+            b')' => {
+                calls.pop();
+                from += 1;
+            }
+            b if is_word(b) => {
+                let word = word_at(from);
+                word_end = Some((from, from + word.len()));
+                from += word.len();
+            }
+            _ => from += 1,
+        }
+    }
+    literals
+}
+
+/// Offset of the first `@include(` of `text` outside of comments and strings.
+fn find_include(text: &str) -> Option<usize> {
+    let mut from = 0;
+    while let Some((start, lexeme, end)) = next_lexeme(text, from) {
+        if let Lexeme::Include = lexeme {
+            return Some(start);
+        }
+        from = end;
+    }
+    None
+}
+
+enum Lexeme {
+    Include,
+    Str(String),
+}
+
+/// The first `@include(` or string literal of `text` at or after `from`, with its offset and
+/// the offset following it.
+fn next_lexeme(text: &str, mut from: usize) -> Option<(usize, Lexeme, usize)> {
+    let bytes = text.as_bytes();
+    while from < bytes.len() {
+        match bytes[from] {
+            b';' => {
+                from = text[from..]
+                    .find('\n')
+                    .map_or(bytes.len(), |end| from + end)
+            }
+            b'"' => {
+                let (literal, len) = string_literal(&text[from..]);
+                return Some((from, Lexeme::Str(literal), from + len));
+            }
+            b'@' if text[from..].starts_with(INCLUDE) => {
+                return Some((from, Lexeme::Include, from + INCLUDE.len()))
+            }
+            _ => from += 1,
+        }
+    }
+    None
+}
+
+/// The string literal `text` starts with unescaped, and its length quotes included. An
+/// unterminated literal runs to the end of `text`.
+fn string_literal(text: &str) -> (String, usize) {
+    if let Some(body) = text.strip_prefix(r#"""""#) {
+        let end = body.find(r#"""""#).unwrap_or(body.len());
+        return (body[..end].to_string(), (end + 6).min(text.len()));
+    }
+
+    let mut literal = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return (literal, i + 1),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => literal.push('\n'),
+                Some((_, 'r')) => literal.push('\r'),
+                Some((_, 't')) => literal.push('\t'),
+                Some((_, escaped)) => literal.push(escaped),
+                None => break,
+            },
+            c => literal.push(c),
+        }
+    }
+    (literal, text.len())
+}
+
+/// All files `@include`d by `text`, recursively, read from disk.
+///
+/// Paths are resolved relative to the including file, `visited` guards against include cycles.
+pub(crate) fn collect_includes(
+    text: &str,
+    dir: &Path,
+    visited: &mut HashSet<PathBuf>,
+    sources: &mut Vec<(PathBuf, String)>,
+) {
+    for include in included_paths(text) {
+        let path = dir.join(include);
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        if let Ok(source) = std::fs::read_to_string(&path) {
+            let include_dir = path.parent().unwrap_or(dir).to_path_buf();
+            sources.push((path, source.clone()));
+            collect_includes(&source, &include_dir, visited, sources);
+        }
+    }
+}
+
+struct Directive {
+    path: String,
+    // Bytes from after `@include(` up to and including the closing parenthesis
+    len: usize,
+}
+
+/// The `@include` directive whose arguments start `rest`.
+fn directive(rest: &str) -> Option<Directive> {
+    let trimmed = rest.trim_start();
+    if !trimmed.starts_with('"') {
+        return None;
+    }
+    let (path, len) = string_literal(trimmed);
+    let args_start = rest.len() - trimmed.len() + len;
+    let close = rest[args_start..].find(')')?;
+    Some(Directive {
+        path,
+        len: args_start + close + 1,
+    })
+}
+
+/// `path` with `.` and `..` components resolved lexically.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if out.file_name().is_some() => {
+                out.pop();
+            }
+            _ => out.push(component),
+        }
+    }
+    out
+}
+
+/// The script at `path` and the files it includes, recursively, for sources that are not on
+/// disk. Files are read with `read` from normalized paths resolved relative to the including
+/// file and listed once, in order of inclusion.
+pub(crate) fn read_includes(
+    path: &Path,
+    read: &mut dyn FnMut(&Path) -> Result<String, Error>,
+) -> Result<Vec<(PathBuf, String)>, Error> {
+    let path = normalize(path);
+    let text = read(&path)?;
+    let mut walker = Walker {
+        read,
+        files: vec![(path.clone(), text.clone())],
+        stack: vec![path.clone()],
+    };
+    walker.file(&path, &text)?;
+    Ok(walker.files)
+}
+
+struct Walker<'a> {
+    read: &'a mut dyn FnMut(&Path) -> Result<String, Error>,
+    files: Vec<(PathBuf, String)>,
+    // Files being walked, to detect include cycles
+    stack: Vec<PathBuf>,
+}
+
+impl Walker<'_> {
+    fn file(&mut self, path: &Path, text: &str) -> Result<(), Error> {
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let mut offset = 0;
+        while let Some(idx) = find_include(&text[offset..]) {
+            let line = text[..offset + idx].matches('\n').count() as u32 + 1;
+            offset += idx + INCLUDE.len();
+            let Some(directive) = directive(&text[offset..]) else {
+                continue;
+            };
+            offset += directive.len;
+
+            let include = normalize(&dir.join(&directive.path));
+            if self.stack.contains(&include) {
+                return Err(Error::Parse {
+                    message: format!(
+                        "include cycle through {} (in {})",
+                        include.display(),
+                        path.display()
+                    ),
+                    line,
+                    column: 1,
+                });
+            }
+            // Its own includes were walked the first time
+            if self.files.iter().any(|(file, _)| *file == include) {
+                continue;
+            }
+
+            let source = (self.read)(&include)?;
+            self.files.push((include.clone(), source.clone()));
+            self.stack.push(include.clone());
+            self.file(&include, &source)?;
+            self.stack.pop();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(files: &[(&str, &str)]) -> Result<Vec<(PathBuf, String)>, Error> {
+        let mut read = |path: &Path| {
+            files
+                .iter()
+                .find(|(name, _)| Path::new(name) == path)
+                .map(|(_, source)| source.to_string())
+                .ok_or_else(|| Error::Io(std::io::ErrorKind::NotFound.into()))
+        };
+        read_includes(Path::new(files[0].0), &mut read)
+    }
+
+    #[test]
+    fn finds_includes_outside_of_comments_and_strings() {
+        let text = r#"
+            @include("a.shs")
+            ; @include("commented.shs")
+            "@include(\"quoted.shs\")" | Log
+            """@include("raw.shs")""" | Log
+            @include( "b.shs" once: true)
+        "#;
+        assert_eq!(included_paths(text), ["a.shs", "b.shs"]);
+        assert_eq!(find_include("; @include(\"a.shs\")"), None);
+        assert_eq!(find_include("\"@include(\" @include("), Some(12));
+    }
+
+    #[test]
+    fn unescapes_string_literals() {
+        let text = r#"@read("a\"b") ; @read("comment")
+            @read("tab\there\n") @read("""raw \n""")  @read("unterminated"#;
+        assert_eq!(
+            literals_passed_to(text, &["@read"]),
+            ["a\"b", "tab\there\n", "raw \\n", "unterminated"]
+        );
+    }
+
+    #[test]
+    fn finds_literals_passed_to_file_shards() {
+        let text = r#"
+            "input.txt" | FS.Read
+            "piped.txt"
+              | FS.Write(Overwrite: true)
+            FS.Copy(Destination: "copy.txt" Behavior: "skip")
+            "message.txt" | Log
+            Log("label.txt") = x
+            @read("data.json")
+            If(Then: { "nested.txt" | FS.Read })
+            ; "commented.txt" | FS.Read
+        "#;
+        assert_eq!(
+            literals_passed_to(text, &["FS.Read", "FS.Write", "FS.Copy", "@read"]),
+            [
+                "input.txt",
+                "piped.txt",
+                "copy.txt",
+                "skip",
+                "data.json",
+                "nested.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_directives() {
+        let directive = directive(r#" "lib.shs" once: true) rest"#).unwrap();
+        assert_eq!(directive.path, "lib.shs");
+        assert_eq!(directive.len, 22);
+        assert!(super::directive("path)").is_none());
+        assert!(super::directive(r#""lib.shs""#).is_none());
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize(Path::new("a/./b/../c")), Path::new("a/c"));
+        assert_eq!(normalize(Path::new("../a/..")), Path::new(".."));
+        assert_eq!(normalize(Path::new("/a/b/../c")), Path::new("/a/c"));
+    }
+
+    #[test]
+    fn reads_includes_recursively() {
+        let files = read(&[
+            (
+                "main.shs",
+                "a\n@include(\"lib/a.shs\") b\n@include(\"c.shs\" once: true)",
+            ),
+            ("lib/a.shs", "x\n@include(\"../c.shs\")"),
+            ("c.shs", "y"),
+        ])
+        .unwrap();
+        let paths: Vec<_> = files
+            .iter()
+            .map(|(path, _)| path.to_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["main.shs", "lib/a.shs", "c.shs"]);
+        assert_eq!(files[2].1, "y");
+    }
+
+    #[test]
+    fn rejects_include_cycles() {
+        let error = read(&[
+            ("main.shs", "@include(\"a.shs\")"),
+            ("a.shs", "\n@include(\"main.shs\")"),
+        ])
+        .err()
+        .unwrap();
+        match error {
+            Error::Parse { message, line, .. } => {
+                assert!(message.contains("include cycle through main.shs"));
+                assert_eq!(line, 2);
+            }
+            error => panic!("unexpected error {}", error),
+        }
+    }
+
+    #[test]
+    fn fails_on_missing_includes() {
+        let error = read(&[("main.shs", "@include(\"missing.shs\")")])
+            .err()
+            .unwrap();
+        assert!(matches!(error, Error::Io(_)));
+    }
+}
@@ -0,0 +1,869 @@
+//! Interposition on the shard activations of a composed wire.
+//!
+//! The features of a runtime (sandbox, quotas, deterministic shards, recording, the virtual
+//! filesystem, metrics, the debugger and the instrumentation below) install a [`Hook`] on the
+//! shards they act on through an [`Interposition`]. The `activate` function of an interposed
+//! shard is swapped with a single trampoline running its hooks, the last installed first, then
+//! the shard itself. The hooks hold the state of their shard, activations only look them up in
+//! a registry they read without locking.
+//!
+//! The instrumentation covers every shard of the root wire and of the wires it references
+//! (nested in parameters included, e.g. the branches of `If` or the wire of a `Do`), feeds the
+//! profiler and remembers which shard failed. Shards activated on other threads (e.g. the body
+//! of an `Await`) are forwarded unrecorded.
+//!
+//! Several wires can be instrumented on the same thread (e.g. by a [`crate::Supervisor`]),
+//! only the first one attached with profiling enabled is profiled.
+
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{c_char, CStr};
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+use shards::shardsc::{
+    SHContext, SHInlineShards, SHStringWithLen, SHTable, SHTableIterator, SHVar, SHWireRef, Shard,
+};
+use shards::types::Wire;
+
+use crate::profiler::{Profile, Recorder};
+
+type ActivateFn = unsafe extern "C" fn(*mut Shard, *mut SHContext, *const SHVar) -> SHVar;
+// Shards report failures by throwing, which must be able to unwind through the trampoline
+type UnwindingActivateFn =
+    unsafe extern "C-unwind" fn(*mut Shard, *mut SHContext, *const SHVar) -> SHVar;
+
+/// Runs in place of the activations of the shards it was installed on, see [`Interposition`].
+///
+/// A hook holds the state of its shard for the feature that installed it.
+pub(crate) trait Hook: Send + Sync + 'static {
+    /// `next` runs the hooks installed before this one, then the shard itself. Replacing the
+    /// shard is not calling it.
+    unsafe fn activate(
+        &self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        input: *const SHVar,
+        next: Next,
+    ) -> SHVar;
+}
+
+/// The rest of the activation of an interposed shard.
+pub(crate) struct Next<'a> {
+    hooks: &'a [Installed],
+    original: UnwindingActivateFn,
+}
+
+impl Next<'_> {
+    pub(crate) unsafe fn activate(
+        self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        input: *const SHVar,
+    ) -> SHVar {
+        match self.hooks.split_last() {
+            Some((installed, hooks)) => installed.hook.activate(
+                shard,
+                context,
+                input,
+                Next {
+                    hooks,
+                    original: self.original,
+                },
+            ),
+            None => (self.original)(shard, context, input),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Installed {
+    // The interposition that installed it
+    owner: u64,
+    kind: TypeId,
+    hook: Arc<dyn Hook>,
+}
+
+/// An interposed shard: its own activation and the hooks running in its place, in order of
+/// installation (the last one runs first).
+#[derive(Clone)]
+struct Interposed {
+    original: UnwindingActivateFn,
+    inline_id: SHInlineShards,
+    hooks: Vec<Installed>,
+}
+
+// Indexed by shard pointer
+type Snapshot = HashMap<usize, Arc<Interposed>>;
+
+/// The interposed shards of all runtimes. Shards can run on worker threads of the core, so the
+/// registry is shared by all threads.
+///
+/// Activations read the current snapshot without locking, installations and removals publish a
+/// new one. A replaced snapshot is freed once no activation is reading.
+struct Registry {
+    current: AtomicPtr<Snapshot>,
+    // Activations looking up their shard in `current`
+    readers: AtomicUsize,
+    // Serializes the writers, with the replaced snapshots not freed yet
+    retired: Mutex<Vec<Box<Snapshot>>>,
+}
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(|| Registry {
+        current: AtomicPtr::new(Box::into_raw(Box::default())),
+        readers: AtomicUsize::new(0),
+        retired: Mutex::new(Vec::new()),
+    })
+}
+
+impl Registry {
+    fn interposed(&self, shard: *mut Shard) -> Option<Arc<Interposed>> {
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        let snapshot = unsafe { &*self.current.load(Ordering::SeqCst) };
+        let interposed = snapshot.get(&(shard as usize)).cloned();
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+        interposed
+    }
+
+    fn write(&self) -> Writer<'_> {
+        let retired = self.retired.lock().unwrap();
+        let snapshot = unsafe { (*self.current.load(Ordering::SeqCst)).clone() };
+        Writer {
+            registry: self,
+            retired,
+            snapshot,
+        }
+    }
+}
+
+/// A copy of the current snapshot being modified, no other writer runs meanwhile.
+struct Writer<'a> {
+    registry: &'a Registry,
+    retired: MutexGuard<'a, Vec<Box<Snapshot>>>,
+    snapshot: Snapshot,
+}
+
+impl Writer<'_> {
+    fn publish(&mut self) {
+        let snapshot = Box::new(std::mem::take(&mut self.snapshot));
+        let previous = self
+            .registry
+            .current
+            .swap(Box::into_raw(snapshot), Ordering::SeqCst);
+        self.retired.push(unsafe { Box::from_raw(previous) });
+        // Activations counted from now on read the new snapshot
+        if self.registry.readers.load(Ordering::SeqCst) == 0 {
+            self.retired.clear();
+        }
+    }
+}
+
+/// Hooks installed on shards by one feature of a runtime, removed when dropped.
+///
+/// A shard is restored once its last hook is removed, in any order.
+pub(crate) struct Interposition {
+    id: u64,
+    shards: Vec<*mut Shard>,
+}
+
+impl Interposition {
+    /// Install each hook on its shard. A shard keeps the first hook of a type, e.g. when it is
+    /// shared by several wires through a `Do`. Shards without activation are left alone.
+    pub(crate) fn install<H: Hook>(hooks: impl IntoIterator<Item = (*mut Shard, H)>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let kind = TypeId::of::<H>();
+
+        let mut shards = Vec::new();
+        let mut patched = Vec::new();
+        let mut writer = registry().write();
+        for (shard, hook) in hooks {
+            let key = shard as usize;
+            let mut interposed = match writer.snapshot.get(&key) {
+                Some(interposed) if interposed.hooks.iter().any(|h| h.kind == kind) => continue,
+                Some(interposed) => (**interposed).clone(),
+                None => {
+                    let Some(activate) = (unsafe { (*shard).activate }) else {
+                        continue;
+                    };
+                    patched.push(shard);
+                    Interposed {
+                        original: unsafe {
+                            std::mem::transmute::<ActivateFn, UnwindingActivateFn>(activate)
+                        },
+                        inline_id: unsafe { (*shard).inlineShardId },
+                        hooks: Vec::new(),
+                    }
+                }
+            };
+            interposed.hooks.push(Installed {
+                owner: id,
+                kind,
+                hook: Arc::new(hook),
+            });
+            writer.snapshot.insert(key, Arc::new(interposed));
+            shards.push(shard);
+        }
+        writer.publish();
+
+        // Rerouted once their hooks are published
+        for shard in patched {
+            unsafe {
+                // Inlined shards are run directly by the core, force the call through activate
+                (*shard).inlineShardId = shards::shardsc::SHInlineShards_NotInline;
+                (*shard).activate = Some(std::mem::transmute::<UnwindingActivateFn, ActivateFn>(
+                    interposed_activate,
+                ));
+            }
+        }
+
+        Interposition { id, shards }
+    }
+}
+
+impl Drop for Interposition {
+    fn drop(&mut self) {
+        let mut writer = registry().write();
+        for &shard in &self.shards {
+            let key = shard as usize;
+            let Some(interposed) = writer.snapshot.get(&key) else {
+                continue;
+            };
+            let mut interposed = (**interposed).clone();
+            interposed
+                .hooks
+                .retain(|installed| installed.owner != self.id);
+            if interposed.hooks.is_empty() {
+                unsafe {
+                    (*shard).activate = Some(
+                        std::mem::transmute::<UnwindingActivateFn, ActivateFn>(interposed.original),
+                    );
+                    (*shard).inlineShardId = interposed.inline_id;
+                }
+                writer.snapshot.remove(&key);
+            } else {
+                writer.snapshot.insert(key, Arc::new(interposed));
+            }
+        }
+        writer.publish();
+    }
+}
+
+unsafe extern "C-unwind" fn interposed_activate(
+    shard: *mut Shard,
+    context: *mut SHContext,
+    input: *const SHVar,
+) -> SHVar {
+    let Some(interposed) = registry().interposed(shard) else {
+        // Activated while its runtime restored it, nothing sensible to forward to
+        abort(context, "Shard activated after its runtime was dropped");
+        return SHVar::default();
+    };
+    Next {
+        hooks: &interposed.hooks,
+        original: interposed.original,
+    }
+    .activate(shard, context, input)
+}
+
+pub(crate) unsafe fn shard_name(shard: *mut Shard) -> String {
+    CStr::from_ptr((*shard).name.unwrap()(shard))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Current value of the parameter `name` of `shard`.
+pub(crate) unsafe fn param(shard: *mut Shard, name: &str) -> Option<SHVar> {
+    let (Some(parameters), Some(get_param)) = ((*shard).parameters, (*shard).getParam) else {
+        return None;
+    };
+
+    let params = parameters(shard);
+    (0..params.len)
+        .find(|i| {
+            CStr::from_ptr((*params.elements.add(*i as usize)).name).to_bytes() == name.as_bytes()
+        })
+        .map(|i| get_param(shard, i as i32))
+}
+
+/// Value of the parameter `name` of `shard`, read from the variable of `context` when the
+/// parameter is set from one.
+pub(crate) unsafe fn resolved_param(
+    shard: *mut Shard,
+    context: *mut SHContext,
+    name: &str,
+) -> Option<SHVar> {
+    let value = param(shard, name)?;
+    if value.valueType != shards::shardsc::SHType_ContextVar {
+        return Some(value);
+    }
+
+    let var_name = &value.payload.__bindgen_anon_1.__bindgen_anon_1;
+    variable(
+        context,
+        SHStringWithLen {
+            string: var_name.stringValue,
+            len: var_name.stringLen as _,
+        },
+    )
+}
+
+/// Value of the variable `name` as seen from `context`, `None` if it is not set.
+pub(crate) unsafe fn variable(context: *mut SHContext, name: SHStringWithLen) -> Option<SHVar> {
+    let core = &*shards::core::Core;
+    let var = core.referenceVariable.unwrap()(context, name);
+    if var.is_null() {
+        return None;
+    }
+    // The context keeps the variable alive while the wire runs
+    let value = *var;
+    core.releaseVariable.unwrap()(var);
+    (value.valueType != shards::shardsc::SHType_None).then_some(value)
+}
+
+/// Name of the variable `shard` assigns or grows, for the shards writing to a variable.
+pub(crate) unsafe fn written_variable(shard: *mut Shard) -> Option<String> {
+    let name = match shard_name(shard).as_str() {
+        "Set" | "Ref" | "Update" | "Push" | "Sequence" | "Table" => "Name",
+        "AppendTo" | "PrependTo" => "Collection",
+        _ => return None,
+    };
+    let value = param(shard, name)?;
+    match value.valueType {
+        shards::shardsc::SHType_String | shards::shardsc::SHType_ContextVar => {
+            Some(string_of(&value))
+        }
+        _ => None,
+    }
+}
+
+/// Memory held by `value`: the Var and what it owns.
+pub(crate) unsafe fn held_bytes(value: &SHVar) -> usize {
+    let payload = &value.payload.__bindgen_anon_1;
+    let owned = match value.valueType {
+        shards::shardsc::SHType_String | shards::shardsc::SHType_Path => {
+            payload.__bindgen_anon_1.stringLen as usize
+        }
+        shards::shardsc::SHType_Seq => {
+            let seq = payload.seqValue;
+            (0..seq.len as usize)
+                .map(|i| held_bytes(&*seq.elements.add(i)))
+                .sum()
+        }
+        shards::shardsc::SHType_Table => table_entries(payload.tableValue)
+            .iter()
+            .map(|(key, value)| held_bytes(key) + held_bytes(value))
+            .sum(),
+        _ => 0,
+    };
+    std::mem::size_of::<SHVar>() + owned
+}
+
+/// Names of the variables `shards` write or read from their parameters, sorted.
+pub(crate) unsafe fn variables_of(shards: &[*mut Shard]) -> Vec<String> {
+    let mut names = Vec::new();
+    for &shard in shards {
+        names.extend(written_variable(shard));
+
+        let (Some(parameters), Some(get_param)) = ((*shard).parameters, (*shard).getParam) else {
+            continue;
+        };
+        let params = parameters(shard);
+        for i in 0..params.len {
+            let value = get_param(shard, i as i32);
+            if value.valueType == shards::shardsc::SHType_ContextVar {
+                names.push(string_of(&value));
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+unsafe fn string_of(value: &SHVar) -> String {
+    let s = &value.payload.__bindgen_anon_1.__bindgen_anon_1;
+    crate::runtime::string_with_len(SHStringWithLen {
+        string: s.stringValue,
+        len: s.stringLen as _,
+    })
+}
+
+/// Keys and values of `table`.
+pub(crate) unsafe fn table_entries(table: SHTable) -> Vec<(SHVar, SHVar)> {
+    let api = &*table.api;
+    let mut iterator = SHTableIterator::default();
+    api.tableGetIterator.unwrap()(table, &mut iterator);
+
+    let mut entries = Vec::new();
+    let (mut key, mut value) = (SHVar::default(), SHVar::default());
+    while api.tableNext.unwrap()(table, &mut iterator, &mut key, &mut value) {
+        entries.push((key, value));
+    }
+    entries
+}
+
+/// Stop the wire running `context` with a failure.
+pub(crate) unsafe fn abort(context: *mut SHContext, message: &str) {
+    (*shards::core::Core).abortWire.unwrap()(
+        context,
+        SHStringWithLen {
+            string: message.as_ptr() as *const c_char,
+            len: message.len() as _,
+        },
+    );
+}
+
+/// Shards of `wire`, nested ones and the ones of the wires it references included.
+pub(crate) fn shards_of(wire: &Wire) -> Vec<*mut Shard> {
+    collect(wire)
+        .shards
+        .into_iter()
+        .map(|(_, shard)| shard)
+        .collect()
+}
+
+/// `wire` and the wires it references, nested ones included.
+pub(crate) fn wires_of(wire: &Wire) -> Vec<SHWireRef> {
+    collect(wire).wires
+}
+
+/// `wire` and the wires it references with their own shards: the ones nested in their
+/// parameters, not the ones of the wires they reference. A shard shared by several wires
+/// belongs to the first one found.
+pub(crate) fn shards_by_wire(wire: &Wire) -> Vec<(SHWireRef, Vec<*mut Shard>)> {
+    let collected = collect(wire);
+    let mut by_wire: Vec<_> = collected
+        .wires
+        .into_iter()
+        .map(|wire| (wire, Vec::new()))
+        .collect();
+    for (owner, shard) in collected.shards {
+        by_wire[owner].1.push(shard);
+    }
+    by_wire
+}
+
+pub(crate) unsafe fn wire_name(wire: SHWireRef) -> String {
+    crate::runtime::string_with_len((*shards::core::Core).getWireInfo.unwrap()(wire).name)
+}
+
+#[derive(Default)]
+struct Collected {
+    visited: HashSet<usize>,
+    // With the index of the wire owning them
+    shards: Vec<(usize, *mut Shard)>,
+    wires: Vec<SHWireRef>,
+}
+
+fn collect(wire: &Wire) -> Collected {
+    let mut out = Collected::default();
+    unsafe { collect_wire(wire.0 .0, &mut out) };
+    out
+}
+
+unsafe fn collect_wire(wire: SHWireRef, out: &mut Collected) {
+    if wire.is_null() || !out.visited.insert(wire as usize) {
+        return;
+    }
+    let owner = out.wires.len();
+    out.wires.push(wire);
+
+    let info = (*shards::core::Core).getWireInfo.unwrap()(wire);
+    for i in 0..info.shards.len {
+        collect_shard(*info.shards.elements.add(i as usize), owner, out);
+    }
+}
+
+unsafe fn collect_shard(shard: *mut Shard, owner: usize, out: &mut Collected) {
+    if shard.is_null() || !out.visited.insert(shard as usize) {
+        return;
+    }
+    out.shards.push((owner, shard));
+
+    // Shards nested in parameters (If branches, Do wires, ...)
+    let (Some(parameters), Some(get_param)) = ((*shard).parameters, (*shard).getParam) else {
+        return;
+    };
+    let params = parameters(shard);
+    for i in 0..params.len {
+        collect_var(&get_param(shard, i as i32), owner, out);
+    }
+}
+
+unsafe fn collect_var(var: &SHVar, owner: usize, out: &mut Collected) {
+    let payload = &var.payload.__bindgen_anon_1;
+    match var.valueType {
+        shards::shardsc::SHType_ShardRef => collect_shard(payload.shardValue, owner, out),
+        shards::shardsc::SHType_Wire => collect_wire(payload.wireValue, out),
+        shards::shardsc::SHType_Seq => {
+            let seq = payload.seqValue;
+            for i in 0..seq.len {
+                collect_var(&*seq.elements.add(i as usize), owner, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// An instrumented shard, `wire` is the instrumented root wire and `owner` the wire it
+/// belongs to.
+#[derive(Debug, Clone)]
+pub(crate) struct Site {
+    wire: usize,
+    pub(crate) owner: usize,
+    // The first shard of its wire, starts a run of it
+    pub(crate) first: bool,
+    pub(crate) name: String,
+    pub(crate) line: u32,
+    pub(crate) column: u32,
+}
+
+/// The shard whose activation failed, innermost first.
+#[derive(Debug, Clone)]
+pub(crate) struct FailedShard {
+    pub(crate) site: Site,
+    pub(crate) input: String,
+}
+
+struct State {
+    // Pointers of the root wires instrumented on this thread
+    wires: HashSet<usize>,
+    // With the pointer of the profiled wire
+    recorder: Option<(usize, Recorder)>,
+    // Indexed by wire pointer
+    failures: HashMap<usize, FailedShard>,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+}
+
+/// Instrumentation of a composed wire, restores the shards when dropped.
+///
+/// Must be dropped on the thread that attached it.
+pub(crate) struct Instrumentation {
+    wire: usize,
+    _interposition: Interposition,
+}
+
+impl Instrumentation {
+    /// Instrument the shards of `wire` and of the wires it references, must happen after it
+    /// was composed.
+    pub(crate) fn attach(wire: &Wire, profile: bool) -> Self {
+        let key = wire.0 .0 as usize;
+        let mut hooks = Vec::new();
+        let mut wires = Vec::new();
+        for (owner, shards) in shards_by_wire(wire) {
+            wires.push((owner as usize, unsafe { wire_name(owner) }));
+            for (i, &shard) in shards.iter().enumerate() {
+                let site = unsafe {
+                    Site {
+                        wire: key,
+                        owner: owner as usize,
+                        first: i == 0,
+                        name: shard_name(shard),
+                        line: (*shard).line,
+                        column: (*shard).column,
+                    }
+                };
+                hooks.push((shard, Instrumented(site)));
+            }
+        }
+        let interposition = Interposition::install(hooks);
+
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let state = state.get_or_insert_with(|| State {
+                wires: HashSet::new(),
+                recorder: None,
+                failures: HashMap::new(),
+            });
+            state.wires.insert(key);
+            if profile && state.recorder.is_none() {
+                state.recorder = Some((key, Recorder::new(wires)));
+            }
+        });
+
+        Instrumentation {
+            wire: key,
+            _interposition: interposition,
+        }
+    }
+
+    /// Record a mesh tick that started at `start`.
+    pub(crate) fn record_tick(&self, start: Instant, duration: Duration) {
+        with_state(|state| match &mut state.recorder {
+            Some((wire, recorder)) if *wire == self.wire => recorder.tick(start, duration),
+            _ => {}
+        });
+    }
+
+    /// Snapshot of the timings collected so far, if profiling.
+    pub(crate) fn profile(&self) -> Option<Profile> {
+        with_state(|state| match &state.recorder {
+            Some((wire, recorder)) if *wire == self.wire => Some(recorder.profile()),
+            _ => None,
+        })
+        .flatten()
+    }
+
+    /// The shard of this wire that failed since the last call, if any.
+    pub(crate) fn take_failure(&self) -> Option<FailedShard> {
+        with_state(|state| state.failures.remove(&self.wire)).flatten()
+    }
+}
+
+impl Drop for Instrumentation {
+    fn drop(&mut self) {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let Some(current) = state.as_mut() else {
+                return;
+            };
+            current.wires.remove(&self.wire);
+            current.failures.remove(&self.wire);
+            if matches!(current.recorder, Some((wire, _)) if wire == self.wire) {
+                current.recorder = None;
+            }
+            if current.wires.is_empty() {
+                *state = None;
+            }
+        });
+    }
+}
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> Option<R> {
+    STATE.with(|state| state.borrow_mut().as_mut().map(f))
+}
+
+struct Instrumented(Site);
+
+impl Hook for Instrumented {
+    unsafe fn activate(
+        &self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        input: *const SHVar,
+        next: Next,
+    ) -> SHVar {
+        let site = &self.0;
+        // Instrumented on another thread, e.g. run by a worker of the core
+        let recorded = with_state(|state| {
+            if !state.wires.contains(&site.wire) {
+                return false;
+            }
+            match &mut state.recorder {
+                Some((wire, recorder)) if *wire == site.wire => {
+                    recorder.enter(context as usize, site.owner)
+                }
+                _ => {}
+            }
+            true
+        })
+        .unwrap_or(false);
+        if !recorded {
+            return next.activate(shard, context, input);
+        }
+
+        let mut activation = Activation {
+            shard,
+            site,
+            context,
+            input,
+            returned: false,
+        };
+        // The state must not be borrowed here, the shard might activate other instrumented shards
+        let output = next.activate(shard, context, input);
+        activation.returned = true;
+        output
+    }
+}
+
+/// One running activation, finished when dropped so that unwinding activations are seen too.
+struct Activation<'a> {
+    shard: *mut Shard,
+    site: &'a Site,
+    context: *mut SHContext,
+    input: *const SHVar,
+    returned: bool,
+}
+
+impl Drop for Activation<'_> {
+    fn drop(&mut self) {
+        // Shards fail by throwing, or by stopping their wire with an error and returning
+        let failed = !self.returned
+            || unsafe { (*shards::core::Core).getState.unwrap()(self.context) }
+                == shards::shardsc::SHWireState_Error;
+
+        with_state(|state| {
+            let site = self.site;
+            match &mut state.recorder {
+                Some((wire, recorder)) if *wire == site.wire => {
+                    recorder.exit(self.context as usize, self.shard as usize, site)
+                }
+                _ => {}
+            }
+
+            // The innermost failing activation finishes first, keep it
+            if failed {
+                state
+                    .failures
+                    .entry(site.wire)
+                    .or_insert_with(|| FailedShard {
+                        site: site.clone(),
+                        input: unsafe { format!("{:?}", *self.input) },
+                    });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(value: i64) -> SHVar {
+        let mut var = SHVar::default();
+        var.valueType = shards::shardsc::SHType_Int;
+        var.payload.__bindgen_anon_1.intValue = value;
+        var
+    }
+
+    unsafe extern "C" fn double(_: *mut Shard, _: *mut SHContext, input: *const SHVar) -> SHVar {
+        int((*input).payload.__bindgen_anon_1.intValue * 2)
+    }
+
+    struct Add(i64);
+
+    impl Hook for Add {
+        unsafe fn activate(
+            &self,
+            shard: *mut Shard,
+            context: *mut SHContext,
+            input: *const SHVar,
+            next: Next,
+        ) -> SHVar {
+            let output = next.activate(shard, context, input);
+            int(output.payload.__bindgen_anon_1.intValue + self.0)
+        }
+    }
+
+    struct Negate;
+
+    impl Hook for Negate {
+        unsafe fn activate(
+            &self,
+            shard: *mut Shard,
+            context: *mut SHContext,
+            input: *const SHVar,
+            next: Next,
+        ) -> SHVar {
+            let output = next.activate(shard, context, input);
+            int(-output.payload.__bindgen_anon_1.intValue)
+        }
+    }
+
+    // Any inlined shard, restored along with the activation
+    const INLINED: SHInlineShards = shards::shardsc::SHInlineShards_NotInline + 1;
+
+    fn shard() -> Box<Shard> {
+        let mut shard: Box<Shard> = Box::new(unsafe { std::mem::zeroed() });
+        shard.activate = Some(double);
+        shard.inlineShardId = INLINED;
+        shard
+    }
+
+    fn activate(shard: *mut Shard, input: i64) -> i64 {
+        unsafe {
+            let output = (*shard).activate.unwrap()(shard, std::ptr::null_mut(), &int(input));
+            output.payload.__bindgen_anon_1.intValue
+        }
+    }
+
+    #[test]
+    fn runs_the_last_hook_first() {
+        let mut shard = shard();
+        let ptr: *mut Shard = &mut *shard;
+
+        let add = Interposition::install([(ptr, Add(1))]);
+        assert_eq!(activate(ptr, 3), 7);
+        assert_eq!(
+            shard.inlineShardId,
+            shards::shardsc::SHInlineShards_NotInline
+        );
+
+        let negate = Interposition::install([(ptr, Negate)]);
+        assert_eq!(activate(ptr, 3), -7);
+        drop(add);
+        assert_eq!(activate(ptr, 3), -6);
+        drop(negate);
+        assert_eq!(activate(ptr, 3), 6);
+    }
+
+    #[test]
+    fn keeps_the_first_hook_of_a_type() {
+        let mut shard = shard();
+        let ptr: *mut Shard = &mut *shard;
+
+        let first = Interposition::install([(ptr, Add(1))]);
+        let second = Interposition::install([(ptr, Add(100))]);
+        assert_eq!(activate(ptr, 3), 7);
+        drop(first);
+        assert_eq!(activate(ptr, 3), 6);
+        drop(second);
+    }
+
+    #[test]
+    fn restores_shards_once_their_last_hook_is_removed() {
+        let mut shard = shard();
+        let ptr: *mut Shard = &mut *shard;
+
+        let add = Interposition::install([(ptr, Add(1))]);
+        let negate = Interposition::install([(ptr, Negate)]);
+        drop(negate);
+        drop(add);
+        assert_eq!(shard.activate.map(|f| f as usize), Some(double as usize));
+        assert_eq!(shard.inlineShardId, INLINED);
+    }
+
+    #[test]
+    fn activates_while_other_shards_are_interposed() {
+        let shards: Vec<usize> = (0..8).map(|_| Box::into_raw(shard()) as usize).collect();
+        let added =
+            Interposition::install(shards.iter().map(|&shard| (shard as *mut Shard, Add(1))));
+
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (shards, stop) = (shards.clone(), stop.clone());
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        for &shard in &shards {
+                            let output = activate(shard as *mut Shard, 2);
+                            assert!(output == 5 || output == -5, "{}", output);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..200 {
+            drop(Interposition::install(
+                shards.iter().map(|&shard| (shard as *mut Shard, Negate)),
+            ));
+        }
+        stop.store(true, Ordering::Relaxed);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        drop(added);
+        for shard in shards {
+            drop(unsafe { Box::from_raw(shard as *mut Shard) });
+        }
+    }
+}
@@ -11,6 +11,59 @@
 //! ```
 
 use std::ffi::{c_char, CString};
+use std::path::Path;
+
+pub mod binary;
+pub mod bundle;
+mod codec;
+pub mod deterministic;
+pub mod diagnostics;
+pub mod docs;
+mod error;
+mod format;
+mod hooks;
+mod includes;
+mod instrument;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod pack;
+pub mod profiler;
+pub mod quota;
+pub mod replay;
+mod runtime;
+pub mod sandbox;
+mod supervisor;
+pub mod vfs;
+
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "cli")]
+pub mod dap;
+#[cfg(feature = "cli")]
+pub mod lsp;
+#[cfg(feature = "cli")]
+mod protocol;
+
+// Tested with the crate, build.rs has no tests of its own
+#[cfg(test)]
+#[path = "../build/manifest.rs"]
+mod manifest;
+
+pub use binary::{BinaryInfo, BuildOptions, Compression, TrustedKeys};
+pub use error::Error;
+pub use format::format_source;
+pub use hooks::{SourceLocation, WireEvent, WireFailure};
+pub use pack::SourcePack;
+pub use quota::Quotas;
+pub use replay::Trace;
+pub use runtime::{EmbeddedScript, Execution, RunOutcome, Runtime, WireInfo};
+pub use sandbox::Sandbox;
+pub use supervisor::{Restart, RestartPolicy, Supervisor, WireId, WireState, WireStatus};
+pub use vfs::{DirVfs, MemoryVfs, OverlayVfs, Vfs};
+#[cfg(feature = "zip")]
+pub use vfs::ZipVfs;
+#[cfg(feature = "macros")]
+pub use shards_embed_macros::include_shards;
 
 // Re-export base shards crate
 pub use shards::*;
@@ -24,62 +77,60 @@ pub use shards_fileops;
 // CR-SQLite bundle disabled due to package resolution issues
 // pub use crsql_bundle;
 
-// Conditional re-exports based on features
+// Re-exports of the Rust crates of the enabled modules, `shards_module` is set by build.rs
+// from its module table
 // GFX/EGUI disabled for now - need nested workspace support
 // #[cfg(feature = "gfx")]
 // pub use gfx;
 // #[cfg(feature = "egui")]
 // pub use shards_egui_register;
 
-#[cfg(feature = "ml")]
+#[cfg(shards_module = "ml")]
 pub use shards_ml;
 
-#[cfg(feature = "core")]
+#[cfg(shards_module = "core")]
 pub use shards_core;
 
-#[cfg(feature = "crypto")]
+#[cfg(shards_module = "crypto")]
 pub use shards_crypto;
 
-#[cfg(feature = "csv")]
+#[cfg(shards_module = "csv")]
 pub use shards_csv;
 
-#[cfg(feature = "fs")]
+#[cfg(shards_module = "fs")]
 pub use shards_fs;
 
-#[cfg(feature = "geo")]
+#[cfg(shards_module = "geo")]
 pub use shards_geo;
 
-#[cfg(feature = "http")]
+#[cfg(shards_module = "http")]
 pub use shards_http;
 
-#[cfg(feature = "network")]
+#[cfg(shards_module = "network")]
 pub use shards_network;
 
-#[cfg(feature = "pdf")]
+#[cfg(shards_module = "pdf")]
 pub use shards_pdf;
 
 // SSH feature disabled - requires OPENSSL_DIR env vars to be set
 // #[cfg(feature = "ssh")]
 // pub use shards_ssh;
 
-#[cfg(feature = "svg")]
+#[cfg(shards_module = "svg")]
 pub use shards_svg;
 
-#[cfg(feature = "random")]
+#[cfg(shards_module = "random")]
 pub use shards_random;
 
-#[cfg(feature = "markdown")]
+#[cfg(shards_module = "markdown")]
 pub use shards_markdown;
 
-#[cfg(feature = "localshell")]
+#[cfg(shards_module = "localshell")]
 pub use shards_localshell;
 
-#[cfg(feature = "langffi")]
+#[cfg(shards_module = "langffi")]
 pub use shards_langffi;
 
-#[cfg(feature = "py")]
-pub use shards_py;
-
 // FFI declarations for C++ core
 extern "C" {
     fn shardsInterface(version: u32) -> *mut shards::shardsc::SHCore;
@@ -100,6 +151,9 @@ pub fn init() {
             (*shards::core::Core).init.unwrap()();
             shards_install_signal_handlers();
         }
+
+        #[cfg(feature = "tracy")]
+        tracy_client::Client::start();
     });
 }
 
@@ -169,36 +223,63 @@ pub fn eval_string(_code: &str) -> i32 {
     shards_lang::cli::process_args(argv.len() as i32, argv.as_ptr(), false)
 }
 
-/// Build a shards script to binary format.
+/// Build a shards script to binary format, see [`binary`] for the layout.
 ///
 /// Returns 0 on success, non-zero on error.
 pub fn build_file(input: &str, output: &str) -> i32 {
-    init();
-
-    let args = vec![
-        CString::new("shards").unwrap(),
-        CString::new("build").unwrap(),
-        CString::new(input).unwrap(),
-        CString::new("-o").unwrap(),
-        CString::new(output).unwrap(),
-    ];
-    let argv: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
+    match binary::build(input, output, &BuildOptions::new()) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
 
-    shards_lang::cli::process_args(argv.len() as i32, argv.as_ptr(), false)
+/// Like [`build_file`], signing the binary with the Ed25519 secret key stored at `key`, see
+/// [`binary::read_key`]. Needs the `crypto` feature.
+///
+/// Returns 0 on success, non-zero on error.
+pub fn build_file_signed(input: &str, output: &str, key: &str) -> i32 {
+    let signed = binary::read_key(key)
+        .and_then(|secret| binary::build(input, output, &BuildOptions::new().sign(secret)));
+    match signed {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
 }
 
 /// Load and run a pre-compiled shards binary.
 ///
+/// Binaries with a header are checked first, see [`BinaryInfo::check`].
 /// Returns 0 on success, non-zero on error.
 pub fn load_binary(path: &str) -> i32 {
     init();
 
-    let args = vec![
-        CString::new("shards").unwrap(),
-        CString::new("load").unwrap(),
-        CString::new(path).unwrap(),
-    ];
-    let argv: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
+    match binary::load(Path::new(path), None, &[]) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
 
-    shards_lang::cli::process_args(argv.len() as i32, argv.as_ptr(), false)
+/// Like [`load_binary`], only running binaries signed by one of the `trusted` keys. The
+/// signature is checked before the script is evaluated. Needs the `crypto` feature.
+///
+/// Returns 0 on success, non-zero on error.
+pub fn load_binary_trusted(path: &str, trusted: &TrustedKeys) -> i32 {
+    init();
+
+    match binary::load(Path::new(path), Some(trusted), &[]) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
 }
@@ -0,0 +1,602 @@
+//! Language Server Protocol implementation for `.shs` files.
+//!
+//! Speaks JSON-RPC over stdio (`shards lsp`) and provides:
+//! - parse, evaluation and composition diagnostics
+//! - hover documentation for shards and their parameters
+//! - completion of shard names, and of the parameter names not given yet inside the parameter
+//!   list of a shard, filtered by the identifier being typed
+//! - go-to-definition for `@define`, `@wire`, `@template` and `@include` paths
+//! - whole document formatting
+//!
+//! Only full document synchronization is supported. Positions are exchanged in UTF-32 (characters)
+//! if the client offers it in `positionEncodings`, in UTF-16 code units otherwise, and converted
+//! to characters on the way in.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::diagnostics::check_source;
+use crate::docs::{shard_doc, shard_names};
+use crate::includes::collect_includes;
+use crate::protocol::{read_message, write_message};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+
+const DEFINITION_KEYWORDS: [&str; 3] = ["@define(", "@wire(", "@template("];
+
+/// Run the language server on stdin/stdout until the client sends `exit`.
+///
+/// Returns 0 if the client shut the server down properly, 1 otherwise.
+pub fn run_stdio() -> i32 {
+    crate::init_with_docs();
+
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    let mut server = Server::new(io::stdout());
+
+    loop {
+        let message = match read_message(&mut reader) {
+            Ok(Some(message)) => message,
+            Ok(None) => return 1,
+            Err(e) => {
+                eprintln!("shards lsp: failed to read message: {}", e);
+                return 1;
+            }
+        };
+
+        match server.handle(&message) {
+            Ok(Some(code)) => return code,
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("shards lsp: failed to write message: {}", e);
+                return 1;
+            }
+        }
+    }
+}
+
+/// How the columns of positions are counted, negotiated in `initialize`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Utf16,
+    Utf32,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf16 => "utf-16",
+            Encoding::Utf32 => "utf-32",
+        }
+    }
+
+    /// Character index on `line` of the client column `column`.
+    fn to_chars(self, line: &str, column: usize) -> usize {
+        match self {
+            Encoding::Utf32 => column,
+            Encoding::Utf16 => {
+                let mut units = 0;
+                for (i, c) in line.chars().enumerate() {
+                    if units >= column {
+                        return i;
+                    }
+                    units += c.len_utf16();
+                }
+                line.chars().count()
+            }
+        }
+    }
+
+    /// Client column of the character index `chars` on `line`.
+    fn from_chars(self, line: &str, chars: usize) -> usize {
+        match self {
+            Encoding::Utf32 => chars,
+            Encoding::Utf16 => {
+                let units: usize = line.chars().take(chars).map(char::len_utf16).sum();
+                units + chars.saturating_sub(line.chars().count())
+            }
+        }
+    }
+}
+
+struct Server<W: Write> {
+    out: W,
+    documents: HashMap<String, String>,
+    encoding: Encoding,
+    shutdown: bool,
+}
+
+impl<W: Write> Server<W> {
+    fn new(out: W) -> Self {
+        Self {
+            out,
+            documents: HashMap::new(),
+            encoding: Encoding::Utf16,
+            shutdown: false,
+        }
+    }
+
+    /// Handle one incoming message, returns the exit code once the client asks to exit.
+    fn handle(&mut self, message: &Value) -> io::Result<Option<i32>> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let id = message.get("id").cloned();
+
+        let result = match method {
+            "initialize" => {
+                let offered = params["capabilities"]["general"]["positionEncodings"].as_array();
+                self.encoding = if offered.is_some_and(|e| e.iter().any(|e| e == "utf-32")) {
+                    Encoding::Utf32
+                } else {
+                    Encoding::Utf16
+                };
+                Ok(json!({
+                    "capabilities": {
+                        "positionEncoding": self.encoding.name(),
+                        "textDocumentSync": 1,
+                        "hoverProvider": true,
+                        "completionProvider": { "triggerCharacters": ["(", "@", "."] },
+                        "definitionProvider": true,
+                        "documentFormattingProvider": true,
+                    },
+                    "serverInfo": { "name": "shards", "version": env!("CARGO_PKG_VERSION") },
+                }))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "exit" => return Ok(Some(if self.shutdown { 0 } else { 1 })),
+            "textDocument/didOpen" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.to_string(), text.to_string());
+                self.publish_diagnostics(uri)?;
+                return Ok(None);
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|c| c.last()).and_then(|c| c["text"].as_str()) {
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+                self.publish_diagnostics(uri)?;
+                return Ok(None);
+            }
+            "textDocument/didSave" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                self.publish_diagnostics(uri)?;
+                return Ok(None);
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                self.documents.remove(uri);
+                self.notify(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                )?;
+                return Ok(None);
+            }
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/completion" => Ok(self.completion(params)),
+            "textDocument/definition" => Ok(self.definition(params)),
+            "textDocument/formatting" => self.formatting(params),
+            _ => Err((METHOD_NOT_FOUND, format!("Unsupported method: {}", method))),
+        };
+
+        // Notifications never get a response
+        let Some(id) = id else {
+            return Ok(None);
+        };
+
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        write_message(&mut self.out, &response)?;
+        Ok(None)
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        write_message(
+            &mut self.out,
+            &json!({ "jsonrpc": "2.0", "method": method, "params": params }),
+        )
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let Some(text) = self.documents.get(uri) else {
+            return Ok(());
+        };
+
+        let diagnostics: Vec<Value> = check_source(text, &uri_to_path(uri))
+            .into_iter()
+            .map(|d| {
+                let line = d.line.saturating_sub(1);
+                let column = d.column.saturating_sub(1) as usize;
+                let text = text.lines().nth(line as usize).unwrap_or_default();
+                json!({
+                    "range": {
+                        "start": { "line": line, "character": self.encoding.from_chars(text, column) },
+                        "end": { "line": line, "character": self.encoding.from_chars(text, column + 1) },
+                    },
+                    "severity": 1,
+                    "source": "shards",
+                    "message": d.message,
+                })
+            })
+            .collect();
+
+        self.notify(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": diagnostics }),
+        )
+    }
+
+    /// The document of a request with the line and the character index of its position.
+    fn document<'a>(&'a self, params: &'a Value) -> Option<(&'a str, &'a str, usize, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let text = self.documents.get(uri)?;
+        let line = params["position"]["line"].as_u64()? as usize;
+        let column = params["position"]["character"].as_u64()? as usize;
+        let character = self
+            .encoding
+            .to_chars(text.lines().nth(line).unwrap_or_default(), column);
+        Some((uri, text.as_str(), line, character))
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let Some((_, text, line, character)) = self.document(params) else {
+            return Value::Null;
+        };
+        let Some(word) = word_at(text, line, character) else {
+            return Value::Null;
+        };
+
+        // Hovering a parameter name shows the help of that parameter
+        if let Some(shard) = enclosing_shard(text, line, character) {
+            if let Some(doc) = shard_doc(&shard) {
+                if let Some(param) = doc.parameters.iter().find(|p| p.name == word) {
+                    return json!({
+                        "contents": {
+                            "kind": "markdown",
+                            "value": format!("**{}** parameter of `{}`\n\n{}", param.name, doc.name, param.help),
+                        }
+                    });
+                }
+            }
+        }
+
+        let Some(doc) = shard_doc(&word) else {
+            return Value::Null;
+        };
+
+        let mut value = format!("**{}**\n\n{}", doc.name, doc.help);
+        if !doc.input_help.is_empty() {
+            value.push_str(&format!("\n\n*Input:* {}", doc.input_help));
+        }
+        if !doc.output_help.is_empty() {
+            value.push_str(&format!("\n\n*Output:* {}", doc.output_help));
+        }
+        if !doc.parameters.is_empty() {
+            value.push_str("\n\n*Parameters:*\n");
+            for param in &doc.parameters {
+                value.push_str(&format!("\n- `{}`: {}", param.name, param.help));
+            }
+        }
+
+        json!({ "contents": { "kind": "markdown", "value": value } })
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let Some((_, text, line, character)) = self.document(params) else {
+            return Value::Null;
+        };
+
+        let prefix = prefix_at(text, line, character).to_lowercase();
+        let matches = |label: &str| label.to_lowercase().starts_with(&prefix);
+        let mut items = Vec::new();
+
+        if let Some((shard, args)) = enclosing_call(text, line, character) {
+            if let Some(doc) = shard_doc(&shard) {
+                let given = named_args(&args);
+                for param in doc.parameters {
+                    if !matches(&param.name) || given.contains(&param.name) {
+                        continue;
+                    }
+                    items.push(json!({
+                        "label": param.name,
+                        "kind": 5,
+                        "insertText": format!("{}: ", param.name),
+                        "documentation": param.help,
+                    }));
+                }
+            }
+        }
+
+        // Shards, parameter values included
+        for name in shard_names().into_iter().filter(|name| matches(name)) {
+            items.push(json!({ "label": name, "kind": 3 }));
+        }
+
+        json!({ "isIncomplete": false, "items": items })
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let Some((uri, text, line, character)) = self.document(params) else {
+            return Value::Null;
+        };
+        let path = uri_to_path(uri);
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        if let Some(include) = include_at(text, line, character) {
+            let target = dir.join(include);
+            if target.exists() {
+                return location(&target, 0, 0, 0, "", self.encoding);
+            }
+            return Value::Null;
+        }
+
+        let Some(word) = word_at(text, line, character) else {
+            return Value::Null;
+        };
+        let word = word.trim_start_matches('@');
+
+        // Search the current document first, then everything it includes
+        let mut sources = vec![(path.clone(), text.to_string())];
+        let mut visited = HashSet::new();
+        visited.insert(path.clone());
+        collect_includes(text, dir, &mut visited, &mut sources);
+
+        for (source_path, source) in &sources {
+            if let Some((line, column)) = find_definition(source, word) {
+                let text = source.lines().nth(line).unwrap_or_default();
+                let len = word.chars().count();
+                return location(source_path, line, column, len, text, self.encoding);
+            }
+        }
+
+        Value::Null
+    }
+
+    fn formatting(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let Some(text) = self.documents.get(uri) else {
+            return Ok(Value::Null);
+        };
+
+        let formatted = crate::format_source(text)
+            .map_err(|e| (INTERNAL_ERROR, format!("Failed to format document: {}", e)))?;
+
+        let lines = text.lines().count() + 1;
+        Ok(json!([{
+            "range": {
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": lines, "character": 0 },
+            },
+            "newText": formatted,
+        }]))
+    }
+}
+
+/// `len` characters at the character index `column` of `line`, whose text is `text`.
+fn location(
+    path: &Path,
+    line: usize,
+    column: usize,
+    len: usize,
+    text: &str,
+    encoding: Encoding,
+) -> Value {
+    json!({
+        "uri": path_to_uri(path),
+        "range": {
+            "start": { "line": line, "character": encoding.from_chars(text, column) },
+            "end": { "line": line, "character": encoding.from_chars(text, column + len) },
+        },
+    })
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == '@'
+}
+
+/// The identifier under the cursor, e.g. `Math.Add` or `@my-define`.
+fn word_at(text: &str, line: usize, character: usize) -> Option<String> {
+    let chars: Vec<char> = text.lines().nth(line)?.chars().collect();
+    let pos = character.min(chars.len());
+
+    let start = chars[..pos]
+        .iter()
+        .rposition(|c| !is_ident_char(*c))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let end = chars[pos..]
+        .iter()
+        .position(|c| !is_ident_char(*c))
+        .map(|i| pos + i)
+        .unwrap_or(chars.len());
+
+    if start >= end {
+        return None;
+    }
+    Some(chars[start..end].iter().collect::<String>().trim_end_matches('.').to_string())
+}
+
+/// The part of the identifier under the cursor before it, what completion filters on.
+fn prefix_at(text: &str, line: usize, character: usize) -> String {
+    let Some(line) = text.lines().nth(line) else {
+        return String::new();
+    };
+    let chars: Vec<char> = line.chars().take(character).collect();
+    let start = chars
+        .iter()
+        .rposition(|c| !is_ident_char(*c))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    chars[start..].iter().collect()
+}
+
+/// Name of the shard whose parameter list contains the cursor, if any.
+fn enclosing_shard(text: &str, line: usize, character: usize) -> Option<String> {
+    enclosing_call(text, line, character).map(|(name, _)| name)
+}
+
+/// Name of the shard whose parameter list contains the cursor and the arguments before the
+/// cursor, if any.
+fn enclosing_call(text: &str, line: usize, character: usize) -> Option<(String, String)> {
+    let before: Vec<char> = text
+        .lines()
+        .take(line)
+        .flat_map(|l| l.chars().chain(std::iter::once('\n')))
+        .chain(text.lines().nth(line)?.chars().take(character))
+        .collect();
+
+    // Walk back to the innermost unclosed bracket
+    let mut depth = 0usize;
+    let mut open = None;
+    for (i, c) in before.iter().enumerate().rev() {
+        match c {
+            ')' | '}' | ']' => depth += 1,
+            '(' | '{' | '[' if depth > 0 => depth -= 1,
+            '(' => {
+                open = Some(i);
+                break;
+            }
+            '{' | '[' => return None,
+            _ => {}
+        }
+    }
+
+    let open = open?;
+    let start = before[..open]
+        .iter()
+        .rposition(|c| !is_ident_char(*c))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let name: String = before[start..open].iter().collect();
+    if name.is_empty() || name.starts_with('@') {
+        return None;
+    }
+    Some((name, before[open + 1..].iter().collect()))
+}
+
+/// Names of the `name: value` arguments of `args`, not the ones of nested calls.
+fn named_args(args: &str) -> HashSet<String> {
+    let mut names = HashSet::new();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut ident = String::new();
+    for c in args.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '(' | '{' | '[' => depth += 1,
+            ')' | '}' | ']' => depth = depth.saturating_sub(1),
+            ':' if depth == 0 && !ident.is_empty() => {
+                names.insert(std::mem::take(&mut ident));
+                continue;
+            }
+            c if is_ident_char(c) => {
+                ident.push(c);
+                continue;
+            }
+            _ => {}
+        }
+        ident.clear();
+    }
+    names
+}
+
+/// Path of the `@include("...")` string under the cursor, if any.
+fn include_at(text: &str, line: usize, character: usize) -> Option<String> {
+    let chars: Vec<char> = text.lines().nth(line)?.chars().collect();
+    let pos = character.min(chars.len());
+
+    let open = chars[..pos].iter().rposition(|c| *c == '"')?;
+    let close = pos + chars[pos..].iter().position(|c| *c == '"')?;
+
+    let prefix: String = chars[..open].iter().collect();
+    if !prefix.trim_end().ends_with("@include(") {
+        return None;
+    }
+    Some(chars[open + 1..close].iter().collect())
+}
+
+/// Zero-based line and column of the name in `@define(name`, `@wire(name` or `@template(name`.
+fn find_definition(source: &str, name: &str) -> Option<(usize, usize)> {
+    for (line_idx, line) in source.lines().enumerate() {
+        for keyword in DEFINITION_KEYWORDS {
+            let mut offset = 0;
+            while let Some(idx) = line[offset..].find(keyword) {
+                let after = offset + idx + keyword.len();
+                let rest = &line[after..];
+                let trimmed = rest.trim_start();
+                let ident_start = after + (rest.len() - trimmed.len());
+
+                if let Some(tail) = trimmed.strip_prefix(name) {
+                    if !tail.starts_with(is_ident_char) {
+                        return Some((line_idx, line[..ident_start].chars().count()));
+                    }
+                }
+                offset = after;
+            }
+        }
+    }
+    None
+}
+
+fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let path = percent_decode(path);
+    // file:///C:/foo maps to /C:/foo, drop the leading slash on Windows
+    if cfg!(windows) {
+        PathBuf::from(path.trim_start_matches('/'))
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut uri = String::from("file://");
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for c in path.chars() {
+        match c {
+            ' ' => uri.push_str("%20"),
+            '#' => uri.push_str("%23"),
+            '?' => uri.push_str("%3F"),
+            _ => uri.push(c),
+        }
+    }
+    uri
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
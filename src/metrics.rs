@@ -0,0 +1,302 @@
+//! Runtime metrics for long-running meshes, reported through the [`metrics`] crate facade.
+//!
+//! Enabled with the `metrics` feature, install any `metrics` recorder (Prometheus, StatsD, ...)
+//! in the host to export them. The wire metrics have a `wire` label, with the name of the root
+//! wire for the tick metrics and of each wire the root references (e.g. through `Spawn` or
+//! `Detach`) for the others, those are polled after every tick:
+//!
+//! - `shards_wires_scheduled_total` (counter)
+//! - `shards_wires_running` (gauge)
+//! - `shards_wires_failed_total` (counter)
+//! - `shards_ticks_total` (counter)
+//! - `shards_ticks_per_second` (gauge, updated once per second)
+//! - `shards_tick_duration_seconds` (histogram)
+//!
+//! The channel metrics have a `channel` label with the channel name:
+//!
+//! - `shards_channel_depth` (gauge), messages sent by `Produce` and not yet received by a
+//!   `Consume` of the instrumented wires. `Broadcast` and `Listen` keep one buffer per listener
+//!   and are not counted.
+//!
+//! - `shards_var_memory_bytes` (gauge), with a `wire` label: memory held by the variables
+//!   the shards of the wire write (`Set`, `Ref`, `Push`, `AppendTo`, ...), updated after each
+//!   write. The Var allocator of the core keeps no statistics, so this is measured from the
+//!   values of the variables like the memory quota (see [`crate::quota`]): the Vars, string
+//!   contents and the elements of sequences and tables.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ::metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use shards::shardsc::{SHContext, SHStringWithLen, SHVar, SHWireRef, Shard};
+use shards::types::Wire;
+
+use crate::instrument::{
+    held_bytes, param, shard_name, shards_by_wire, shards_of, variable, wire_name, wires_of,
+    written_variable, Hook, Interposition, Next,
+};
+use crate::sandbox::var_string;
+use crate::WireInfo;
+
+const TICKS_PER_SECOND_WINDOW: Duration = Duration::from_secs(1);
+
+fn describe() {
+    static DESCRIBE: std::sync::Once = std::sync::Once::new();
+
+    DESCRIBE.call_once(|| {
+        describe_counter!("shards_wires_scheduled_total", "Wires scheduled on a mesh");
+        describe_gauge!("shards_wires_running", "Wires currently running");
+        describe_counter!(
+            "shards_wires_failed_total",
+            "Wires that stopped with a failure"
+        );
+        describe_counter!("shards_ticks_total", "Mesh ticks");
+        describe_gauge!("shards_ticks_per_second", "Mesh ticks in the last second");
+        describe_histogram!(
+            "shards_tick_duration_seconds",
+            ::metrics::Unit::Seconds,
+            "Duration of a mesh tick"
+        );
+        describe_gauge!(
+            "shards_channel_depth",
+            "Messages produced on a channel and not consumed yet"
+        );
+        describe_gauge!(
+            "shards_var_memory_bytes",
+            ::metrics::Unit::Bytes,
+            "Memory held by the variables of a wire"
+        );
+    });
+}
+
+/// A wire referenced by the root one, with whether it was running at the last poll.
+struct Referenced {
+    wire: SHWireRef,
+    name: String,
+    running: bool,
+}
+
+impl Referenced {
+    /// Count the wire as started or finished if it changed since the last poll.
+    fn poll(&mut self) {
+        let info = WireInfo::of_ref(self.wire);
+        if info.running == self.running {
+            return;
+        }
+        self.running = info.running;
+
+        if info.running {
+            counter!("shards_wires_scheduled_total", "wire" => self.name.clone()).increment(1);
+            gauge!("shards_wires_running", "wire" => self.name.clone()).increment(1.0);
+        } else {
+            gauge!("shards_wires_running", "wire" => self.name.clone()).decrement(1.0);
+            if info.failed {
+                counter!("shards_wires_failed_total", "wire" => self.name.clone()).increment(1);
+            }
+        }
+    }
+}
+
+/// Metrics of one scheduled root wire `name` and of the wires it references, see
+/// [`crate::Execution`].
+pub(crate) struct WireMetrics {
+    wire: String,
+    referenced: Vec<Referenced>,
+    window_start: Instant,
+    window_ticks: u64,
+    running: bool,
+}
+
+impl WireMetrics {
+    pub(crate) fn started(name: &str, wire: &Wire) -> Self {
+        describe();
+
+        let name = name.to_string();
+        counter!("shards_wires_scheduled_total", "wire" => name.clone()).increment(1);
+        gauge!("shards_wires_running", "wire" => name.clone()).increment(1.0);
+
+        let referenced = wires_of(wire)
+            .into_iter()
+            .skip(1)
+            .map(|wire| Referenced {
+                wire,
+                name: unsafe { wire_name(wire) },
+                running: false,
+            })
+            .collect();
+
+        Self {
+            wire: name,
+            referenced,
+            window_start: Instant::now(),
+            window_ticks: 0,
+            running: true,
+        }
+    }
+
+    pub(crate) fn tick(&mut self, duration: Duration) {
+        counter!("shards_ticks_total", "wire" => self.wire.clone()).increment(1);
+        histogram!("shards_tick_duration_seconds", "wire" => self.wire.clone())
+            .record(duration.as_secs_f64());
+
+        for referenced in &mut self.referenced {
+            referenced.poll();
+        }
+
+        self.window_ticks += 1;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= TICKS_PER_SECOND_WINDOW {
+            gauge!("shards_ticks_per_second", "wire" => self.wire.clone())
+                .set(self.window_ticks as f64 / elapsed.as_secs_f64());
+            self.window_start = Instant::now();
+            self.window_ticks = 0;
+        }
+    }
+
+    pub(crate) fn finished(&mut self, failed: bool) {
+        if !self.running {
+            return;
+        }
+        self.running = false;
+
+        gauge!("shards_wires_running", "wire" => self.wire.clone()).decrement(1.0);
+        gauge!("shards_ticks_per_second", "wire" => self.wire.clone()).set(0.0);
+        if failed {
+            counter!("shards_wires_failed_total", "wire" => self.wire.clone()).increment(1);
+        }
+        // Stopped along with the root wire
+        for referenced in self.referenced.iter_mut().filter(|r| r.running) {
+            referenced.running = false;
+            gauge!("shards_wires_running", "wire" => referenced.name.clone()).decrement(1.0);
+        }
+    }
+}
+
+impl Drop for WireMetrics {
+    // Executions dropped while still running no longer count as running
+    fn drop(&mut self) {
+        self.finished(false);
+    }
+}
+
+/// Shards moving messages through channels, with the change of the depth of the channel
+/// named by their `Name` parameter once they return.
+const CHANNEL_SHARDS: &[(&str, f64)] = &[("Produce", 1.0), ("Consume", -1.0)];
+
+struct Counted {
+    channel: String,
+    change: f64,
+}
+
+/// The channel shards of a wire counting the depth of their channel, restored when dropped.
+pub(crate) struct ChannelDepths {
+    _interposition: Interposition,
+}
+
+impl ChannelDepths {
+    /// Count the channel shards of `wire`, must happen after it was composed.
+    pub(crate) fn attach(wire: &Wire) -> Self {
+        describe();
+
+        let hooks = shards_of(wire).into_iter().filter_map(|shard| {
+            let name = unsafe { shard_name(shard) };
+            let (_, change) = CHANNEL_SHARDS.iter().find(|(known, _)| *known == name)?;
+            let channel = unsafe { param(shard, "Name").and_then(|name| var_string(&name)) }?;
+            let counted = Counted {
+                channel,
+                change: *change,
+            };
+            Some((shard, counted))
+        });
+
+        ChannelDepths {
+            _interposition: Interposition::install(hooks),
+        }
+    }
+}
+
+impl Hook for Counted {
+    unsafe fn activate(
+        &self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        input: *const SHVar,
+        next: Next,
+    ) -> SHVar {
+        // A failing activation unwinds past the count, it moved no message
+        let output = next.activate(shard, context, input);
+        gauge!("shards_channel_depth", "channel" => self.channel.clone()).increment(self.change);
+        output
+    }
+}
+
+// Bytes held by each written variable, by wire and variable name
+type Held = Arc<Mutex<HashMap<String, HashMap<String, usize>>>>;
+
+struct Written {
+    wire: String,
+    variable: String,
+    held: Held,
+}
+
+/// The shards of a wire writing variables, measuring the memory they hold. Restored when
+/// dropped.
+pub(crate) struct VarMemory {
+    _interposition: Interposition,
+}
+
+impl VarMemory {
+    /// Measure the variables written by the shards of `wire`, must happen after it was
+    /// composed.
+    pub(crate) fn attach(wire: &Wire) -> Self {
+        describe();
+
+        let held = Held::default();
+        let hooks = shards_by_wire(wire)
+            .into_iter()
+            .flat_map(|(wire, shards)| {
+                let name = unsafe { wire_name(wire) };
+                shards.into_iter().map(move |shard| (name.clone(), shard))
+            })
+            .filter_map(|(wire, shard)| {
+                let variable = unsafe { written_variable(shard) }?;
+                let written = Written {
+                    wire,
+                    variable,
+                    held: held.clone(),
+                };
+                Some((shard, written))
+            });
+
+        VarMemory {
+            _interposition: Interposition::install(hooks),
+        }
+    }
+}
+
+impl Hook for Written {
+    unsafe fn activate(
+        &self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        input: *const SHVar,
+        next: Next,
+    ) -> SHVar {
+        let output = next.activate(shard, context, input);
+
+        let name = SHStringWithLen {
+            string: self.variable.as_ptr() as *const _,
+            len: self.variable.len() as _,
+        };
+        let bytes = variable(context, name).map_or(0, |value| held_bytes(&value));
+        let total: usize = {
+            let mut held = self.held.lock().unwrap();
+            let wire = held.entry(self.wire.clone()).or_default();
+            wire.insert(self.variable.clone(), bytes);
+            wire.values().sum()
+        };
+        gauge!("shards_var_memory_bytes", "wire" => self.wire.clone()).set(total as f64);
+        output
+    }
+}
@@ -0,0 +1,392 @@
+//! Source packs: a script, the files it `@include`s and the assets it references, in one file.
+//!
+//! `shards pack dir/ -o app.shpack` starts from the entry script (`main.shs` unless `--entry`
+//! says otherwise), follows its includes and stores every source along with the files under
+//! `dir` named by a string literal of a source passed to a file shard served from the pack
+//! (see [`VFS_SHARDS`]) or to `@read`, or added with `--asset`. [`crate::Runtime::run_pack`] runs the entry script with its includes and the
+//! files of the file shards served from the pack instead of the disk, see [`crate::vfs`].
+//!
+//! Layout: the magic `SHPACK\0\0`, the format version (u32), then the manifest: the entry
+//! script and the number of files, each with its path relative to `dir` (`/` separated), its
+//! kind, the SHA-256 hash and the content.
+
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::codec::{put_bytes, put_str, put_u32, Reader};
+use crate::includes::{literals_passed_to, normalize, read_includes};
+use crate::vfs::VFS_SHARDS;
+use crate::Error;
+
+const MAGIC: &[u8; 8] = b"SHPACK\0\0";
+const VERSION: u32 = 1;
+
+// Longest string literal considered a reference to an asset
+const MAX_ASSET_PATH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// The entry script or a file it includes.
+    Source,
+    Asset,
+}
+
+#[derive(Debug, Clone)]
+pub struct PackedFile {
+    pub kind: FileKind,
+    pub content: Vec<u8>,
+}
+
+/// A script with its includes and assets, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct SourcePack {
+    entry: String,
+    files: BTreeMap<String, PackedFile>,
+}
+
+impl SourcePack {
+    /// Pack the script `entry` (relative to `dir`), everything it includes and the assets its
+    /// sources reference. Includes must stay within `dir`, symbolic links included.
+    pub fn from_dir(dir: impl AsRef<Path>, entry: &str) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        let sources = read_includes(Path::new(entry), &mut |path| {
+            String::from_utf8(read_file(dir, path)?)
+                .map_err(|_| Error::Pack(format!("{} is not valid UTF-8", path.display())))
+        })?;
+
+        let mut pack = SourcePack {
+            entry: pack_path(&normalize(Path::new(entry)))?,
+            files: BTreeMap::new(),
+        };
+        for (path, source) in &sources {
+            for asset in referenced_files(dir, path, source) {
+                pack.insert_file(dir, &asset, FileKind::Asset)?;
+            }
+        }
+        for (path, source) in sources {
+            pack.files.insert(
+                pack_path(&path)?,
+                PackedFile {
+                    kind: FileKind::Source,
+                    content: source.into_bytes(),
+                },
+            );
+        }
+        Ok(pack)
+    }
+
+    /// Add the file at `path` (relative to `dir`) as an asset, for files no source names.
+    pub fn add_asset(&mut self, dir: impl AsRef<Path>, path: &str) -> Result<(), Error> {
+        self.insert_file(dir.as_ref(), &normalize(Path::new(path)), FileKind::Asset)
+    }
+
+    fn insert_file(&mut self, dir: &Path, path: &Path, kind: FileKind) -> Result<(), Error> {
+        let content = read_file(dir, path)?;
+        self.files
+            .insert(pack_path(path)?, PackedFile { kind, content });
+        Ok(())
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        Self::decode(&bytes).map_err(|e| Error::Pack(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        std::fs::write(path, self.encode())?;
+        Ok(())
+    }
+
+    /// Path of the entry script within the pack.
+    pub fn entry(&self) -> &str {
+        &self.entry
+    }
+
+    /// The file at `path` within the pack, `/` separated.
+    pub fn file(&self, path: &str) -> Option<&PackedFile> {
+        self.files.get(path)
+    }
+
+    /// All files of the pack, sorted by path.
+    pub fn files(&self) -> impl Iterator<Item = (&str, &PackedFile)> {
+        self.files.iter().map(|(path, file)| (path.as_str(), file))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        put_u32(&mut out, VERSION);
+        put_str(&mut out, &self.entry);
+        put_u32(&mut out, self.files.len() as u32);
+        for (path, file) in &self.files {
+            put_str(&mut out, path);
+            out.push(match file.kind {
+                FileKind::Source => 0,
+                FileKind::Asset => 1,
+            });
+            put_bytes(&mut out, &Sha256::digest(&file.content));
+            put_bytes(&mut out, &file.content);
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut input = Reader(bytes);
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err("not a source pack".to_string());
+        }
+        let version = input.u32()?;
+        if version != VERSION {
+            return Err(format!(
+                "unsupported pack version {} (expected {})",
+                version, VERSION
+            ));
+        }
+
+        let entry = input.string()?;
+        let files = (0..input.u32()?)
+            .map(|_| {
+                let path = input.string()?;
+                let kind = match input.u8()? {
+                    0 => FileKind::Source,
+                    1 => FileKind::Asset,
+                    kind => return Err(format!("{}: unknown file kind {}", path, kind)),
+                };
+                let hash = input.bytes()?;
+                let content = input.bytes()?;
+                if Sha256::digest(content).as_slice() != hash {
+                    return Err(format!(
+                        "{}: content hash mismatch, the file is corrupted",
+                        path
+                    ));
+                }
+                let content = content.to_vec();
+                Ok((path, PackedFile { kind, content }))
+            })
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+
+        match files.get(&entry) {
+            Some(file) if file.kind == FileKind::Source => Ok(SourcePack { entry, files }),
+            _ => Err(format!("entry script {} is missing", entry)),
+        }
+    }
+}
+
+/// `path` (normalized, relative to the pack root) as stored in the pack.
+fn pack_path(path: &Path) -> Result<String, Error> {
+    let parts: Vec<_> = path
+        .components()
+        .map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(|| {
+            Error::Pack(format!(
+                "{} is outside of the packed directory",
+                path.display()
+            ))
+        })?;
+    Ok(parts.join("/"))
+}
+
+/// `path` (normalized, relative to `dir`) on the disk, symbolic links resolved. Fails if it
+/// leaves `dir`.
+fn resolve_within(dir: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let name = pack_path(path)?;
+    let resolved = dir
+        .join(path)
+        .canonicalize()
+        .map_err(|e| Error::Pack(format!("cannot read {}: {}", name, e)))?;
+    if !resolved.starts_with(dir.canonicalize()?) {
+        return Err(Error::Pack(format!(
+            "{} links outside of the packed directory",
+            name
+        )));
+    }
+    Ok(resolved)
+}
+
+fn read_file(dir: &Path, path: &Path) -> Result<Vec<u8>, Error> {
+    std::fs::read(resolve_within(dir, path)?)
+        .map_err(|e| Error::Pack(format!("cannot read {}: {}", path.display(), e)))
+}
+
+/// Files under `dir` named by a string literal of the source at `path` passed to a file shard
+/// or to `@read`, relative to the source or to `dir`.
+fn referenced_files(dir: &Path, path: &Path, source: &str) -> Vec<PathBuf> {
+    let source_dir = path.parent().unwrap_or(Path::new(""));
+    let callees: Vec<&str> = VFS_SHARDS.iter().copied().chain(["@read"]).collect();
+    literals_passed_to(source, &callees)
+        .into_iter()
+        .filter(|literal| {
+            !literal.is_empty() && literal.len() <= MAX_ASSET_PATH && !literal.contains(['\n', ':'])
+        })
+        .filter_map(|literal| {
+            [source_dir.join(&literal), PathBuf::from(&literal)]
+                .into_iter()
+                .map(|candidate| normalize(&candidate))
+                .find(|candidate| {
+                    resolve_within(dir, candidate).is_ok_and(|resolved| resolved.is_file())
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for `name`, removed first if a previous run left it.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("shards-embed-pack-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sample() -> SourcePack {
+        let mut files = BTreeMap::new();
+        files.insert(
+            "main.shs".to_string(),
+            PackedFile {
+                kind: FileKind::Source,
+                content: b"@include(\"lib.shs\")".to_vec(),
+            },
+        );
+        files.insert(
+            "data/values.json".to_string(),
+            PackedFile {
+                kind: FileKind::Asset,
+                content: vec![0, 1, 2, 255],
+            },
+        );
+        SourcePack {
+            entry: "main.shs".to_string(),
+            files,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let pack = sample();
+        let decoded = SourcePack::decode(&pack.encode()).unwrap();
+        assert_eq!(decoded.entry(), "main.shs");
+        assert_eq!(decoded.files.len(), 2);
+        for (path, file) in pack.files() {
+            let decoded = decoded.file(path).unwrap();
+            assert_eq!(decoded.kind, file.kind);
+            assert_eq!(decoded.content, file.content);
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(SourcePack::decode(b"").is_err());
+        assert!(SourcePack::decode(b"SHTRACE\0\x02\0\0\0")
+            .unwrap_err()
+            .contains("not a source pack"));
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let mut bytes = sample().encode();
+        bytes[MAGIC.len()] = 99;
+        assert!(SourcePack::decode(&bytes)
+            .unwrap_err()
+            .contains("unsupported pack version"));
+    }
+
+    #[test]
+    fn rejects_corrupted_files() {
+        let mut bytes = sample().encode();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(SourcePack::decode(&bytes)
+            .unwrap_err()
+            .contains("hash mismatch"));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = sample().encode();
+        for len in 0..bytes.len() {
+            assert!(
+                SourcePack::decode(&bytes[..len]).is_err(),
+                "truncated to {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_missing_entry() {
+        let mut pack = sample();
+        pack.entry = "missing.shs".to_string();
+        assert!(SourcePack::decode(&pack.encode())
+            .unwrap_err()
+            .contains("entry script missing.shs is missing"));
+
+        // Assets cannot be the entry script
+        pack.entry = "data/values.json".to_string();
+        assert!(SourcePack::decode(&pack.encode()).is_err());
+    }
+
+    #[test]
+    fn packs_includes_and_referenced_assets() {
+        let dir = scratch_dir("from-dir");
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("main.shs"),
+            "; \"commented.txt\"\n@include(\"lib/util.shs\")\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("lib/util.shs"),
+            "\"data.txt\" | FS.Read\n\"name.txt\" | Log\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("lib/data.txt"), "data").unwrap();
+        std::fs::write(dir.join("lib/name.txt"), "not packed").unwrap();
+        std::fs::write(dir.join("commented.txt"), "not packed").unwrap();
+
+        let pack = SourcePack::from_dir(&dir, "main.shs").unwrap();
+        let files: Vec<(&str, FileKind)> =
+            pack.files().map(|(path, file)| (path, file.kind)).collect();
+        assert_eq!(
+            files,
+            [
+                ("lib/data.txt", FileKind::Asset),
+                ("lib/util.shs", FileKind::Source),
+                ("main.shs", FileKind::Source),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_includes_outside_of_the_directory() {
+        let dir = scratch_dir("outside");
+        std::fs::write(dir.join("main.shs"), "@include(\"../other.shs\")\n").unwrap();
+        assert!(SourcePack::from_dir(&dir, "main.shs").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_links_outside_of_the_directory() {
+        let root = scratch_dir("links");
+        let dir = root.join("packed");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(root.join("secret.shs"), "secret").unwrap();
+        std::os::unix::fs::symlink(root.join("secret.shs"), dir.join("lib.shs")).unwrap();
+        std::fs::write(dir.join("main.shs"), "@include(\"lib.shs\")\n").unwrap();
+
+        let error = SourcePack::from_dir(&dir, "main.shs").unwrap_err();
+        assert!(error.to_string().contains("links outside"), "{}", error);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
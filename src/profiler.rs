@@ -0,0 +1,410 @@
+//! Lightweight per-shard profiler, independent of Tracy.
+//!
+//! Enabled with [`crate::Runtime::profile`]. After composition the `activate` function of every
+//! shard of the root wire and of the wires it references is swapped with a timing trampoline
+//! (see [`crate::instrument`]), shards nested in parameters (e.g. the branches of `If`) are
+//! accounted in the time of the shard that runs them too. Each wire gets its own stats.
+//!
+//! Time spent suspended (e.g. in `Pause` or `Await`) is not counted: a wire is seen suspended
+//! from its last shard activation event until its next one, when the mesh ended a tick or ran
+//! another wire in between. Allocation counts need [`CountingAllocator`] installed as the
+//! global allocator (the `shards` binary does with the `profile-allocations` feature) and
+//! only cover Rust allocations.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::instrument::Site;
+
+// Beyond this the trace file gets unwieldy, stats keep being collected
+const MAX_TRACE_EVENTS: usize = 1_000_000;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Global allocator counting allocations, install it to get allocation counts in profiles.
+///
+/// ```rust,ignore
+/// #[global_allocator]
+/// static ALLOCATOR: shards_embed::profiler::CountingAllocator = shards_embed::profiler::CountingAllocator;
+/// ```
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Timing of one shard (by name and source location) or one wire.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub name: String,
+    pub line: u32,
+    pub column: u32,
+    /// Activations of a shard, ticks of the root wire, runs of other wires.
+    pub calls: u64,
+    pub total: Duration,
+    /// Total time minus time spent in profiled shards called from this one, for a wire in the
+    /// shards of other wires.
+    pub self_time: Duration,
+    /// Rust allocations during the calls, including the ones of called shards.
+    pub allocations: u64,
+}
+
+#[derive(Debug, Clone)]
+struct TraceEvent {
+    name: String,
+    category: &'static str,
+    start: Duration,
+    duration: Duration,
+}
+
+/// Profile of an execution, see [`crate::RunOutcome::profile`].
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub shards: Vec<Stats>,
+    pub wires: Vec<Stats>,
+    trace: Vec<TraceEvent>,
+    dropped_events: usize,
+}
+
+impl Profile {
+    /// Human-readable report, sorted by self time.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+
+        let mut wires = self.wires.clone();
+        wires.sort_by(|a, b| b.self_time.cmp(&a.self_time));
+        let _ = writeln!(
+            out,
+            "{:<40} {:>10} {:>12} {:>12}",
+            "Wire", "Ticks", "Total ms", "Self ms"
+        );
+        for wire in &wires {
+            let _ = writeln!(
+                out,
+                "{:<40} {:>10} {:>12.3} {:>12.3}",
+                wire.name,
+                wire.calls,
+                millis(wire.total),
+                millis(wire.self_time)
+            );
+        }
+
+        let mut shards = self.shards.clone();
+        shards.sort_by(|a, b| b.self_time.cmp(&a.self_time));
+        let _ = writeln!(
+            out,
+            "\n{:<40} {:>10} {:>10} {:>12} {:>12} {:>10}",
+            "Shard", "Location", "Calls", "Total ms", "Self ms", "Allocs"
+        );
+        for shard in &shards {
+            let _ = writeln!(
+                out,
+                "{:<40} {:>10} {:>10} {:>12.3} {:>12.3} {:>10}",
+                shard.name,
+                format!("{}:{}", shard.line, shard.column),
+                shard.calls,
+                millis(shard.total),
+                millis(shard.self_time),
+                shard.allocations
+            );
+        }
+
+        if self.dropped_events > 0 {
+            let _ = writeln!(
+                out,
+                "\n{} trace events dropped (limit {})",
+                self.dropped_events, MAX_TRACE_EVENTS
+            );
+        }
+        out
+    }
+
+    /// Write a Chrome trace-event file, viewable in Perfetto or `chrome://tracing`.
+    pub fn write_chrome_trace(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut out = String::from("{\"displayTimeUnit\":\"ms\",\"traceEvents\":[");
+        for (i, event) in self.trace.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"pid\":1,\"tid\":1,\"ts\":{:.3},\"dur\":{:.3}}}",
+                escape_json(&event.name),
+                event.category,
+                event.start.as_secs_f64() * 1e6,
+                event.duration.as_secs_f64() * 1e6
+            );
+        }
+        out.push_str("]}");
+        std::fs::write(path, out)
+    }
+}
+
+struct Frame {
+    start: Instant,
+    // Suspended time of its context when it started
+    suspended: Duration,
+    children: Duration,
+    allocations: u64,
+    wire: usize,
+}
+
+/// The activations running in one context, a wire and the ones it runs inline.
+struct Context {
+    stack: Vec<Frame>,
+    suspended: Duration,
+    // Time and switch count of its last event
+    last: Instant,
+    switches: u64,
+}
+
+#[derive(Default)]
+struct WireStats {
+    stats: Stats,
+    // Time spent in the shards of other wires
+    nested: Duration,
+}
+
+/// Collects the timings of one instrumented wire and of the wires it references.
+pub(crate) struct Recorder {
+    origin: Instant,
+    // Indexed by shard pointer, several shards can share a name
+    shards: HashMap<usize, Stats>,
+    root: usize,
+    // Indexed by wire pointer, in the order they were found
+    wires: Vec<(usize, WireStats)>,
+    contexts: HashMap<usize, Context>,
+    // Context of the last event, switching to another one or ending a tick suspends it
+    current: usize,
+    switches: u64,
+    trace: Vec<TraceEvent>,
+    dropped_events: usize,
+}
+
+impl Recorder {
+    /// Profile the wires of `wires` (pointer and name), the root one first.
+    pub(crate) fn new(wires: Vec<(usize, String)>) -> Self {
+        Self {
+            origin: Instant::now(),
+            shards: HashMap::new(),
+            root: wires.first().map_or(0, |(wire, _)| *wire),
+            wires: wires
+                .into_iter()
+                .map(|(wire, name)| {
+                    let stats = WireStats {
+                        stats: Stats {
+                            name,
+                            ..Default::default()
+                        },
+                        nested: Duration::ZERO,
+                    };
+                    (wire, stats)
+                })
+                .collect(),
+            contexts: HashMap::new(),
+            current: 0,
+            switches: 0,
+            trace: Vec::new(),
+            dropped_events: 0,
+        }
+    }
+
+    /// A shard activation of wire `wire` starts in `context`.
+    pub(crate) fn enter(&mut self, context: usize, wire: usize) {
+        let now = Instant::now();
+        let context = self.resume(context, now);
+        context.stack.push(Frame {
+            start: now,
+            suspended: context.suspended,
+            children: Duration::ZERO,
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            wire,
+        });
+    }
+
+    /// The innermost activation of `shard` in `context` ended.
+    pub(crate) fn exit(&mut self, context: usize, shard: usize, site: &Site) {
+        let now = Instant::now();
+        let root = self.root;
+        let context = self.resume(context, now);
+        let Some(frame) = context.stack.pop() else {
+            return;
+        };
+
+        let duration = now
+            .duration_since(frame.start)
+            .saturating_sub(context.suspended - frame.suspended);
+        // The wire whose time this activation is part of, a spawned wire is part of the tick
+        let caller = match context.stack.last_mut() {
+            Some(parent) => {
+                parent.children += duration;
+                parent.wire
+            }
+            None => root,
+        };
+        if caller != frame.wire {
+            if let Some(wire) = self.wire_stats(caller) {
+                wire.nested += duration;
+            }
+            if frame.wire != root {
+                if let Some(wire) = self.wire_stats(frame.wire) {
+                    wire.stats.total += duration;
+                }
+            }
+        }
+        if site.first && frame.wire != root {
+            if let Some(wire) = self.wire_stats(frame.wire) {
+                wire.stats.calls += 1;
+            }
+        }
+
+        let stats = self.shards.entry(shard).or_insert_with(|| Stats {
+            name: site.name.clone(),
+            line: site.line,
+            column: site.column,
+            ..Default::default()
+        });
+        stats.calls += 1;
+        stats.total += duration;
+        stats.self_time += duration.saturating_sub(frame.children);
+        stats.allocations += ALLOCATIONS.load(Ordering::Relaxed) - frame.allocations;
+
+        self.trace(site.name.clone(), "shard", frame.start, duration);
+    }
+
+    /// Record a mesh tick that started at `start`.
+    pub(crate) fn tick(&mut self, start: Instant, duration: Duration) {
+        // The activations still running are suspended until a later tick
+        self.switches += 1;
+        self.current = 0;
+
+        let root = self.root;
+        if let Some(wire) = self.wire_stats(root) {
+            wire.stats.calls += 1;
+            wire.stats.total += duration;
+        }
+        let name = self
+            .wire_stats(root)
+            .map(|wire| wire.stats.name.clone())
+            .unwrap_or_default();
+        self.trace(name, "wire", start, duration);
+    }
+
+    /// The context of an event at `now`, the time since its previous event is suspended time
+    /// if the mesh ran something else in between.
+    fn resume(&mut self, context: usize, now: Instant) -> &mut Context {
+        if self.current != context {
+            self.switches += 1;
+            self.current = context;
+        }
+        let switches = self.switches;
+        let context = self.contexts.entry(context).or_insert_with(|| Context {
+            stack: Vec::new(),
+            suspended: Duration::ZERO,
+            last: now,
+            switches,
+        });
+        if context.switches != switches && !context.stack.is_empty() {
+            context.suspended += now.duration_since(context.last);
+        }
+        context.last = now;
+        context.switches = switches;
+        context
+    }
+
+    fn wire_stats(&mut self, wire: usize) -> Option<&mut WireStats> {
+        self.wires
+            .iter_mut()
+            .find(|(key, _)| *key == wire)
+            .map(|(_, stats)| stats)
+    }
+
+    /// Snapshot of the data collected so far.
+    pub(crate) fn profile(&self) -> Profile {
+        // Merge instances of the same shard at the same location
+        let mut merged: HashMap<(String, u32, u32), Stats> = HashMap::new();
+        for stats in self.shards.values() {
+            let entry = merged
+                .entry((stats.name.clone(), stats.line, stats.column))
+                .or_insert_with(|| Stats {
+                    name: stats.name.clone(),
+                    line: stats.line,
+                    column: stats.column,
+                    ..Default::default()
+                });
+            entry.calls += stats.calls;
+            entry.total += stats.total;
+            entry.self_time += stats.self_time;
+            entry.allocations += stats.allocations;
+        }
+
+        let wires = self
+            .wires
+            .iter()
+            .map(|(_, wire)| Stats {
+                self_time: wire.stats.total.saturating_sub(wire.nested),
+                ..wire.stats.clone()
+            })
+            .collect();
+
+        Profile {
+            shards: merged.into_values().collect(),
+            wires,
+            trace: self.trace.clone(),
+            dropped_events: self.dropped_events,
+        }
+    }
+
+    fn trace(&mut self, name: String, category: &'static str, start: Instant, duration: Duration) {
+        if self.trace.len() < MAX_TRACE_EVENTS {
+            self.trace.push(TraceEvent {
+                name,
+                category,
+                start: start.duration_since(self.origin),
+                duration,
+            });
+        } else {
+            self.dropped_events += 1;
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,356 @@
+//! Resource quotas for untrusted scripts.
+//!
+//! [`Quotas`] set with [`crate::Runtime::quotas`] bound what a script may consume. Exceeding a
+//! quota terminates the execution with [`crate::Error::QuotaExceeded`]:
+//!
+//! - Sequence, table and string sizes are checked on the output of every shard, nested
+//!   shards and the shards of referenced wires included, and on the variables written by
+//!   `Set`, `Ref`, `Update`, `Push`, `AppendTo` and the like after each of their activations,
+//!   so values built in place are bounded too.
+//! - Shards executed per tick count every activation, nested ones included.
+//! - The run time is the wall-clock time since the execution started, checked between ticks
+//!   and before every shard activation, so a wire looping without yielding (a `Repeat` with
+//!   no `Pause`) is stopped too. A single shard blocking in native code is not interrupted.
+//! - The memory quota bounds the memory held by the values of the variables these shards
+//!   write, measured after each of their activations: the Vars themselves, the contents of
+//!   strings, and the elements of sequences and tables, nested ones included. Memory held
+//!   natively by shards or by values of other types (images, objects) is not counted.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use shards::shardsc::{SHContext, SHStringWithLen, SHVar, Shard};
+use shards::types::Wire;
+
+use crate::instrument::{
+    abort, held_bytes, shard_name, shards_by_wire, variable, written_variable, Hook, Interposition,
+    Next,
+};
+
+/// A resource bounded by [`Quotas`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    Memory,
+    SeqLen,
+    TableLen,
+    StringLen,
+    ShardsPerTick,
+    RunTime,
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Quota::Memory => "memory",
+            Quota::SeqLen => "sequence length",
+            Quota::TableLen => "table length",
+            Quota::StringLen => "string length",
+            Quota::ShardsPerTick => "shards per tick",
+            Quota::RunTime => "run time",
+        })
+    }
+}
+
+/// Limits of an execution, none by default.
+///
+/// ```rust,ignore
+/// let quotas = shards_embed::Quotas::new()
+///     .memory(256 << 20)
+///     .seq_len(100_000)
+///     .shards_per_tick(1_000_000)
+///     .run_time(Duration::from_secs(10));
+/// let runtime = shards_embed::Runtime::new().quotas(quotas);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Quotas {
+    memory: Option<u64>,
+    seq_len: Option<usize>,
+    table_len: Option<usize>,
+    string_len: Option<usize>,
+    shards_per_tick: Option<u64>,
+    run_time: Option<Duration>,
+}
+
+impl Quotas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Memory held by the variables of the execution, in bytes, see the
+    /// [module documentation](self) for what is counted.
+    pub fn memory(mut self, bytes: u64) -> Self {
+        self.memory = Some(bytes);
+        self
+    }
+
+    pub fn seq_len(mut self, len: usize) -> Self {
+        self.seq_len = Some(len);
+        self
+    }
+
+    pub fn table_len(mut self, len: usize) -> Self {
+        self.table_len = Some(len);
+        self
+    }
+
+    /// Length of strings, in bytes.
+    pub fn string_len(mut self, len: usize) -> Self {
+        self.string_len = Some(len);
+        self
+    }
+
+    pub fn shards_per_tick(mut self, shards: u64) -> Self {
+        self.shards_per_tick = Some(shards);
+        self
+    }
+
+    pub fn run_time(mut self, limit: Duration) -> Self {
+        self.run_time = Some(limit);
+        self
+    }
+
+    fn checks_activations(&self) -> bool {
+        self.memory.is_some()
+            || self.seq_len.is_some()
+            || self.table_len.is_some()
+            || self.string_len.is_some()
+            || self.shards_per_tick.is_some()
+            || self.run_time.is_some()
+    }
+
+    /// The quota `output` exceeds, with its size and the limit.
+    unsafe fn oversized(&self, output: &SHVar) -> Option<(Quota, usize, usize)> {
+        let payload = &output.payload.__bindgen_anon_1;
+        let (quota, limit, len) = match output.valueType {
+            shards::shardsc::SHType_Seq => {
+                (Quota::SeqLen, self.seq_len?, payload.seqValue.len as usize)
+            }
+            shards::shardsc::SHType_String => (
+                Quota::StringLen,
+                self.string_len?,
+                payload.__bindgen_anon_1.stringLen as usize,
+            ),
+            shards::shardsc::SHType_Table => {
+                let limit = self.table_len?;
+                let table = payload.tableValue;
+                (
+                    Quota::TableLen,
+                    limit,
+                    (*table.api).tableSize.unwrap()(table) as usize,
+                )
+            }
+            _ => return None,
+        };
+        (len > limit).then_some((quota, len, limit))
+    }
+
+    /// Start enforcing the quotas on `wire`, must happen after it was composed.
+    pub(crate) fn enforce(&self, wire: &Wire) -> Limiter {
+        let state = Arc::new(LimiterState {
+            quotas: self.clone(),
+            activations: AtomicU64::new(0),
+            exceeded: Mutex::new(None),
+            started: Mutex::new(Instant::now()),
+            held: Mutex::new(HashMap::new()),
+        });
+
+        let by_wire = if self.checks_activations() {
+            shards_by_wire(wire)
+        } else {
+            Vec::new()
+        };
+        let interposition =
+            Interposition::install(by_wire.into_iter().flat_map(|(wire, shards)| {
+                let state = state.clone();
+                shards.into_iter().map(move |shard| {
+                    let writes =
+                        unsafe { written_variable(shard) }.map(|name| (wire as usize, name));
+                    let hook = Limited {
+                        state: state.clone(),
+                        writes,
+                    };
+                    (shard, hook)
+                })
+            }));
+
+        Limiter {
+            _interposition: interposition,
+            state,
+        }
+    }
+}
+
+struct LimiterState {
+    quotas: Quotas,
+    activations: AtomicU64,
+    exceeded: Mutex<Option<(Quota, String)>>,
+    started: Mutex<Instant>,
+    // Bytes held by each written variable, by wire and name
+    held: Mutex<HashMap<(usize, String), usize>>,
+}
+
+impl LimiterState {
+    /// Remember the first quota exceeded.
+    fn exceed(&self, quota: Quota, message: String) {
+        self.exceeded
+            .lock()
+            .unwrap()
+            .get_or_insert((quota, message));
+    }
+
+    /// Check the run time quota, returns `false` once exceeded.
+    fn check_run_time(&self) -> bool {
+        let Some(limit) = self.quotas.run_time else {
+            return true;
+        };
+        let elapsed = self.started.lock().unwrap().elapsed();
+        if elapsed <= limit {
+            return true;
+        }
+        self.exceed(
+            Quota::RunTime,
+            format!("ran for {:?} (limit {:?})", elapsed, limit),
+        );
+        false
+    }
+
+    /// Check the quotas on the value of a variable just written, returns `false` once exceeded.
+    unsafe fn check_variable(&self, key: &(usize, String), value: &SHVar) -> bool {
+        if let Some((quota, len, limit)) = self.quotas.oversized(value) {
+            self.exceed(
+                quota,
+                format!("variable {} has length {} (limit {})", key.1, len, limit),
+            );
+            return false;
+        }
+
+        let Some(limit) = self.quotas.memory else {
+            return true;
+        };
+        let mut held = self.held.lock().unwrap();
+        held.insert(key.clone(), held_bytes(value));
+        let total: usize = held.values().sum();
+        if total as u64 <= limit {
+            return true;
+        }
+        drop(held);
+        self.exceed(
+            Quota::Memory,
+            format!(
+                "variables hold {} bytes after writing {} (limit {})",
+                total, key.1, limit
+            ),
+        );
+        false
+    }
+
+    fn message(&self) -> Option<String> {
+        self.exceeded
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(quota, message)| format!("{} quota exceeded: {}", quota, message))
+    }
+}
+
+struct Limited {
+    state: Arc<LimiterState>,
+    // Variable the shard writes, with the wire owning the shard
+    writes: Option<(usize, String)>,
+}
+
+/// Quotas enforced on a wire, see [`Quotas::enforce`]. Removes its checks when dropped.
+pub(crate) struct Limiter {
+    _interposition: Interposition,
+    state: Arc<LimiterState>,
+}
+
+impl Limiter {
+    /// Check the quotas measured between ticks, call before every tick.
+    pub(crate) fn begin_tick(&self) {
+        self.state.activations.store(0, Ordering::Relaxed);
+        self.state.check_run_time();
+    }
+
+    /// The first quota exceeded and its details.
+    pub(crate) fn exceeded(&self) -> Option<(Quota, String)> {
+        self.state.exceeded.lock().unwrap().clone()
+    }
+
+    /// Start over, when the wire is restarted.
+    pub(crate) fn reset(&mut self) {
+        *self.state.exceeded.lock().unwrap() = None;
+        *self.state.started.lock().unwrap() = Instant::now();
+        self.state.held.lock().unwrap().clear();
+    }
+}
+
+impl Hook for Limited {
+    unsafe fn activate(
+        &self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        input: *const SHVar,
+        next: Next,
+    ) -> SHVar {
+        let state = &self.state;
+
+        // Keep failing until the execution notices
+        if let Some(message) = state.message() {
+            abort(context, &message);
+            return SHVar::default();
+        }
+
+        // Also stops wires that never yield back to the execution
+        if !state.check_run_time() {
+            abort(context, &state.message().unwrap_or_default());
+            return SHVar::default();
+        }
+
+        if let Some(limit) = state.quotas.shards_per_tick {
+            let activations = state.activations.fetch_add(1, Ordering::Relaxed) + 1;
+            if activations > limit {
+                state.exceed(
+                    Quota::ShardsPerTick,
+                    format!("more than {} shard activations in one tick", limit),
+                );
+                abort(context, &state.message().unwrap_or_default());
+                return SHVar::default();
+            }
+        }
+
+        let output = next.activate(shard, context, input);
+
+        if let Some((quota, len, limit)) = state.quotas.oversized(&output) {
+            state.exceed(
+                quota,
+                format!(
+                    "{} at {}:{} output a value of length {} (limit {})",
+                    shard_name(shard),
+                    (*shard).line,
+                    (*shard).column,
+                    len,
+                    limit
+                ),
+            );
+            abort(context, &state.message().unwrap_or_default());
+            return output;
+        }
+
+        if let Some(key) = &self.writes {
+            let name = SHStringWithLen {
+                string: key.1.as_ptr() as *const _,
+                len: key.1.len() as _,
+            };
+            if let Some(value) = variable(context, name) {
+                if !state.check_variable(key, &value) {
+                    abort(context, &state.message().unwrap_or_default());
+                }
+            }
+        }
+        output
+    }
+}
@@ -0,0 +1,664 @@
+//! Recording and replaying the nondeterministic inputs of a run.
+//!
+//! A runtime built with [`crate::Runtime::record`] captures the script source, the sources of
+//! the files it `@include`s, the defines injected by the host and the output of every activation of the shards listed in
+//! [`RECORDED_SHARDS`] (random draws, clocks, channel messages, HTTP responses and file reads)
+//! into a [`Trace`]. [`crate::Runtime::replay_file`] runs the recorded sources again, the
+//! recorded shards returning their recorded outputs instead of running, so an incident can be
+//! reproduced on another machine without the script files.
+//!
+//! Values are recorded per shard location, a shard that runs more often than it did while
+//! recording fails the wire. Only none, boolean, integer, float, string, path, sequence and
+//! table outputs can be recorded, others fail the wire when replayed. Sequences and tables
+//! nested deeper than [`MAX_DEPTH`] are not recorded either.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use shards::shardsc::{SHContext, SHTable, SHVar, Shard};
+use shards::types::Wire;
+
+use crate::codec::{put_str, put_u32, Reader};
+use crate::deterministic::{CLOCK_SHARDS, RANDOM_SHARDS};
+use crate::instrument::{abort, shard_name, shards_of, table_entries, Hook, Interposition, Next};
+use crate::Error;
+
+/// Shards reading inputs from outside of the script, recorded along with [`RANDOM_SHARDS`] and
+/// [`CLOCK_SHARDS`].
+pub const INPUT_SHARDS: &[&str] = &[
+    "Consume",
+    "Listen",
+    "Http.Get",
+    "Http.Post",
+    "Http.Put",
+    "Http.Patch",
+    "Http.Delete",
+    "FS.Read",
+];
+
+/// All the shards whose outputs are recorded.
+pub const RECORDED_SHARDS: &[&[&str]] = &[RANDOM_SHARDS, CLOCK_SHARDS, INPUT_SHARDS];
+
+/// Deepest nesting of sequences and tables in a recorded output.
+pub const MAX_DEPTH: usize = 64;
+
+const MAGIC: &[u8; 8] = b"SHTRACE\0";
+const VERSION: u32 = 2;
+
+/// A recorded output.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Path(String),
+    Seq(Vec<Value>),
+    /// Keys and values, in the order of the table.
+    Table(Vec<(Value, Value)>),
+    /// Of a type that cannot be recorded, with its type id.
+    Unsupported(u8),
+}
+
+/// Identifies a recorded shard across runs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Site {
+    shard: String,
+    line: u32,
+    column: u32,
+}
+
+impl Site {
+    unsafe fn of(shard: *mut Shard) -> Self {
+        Site {
+            shard: shard_name(shard),
+            line: (*shard).line,
+            column: (*shard).column,
+        }
+    }
+}
+
+/// The inputs a run consumed, see [`crate::replay`].
+#[derive(Debug, Clone)]
+pub struct Trace {
+    /// Source of the script.
+    pub source: String,
+    /// Path the script was loaded from, used to resolve includes.
+    pub path: PathBuf,
+    /// Files the script included and their source, replayed instead of reading them again.
+    pub includes: Vec<(PathBuf, String)>,
+    /// Defines injected by the host, sorted by key.
+    pub defines: Vec<(String, String)>,
+    // In order of activation
+    events: Vec<(Site, Value)>,
+}
+
+impl Trace {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        Self::decode(&bytes).map_err(|e| Error::Trace(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        std::fs::write(path, self.encode())?;
+        Ok(())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        put_str(&mut out, &self.source);
+        put_str(&mut out, &self.path.to_string_lossy());
+        put_u32(&mut out, self.includes.len() as u32);
+        for (path, source) in &self.includes {
+            put_str(&mut out, &path.to_string_lossy());
+            put_str(&mut out, source);
+        }
+        put_u32(&mut out, self.defines.len() as u32);
+        for (key, value) in &self.defines {
+            put_str(&mut out, key);
+            put_str(&mut out, value);
+        }
+        put_u32(&mut out, self.events.len() as u32);
+        for (site, value) in &self.events {
+            put_str(&mut out, &site.shard);
+            put_u32(&mut out, site.line);
+            put_u32(&mut out, site.column);
+            put_value(&mut out, value);
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut input = Reader(bytes);
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err("not a trace file".to_string());
+        }
+        let version = input.u32()?;
+        if version != VERSION {
+            return Err(format!(
+                "unsupported trace version {} (expected {})",
+                version, VERSION
+            ));
+        }
+
+        let source = input.string()?;
+        let path = PathBuf::from(input.string()?);
+        let includes = (0..input.u32()?)
+            .map(|_| Ok((PathBuf::from(input.string()?), input.string()?)))
+            .collect::<Result<_, String>>()?;
+        let defines = (0..input.u32()?)
+            .map(|_| Ok((input.string()?, input.string()?)))
+            .collect::<Result<_, String>>()?;
+        let events = (0..input.u32()?)
+            .map(|_| {
+                let site = Site {
+                    shard: input.string()?,
+                    line: input.u32()?,
+                    column: input.u32()?,
+                };
+                Ok((site, read_value(&mut input, 0)?))
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Trace {
+            source,
+            path,
+            includes,
+            defines,
+            events,
+        })
+    }
+}
+
+fn put_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::None => out.push(0),
+        Value::Bool(b) => out.extend_from_slice(&[1, *b as u8]),
+        Value::Int(i) => {
+            out.push(2);
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        Value::Float(f) => {
+            out.push(3);
+            out.extend_from_slice(&f.to_le_bytes());
+        }
+        Value::String(s) => {
+            out.push(4);
+            put_str(out, s);
+        }
+        Value::Path(s) => {
+            out.push(5);
+            put_str(out, s);
+        }
+        Value::Seq(values) => {
+            out.push(6);
+            put_u32(out, values.len() as u32);
+            for value in values {
+                put_value(out, value);
+            }
+        }
+        Value::Unsupported(ty) => out.extend_from_slice(&[7, *ty]),
+        Value::Table(entries) => {
+            out.push(8);
+            put_u32(out, entries.len() as u32);
+            for (key, value) in entries {
+                put_value(out, key);
+                put_value(out, value);
+            }
+        }
+    }
+}
+
+fn read_value(input: &mut Reader, depth: usize) -> Result<Value, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("values nested deeper than {}", MAX_DEPTH));
+    }
+    Ok(match input.u8()? {
+        0 => Value::None,
+        1 => Value::Bool(input.u8()? != 0),
+        2 => Value::Int(input.u64()? as i64),
+        3 => Value::Float(f64::from_bits(input.u64()?)),
+        4 => Value::String(input.string()?),
+        5 => Value::Path(input.string()?),
+        6 => Value::Seq(
+            (0..input.u32()?)
+                .map(|_| read_value(input, depth + 1))
+                .collect::<Result<_, _>>()?,
+        ),
+        7 => Value::Unsupported(input.u8()?),
+        8 => Value::Table(
+            (0..input.u32()?)
+                .map(|_| Ok((read_value(input, depth + 1)?, read_value(input, depth + 1)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        tag => return Err(format!("invalid value tag {}", tag)),
+    })
+}
+
+unsafe fn value_of(var: &SHVar, depth: usize) -> Value {
+    let payload = &var.payload.__bindgen_anon_1;
+    let string = || {
+        let s = &payload.__bindgen_anon_1;
+        if s.stringValue.is_null() {
+            return String::new();
+        }
+        let bytes = std::slice::from_raw_parts(s.stringValue as *const u8, s.stringLen as usize);
+        String::from_utf8_lossy(bytes).into_owned()
+    };
+
+    match var.valueType {
+        shards::shardsc::SHType_None => Value::None,
+        shards::shardsc::SHType_Bool => Value::Bool(payload.boolValue),
+        shards::shardsc::SHType_Int => Value::Int(payload.intValue),
+        shards::shardsc::SHType_Float => Value::Float(payload.floatValue),
+        shards::shardsc::SHType_String => Value::String(string()),
+        shards::shardsc::SHType_Path => Value::Path(string()),
+        shards::shardsc::SHType_Seq if depth < MAX_DEPTH => {
+            let seq = payload.seqValue;
+            Value::Seq(
+                (0..seq.len)
+                    .map(|i| value_of(&*seq.elements.add(i as usize), depth + 1))
+                    .collect(),
+            )
+        }
+        shards::shardsc::SHType_Table if depth < MAX_DEPTH => Value::Table(
+            table_entries(payload.tableValue)
+                .iter()
+                .map(|(key, value)| (value_of(key, depth + 1), value_of(value, depth + 1)))
+                .collect(),
+        ),
+        ty => Value::Unsupported(ty as u8),
+    }
+}
+
+/// A replayed output and the memory it points to, alive until the shard's next activation.
+struct Output {
+    var: SHVar,
+    strings: Vec<Vec<u8>>,
+    seqs: Vec<Vec<SHVar>>,
+    tables: Vec<SHTable>,
+}
+
+// The pointers of `var` only point into the buffers and tables owned alongside it
+unsafe impl Send for Output {}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        for table in &self.tables {
+            unsafe { (*table.api).tableFree.unwrap()(*table) };
+        }
+    }
+}
+
+impl Output {
+    fn new(value: &Value) -> Option<Self> {
+        let mut output = Output {
+            var: SHVar::default(),
+            strings: Vec::new(),
+            seqs: Vec::new(),
+            tables: Vec::new(),
+        };
+        output.var = output.build(value)?;
+        Some(output)
+    }
+
+    fn build(&mut self, value: &Value) -> Option<SHVar> {
+        let mut var = SHVar::default();
+        unsafe {
+            let payload = &mut var.payload.__bindgen_anon_1;
+            match value {
+                Value::None => {}
+                Value::Bool(b) => {
+                    var.valueType = shards::shardsc::SHType_Bool;
+                    payload.boolValue = *b;
+                }
+                Value::Int(i) => {
+                    var.valueType = shards::shardsc::SHType_Int;
+                    payload.intValue = *i;
+                }
+                Value::Float(f) => {
+                    var.valueType = shards::shardsc::SHType_Float;
+                    payload.floatValue = *f;
+                }
+                Value::String(s) | Value::Path(s) => {
+                    var.valueType = if matches!(value, Value::Path(_)) {
+                        shards::shardsc::SHType_Path
+                    } else {
+                        shards::shardsc::SHType_String
+                    };
+                    // Null terminated, the core accepts both
+                    let mut bytes = s.as_bytes().to_vec();
+                    bytes.push(0);
+                    payload.__bindgen_anon_1.stringValue = bytes.as_ptr() as *const _;
+                    payload.__bindgen_anon_1.stringLen = s.len() as _;
+                    self.strings.push(bytes);
+                }
+                Value::Seq(values) => {
+                    let mut elements = values
+                        .iter()
+                        .map(|value| self.build(value))
+                        .collect::<Option<Vec<_>>>()?;
+                    var.valueType = shards::shardsc::SHType_Seq;
+                    payload.seqValue.elements = elements.as_mut_ptr();
+                    payload.seqValue.len = elements.len() as _;
+                    payload.seqValue.cap = elements.len() as _;
+                    self.seqs.push(elements);
+                }
+                Value::Table(entries) => {
+                    let core = &*shards::core::Core;
+                    let table = core.tableNew.unwrap()();
+                    // Owned from here on, even if an entry cannot be built
+                    self.tables.push(table);
+                    for (key, value) in entries {
+                        let (key, value) = (self.build(key)?, self.build(value)?);
+                        let slot = (*table.api).tableAt.unwrap()(table, key);
+                        core.cloneVar.unwrap()(slot, &value);
+                    }
+                    var.valueType = shards::shardsc::SHType_Table;
+                    payload.tableValue = table;
+                }
+                Value::Unsupported(_) => return None,
+            }
+        }
+        Some(var)
+    }
+}
+
+struct Recording {
+    source: String,
+    path: PathBuf,
+    includes: Vec<(PathBuf, String)>,
+    defines: Vec<(String, String)>,
+    events: Mutex<Vec<(Site, Value)>>,
+}
+
+struct Replaying {
+    queues: Mutex<HashMap<Site, VecDeque<Value>>>,
+}
+
+struct Recorded {
+    site: Site,
+    state: Arc<Recording>,
+}
+
+struct Replayed {
+    site: Site,
+    state: Arc<Replaying>,
+    output: Mutex<Option<Output>>,
+}
+
+fn is_recorded(name: &str) -> bool {
+    RECORDED_SHARDS.iter().any(|shards| shards.contains(&name))
+}
+
+/// Recorded shards of a wire being recorded or replayed, restored when dropped.
+pub(crate) struct Tape {
+    _interposition: Interposition,
+    recording: Option<Arc<Recording>>,
+}
+
+impl Tape {
+    /// Record the recorded shards of `wire`, loaded from `source` and the sources it included.
+    /// Must happen after the wire was composed.
+    pub(crate) fn record(
+        wire: &Wire,
+        source: &str,
+        path: &Path,
+        includes: Vec<(PathBuf, String)>,
+        defines: &HashMap<String, String>,
+    ) -> Self {
+        let mut defines: Vec<_> = defines
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        defines.sort();
+        let state = Arc::new(Recording {
+            source: source.to_string(),
+            path: path.to_path_buf(),
+            includes,
+            defines,
+            events: Mutex::new(Vec::new()),
+        });
+
+        let interposition = install(wire, |site| Recorded {
+            site,
+            state: state.clone(),
+        });
+        Tape {
+            _interposition: interposition,
+            recording: Some(state),
+        }
+    }
+
+    /// Feed the outputs recorded in `trace` to the recorded shards of `wire`. Must happen after
+    /// the wire was composed.
+    pub(crate) fn replay(wire: &Wire, trace: &Trace) -> Self {
+        let mut queues: HashMap<Site, VecDeque<Value>> = HashMap::new();
+        for (site, value) in &trace.events {
+            queues
+                .entry(site.clone())
+                .or_default()
+                .push_back(value.clone());
+        }
+        let state = Arc::new(Replaying {
+            queues: Mutex::new(queues),
+        });
+
+        let interposition = install(wire, |site| Replayed {
+            site,
+            state: state.clone(),
+            output: Mutex::new(None),
+        });
+        Tape {
+            _interposition: interposition,
+            recording: None,
+        }
+    }
+
+    /// The inputs recorded so far, if recording.
+    pub(crate) fn trace(&self) -> Option<Trace> {
+        let recording = self.recording.as_ref()?;
+        Some(Trace {
+            source: recording.source.clone(),
+            path: recording.path.clone(),
+            includes: recording.includes.clone(),
+            defines: recording.defines.clone(),
+            events: recording.events.lock().unwrap().clone(),
+        })
+    }
+}
+
+/// Install the hook made by `taped_as` on the recorded shards of `wire`.
+fn install<H: Hook>(wire: &Wire, taped_as: impl Fn(Site) -> H) -> Interposition {
+    Interposition::install(shards_of(wire).into_iter().filter_map(|shard| {
+        let site = unsafe { Site::of(shard) };
+        is_recorded(&site.shard).then(|| (shard, taped_as(site)))
+    }))
+}
+
+impl Hook for Recorded {
+    unsafe fn activate(
+        &self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        input: *const SHVar,
+        next: Next,
+    ) -> SHVar {
+        let output = next.activate(shard, context, input);
+        let value = value_of(&output, 0);
+        self.state
+            .events
+            .lock()
+            .unwrap()
+            .push((self.site.clone(), value));
+        output
+    }
+}
+
+impl Hook for Replayed {
+    // Replaces the shard, `next` is not called
+    unsafe fn activate(
+        &self,
+        _shard: *mut Shard,
+        context: *mut SHContext,
+        _input: *const SHVar,
+        _next: Next,
+    ) -> SHVar {
+        let site = &self.site;
+        let value = self
+            .state
+            .queues
+            .lock()
+            .unwrap()
+            .get_mut(site)
+            .and_then(VecDeque::pop_front);
+        let Some(value) = value else {
+            abort(
+                context,
+                &format!(
+                    "Replay diverged: no recorded output left for {} at {}:{}",
+                    site.shard, site.line, site.column
+                ),
+            );
+            return SHVar::default();
+        };
+        let Some(output) = Output::new(&value) else {
+            abort(
+                context,
+                &format!(
+                    "Replay failed: the output of {} at {}:{} could not be recorded",
+                    site.shard, site.line, site.column
+                ),
+            );
+            return SHVar::default();
+        };
+
+        let var = output.var;
+        *self.output.lock().unwrap() = Some(output);
+        var
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(shard: &str, line: u32) -> Site {
+        Site {
+            shard: shard.to_string(),
+            line,
+            column: 5,
+        }
+    }
+
+    fn sample() -> Trace {
+        Trace {
+            source: "@include(\"lib.shs\")\nRandomInt | Log".to_string(),
+            path: PathBuf::from("scripts/main.shs"),
+            includes: vec![(PathBuf::from("scripts/lib.shs"), "1 | Log".to_string())],
+            defines: vec![("name".to_string(), "value".to_string())],
+            events: vec![
+                (site("RandomInt", 2), Value::Int(-42)),
+                (site("Time.Now", 3), Value::Float(1.5)),
+                (
+                    site("FS.Read", 4),
+                    Value::Seq(vec![
+                        Value::None,
+                        Value::Bool(true),
+                        Value::String("text".to_string()),
+                        Value::Path("a/b".to_string()),
+                        Value::Seq(vec![Value::Unsupported(42)]),
+                    ]),
+                ),
+                (
+                    site("Http.Get", 5),
+                    Value::Table(vec![
+                        (Value::String("status".to_string()), Value::Int(200)),
+                        (
+                            Value::String("headers".to_string()),
+                            Value::Table(vec![(
+                                Value::String("content-type".to_string()),
+                                Value::String("text/plain".to_string()),
+                            )]),
+                        ),
+                        (
+                            Value::String("body".to_string()),
+                            Value::String("ok".to_string()),
+                        ),
+                    ]),
+                ),
+            ],
+        }
+    }
+
+    /// A value of `depth` sequences nested in each other, encoded.
+    fn nested(depth: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for _ in 0..depth {
+            out.push(6);
+            put_u32(&mut out, 1);
+        }
+        out.push(0);
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let trace = sample();
+        let decoded = Trace::decode(&trace.encode()).unwrap();
+        assert_eq!(decoded.source, trace.source);
+        assert_eq!(decoded.path, trace.path);
+        assert_eq!(decoded.includes, trace.includes);
+        assert_eq!(decoded.defines, trace.defines);
+        assert_eq!(decoded.events, trace.events);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Trace::decode(b"").is_err());
+        assert!(Trace::decode(b"SHPACK\0\0\x01\0\0\0")
+            .unwrap_err()
+            .contains("not a trace file"));
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let mut bytes = sample().encode();
+        bytes[MAGIC.len()] = 99;
+        assert!(Trace::decode(&bytes)
+            .unwrap_err()
+            .contains("unsupported trace version"));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = sample().encode();
+        for len in 0..bytes.len() {
+            assert!(
+                Trace::decode(&bytes[..len]).is_err(),
+                "truncated to {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(read_value(&mut Reader(&[9]), 0)
+            .unwrap_err()
+            .contains("invalid value tag 9"));
+        assert!(read_value(&mut Reader(&[4, 2, 0, 0, 0, 0xff, 0xfe]), 0).is_err());
+    }
+
+    #[test]
+    fn bounds_the_nesting_of_values() {
+        assert!(read_value(&mut Reader(&nested(MAX_DEPTH)), 0).is_ok());
+        assert!(read_value(&mut Reader(&nested(MAX_DEPTH + 1)), 0)
+            .unwrap_err()
+            .contains("nested deeper"));
+    }
+}
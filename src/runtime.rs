@@ -0,0 +1,772 @@
+//! In-process script execution driven from Rust.
+//!
+//! Unlike [`crate::run_file`], which goes through the shards CLI, a [`Runtime`] parses,
+//! evaluates and ticks the script itself, so the host gets structured errors, timings and the
+//! final output of the root wire.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use shards::shardsc::SHWireRef;
+use shards::types::{Mesh, Wire};
+use shards_lang::ast::Program;
+use shards_lang::read::{read_with_env, ReadEnv};
+
+use crate::deterministic::{check_seedable, Determinism};
+use crate::hooks::Hooks;
+use crate::includes::normalize;
+use crate::instrument::Instrumentation;
+#[cfg(feature = "metrics")]
+use crate::metrics::{ChannelDepths, VarMemory, WireMetrics};
+use crate::pack::SourcePack;
+use crate::profiler::Profile;
+use crate::quota::{Limiter, Quotas};
+use crate::replay::{Tape, Trace};
+use crate::sandbox::{Guards, Sandbox};
+use crate::vfs::{Mount, OverlayVfs, Vfs};
+use crate::{Error, SourceLocation, WireEvent, WireFailure};
+
+/// Result of a successful run.
+#[derive(Debug, Clone)]
+pub struct RunOutcome {
+    /// Final output of the root wire, printed.
+    pub output: String,
+    /// Number of mesh ticks until the wire finished.
+    pub ticks: u64,
+    pub parse_time: Duration,
+    pub eval_time: Duration,
+    pub run_time: Duration,
+    /// Per-shard timings, if the runtime was built with [`Runtime::profile`].
+    pub profile: Option<Profile>,
+}
+
+/// A script parsed at compile time by [`crate::include_shards!`], loaded without touching the
+/// disk, includes already resolved.
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedScript {
+    /// Absolute path of the script when it was embedded.
+    pub path: &'static str,
+    pub source: &'static str,
+    /// The parsed program, serialized like the output of `shards build`.
+    pub program: &'static [u8],
+}
+
+/// Runs scripts in-process.
+///
+/// ```rust,ignore
+/// let outcome = shards_embed::Runtime::new()
+///     .define("name", "world")
+///     .run_file("script.shs")?;
+/// println!("{}", outcome.output);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Runtime {
+    defines: HashMap<String, String>,
+    pub(crate) tick_interval: Duration,
+    profile: bool,
+    pub(crate) hooks: Hooks,
+    pub(crate) sandbox: Option<Sandbox>,
+    quotas: Option<Quotas>,
+    seed: Option<u64>,
+    virtual_time: bool,
+    record: Option<PathBuf>,
+    replay: Option<Arc<Trace>>,
+    vfs: Option<Arc<dyn Vfs>>,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self {
+            defines: HashMap::new(),
+            tick_interval: Duration::from_millis(1),
+            profile: false,
+            hooks: Hooks::default(),
+            sandbox: None,
+            quotas: None,
+            seed: None,
+            virtual_time: false,
+            record: None,
+            replay: None,
+            vfs: None,
+        }
+    }
+}
+
+impl Runtime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Define a value visible to the script, same as a `key:value` CLI argument.
+    pub fn define(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.defines.insert(key.into(), value.into());
+        self
+    }
+
+    /// Time slept between mesh ticks.
+    pub fn tick_interval(mut self, interval: Duration) -> Self {
+        self.tick_interval = interval;
+        self
+    }
+
+    /// Record per-shard and per-wire timings, see [`crate::profiler`].
+    pub fn profile(mut self, enabled: bool) -> Self {
+        self.profile = enabled;
+        self
+    }
+
+    /// Call `callback` when a wire of an execution or supervisor starts, stops, fails or restarts.
+    ///
+    /// Callbacks run on the thread ticking the execution. Registering one instruments the wire
+    /// so that failures name the failing shard, see [`WireFailure::shard`].
+    ///
+    /// ```rust,ignore
+    /// let runtime = shards_embed::Runtime::new().on_wire_event(|event| {
+    ///     if let shards_embed::WireEvent::Failed(failure) = event {
+    ///         eprintln!("{} (input: {:?})", failure, failure.input);
+    ///     }
+    /// });
+    /// ```
+    pub fn on_wire_event(mut self, callback: impl Fn(&WireEvent) + Send + Sync + 'static) -> Self {
+        self.hooks.add(Arc::new(callback));
+        self
+    }
+
+    /// Check scripts against `sandbox` before running them, see [`crate::sandbox`].
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Terminate executions exceeding `quotas`, see [`crate::quota`].
+    ///
+    /// Only applies to executions, not to the wires of a [`crate::Supervisor`].
+    pub fn quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    /// Make the random shards of executions draw from a generator seeded with `seed`, see
+    /// [`crate::deterministic`].
+    ///
+    /// Only applies to executions, not to the wires of a [`crate::Supervisor`].
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Make the clock shards of executions read a virtual clock advanced by the tick interval
+    /// on every tick, see [`crate::deterministic`].
+    ///
+    /// Only applies to executions, not to the wires of a [`crate::Supervisor`].
+    pub fn virtual_time(mut self, enabled: bool) -> Self {
+        self.virtual_time = enabled;
+        self
+    }
+
+    /// Record the nondeterministic inputs of runs into the trace file `path`, see
+    /// [`crate::replay`].
+    ///
+    /// [`Runtime::run_file`] and [`Runtime::run_source`] write the trace once the run ended,
+    /// failed or not. Executions started by the caller record too, see [`Execution::trace`].
+    pub fn record(mut self, path: impl Into<PathBuf>) -> Self {
+        self.record = Some(path.into());
+        self
+    }
+
+    /// Read scripts, their includes and the files of the file shards from `vfs` instead of the
+    /// disk, see [`crate::vfs`].
+    ///
+    /// The file shards are only served to executions, not to the wires of a
+    /// [`crate::Supervisor`].
+    pub fn vfs(mut self, vfs: impl Vfs + 'static) -> Self {
+        self.vfs = Some(Arc::new(vfs));
+        self
+    }
+
+    /// Run the script recorded in the trace file `path` again, feeding back the recorded
+    /// inputs. The defines of the trace replace the ones of the runtime.
+    pub fn replay_file(&self, path: impl AsRef<Path>) -> Result<RunOutcome, Error> {
+        let trace = Trace::read(path)?;
+        let (source, script) = (trace.source.clone(), trace.path.clone());
+        self.replaying(trace).run_source(&source, &script)
+    }
+
+    /// A runtime replaying `trace`, with its defines and without recording.
+    pub(crate) fn replaying(&self, trace: Trace) -> Runtime {
+        let mut runtime = self.clone();
+        runtime.defines = trace.defines.iter().cloned().collect();
+        runtime.record = None;
+        runtime.replay = Some(Arc::new(trace));
+        runtime
+    }
+
+    /// Read and run a script file until its root wire finishes.
+    pub fn run_file(&self, path: impl AsRef<Path>) -> Result<RunOutcome, Error> {
+        let path = path.as_ref();
+        self.run_source(&self.read_source(path)?, path)
+    }
+
+    /// Run `source` as if it was loaded from `path` (used to resolve includes).
+    pub fn run_source(&self, source: &str, path: &Path) -> Result<RunOutcome, Error> {
+        self.run(self.start_source(source, path)?)
+    }
+
+    /// Read a source pack (see [`crate::pack`]) and run its entry script until its root wire
+    /// finishes.
+    pub fn run_pack(&self, path: impl AsRef<Path>) -> Result<RunOutcome, Error> {
+        let pack = SourcePack::read(path)?;
+        self.run(self.start_pack(Arc::new(pack))?)
+    }
+
+    /// Run a script embedded with [`crate::include_shards!`] until its root wire finishes.
+    pub fn run_embedded(&self, script: &EmbeddedScript) -> Result<RunOutcome, Error> {
+        self.run(self.start_embedded(script)?)
+    }
+
+    /// Read the script at `path`, from the [`Runtime::vfs`] if set.
+    pub(crate) fn read_source(&self, path: &Path) -> Result<String, Error> {
+        Ok(match &self.vfs {
+            Some(vfs) => vfs.read_to_string(path)?,
+            None => std::fs::read_to_string(path)?,
+        })
+    }
+
+    /// Run a program serialized by `shards build` or [`crate::binary::build`] until its root
+    /// wire finishes, `path` names it in errors.
+    pub(crate) fn run_serialized(&self, program: &[u8], path: &Path) -> Result<RunOutcome, Error> {
+        self.run(self.start_serialized(program, "", path)?)
+    }
+
+    /// Tick `execution` until its root wire finishes.
+    pub(crate) fn run(&self, mut execution: Execution) -> Result<RunOutcome, Error> {
+        let start = Instant::now();
+        while execution.tick() {
+            shards::core::sleep(self.tick_interval.as_secs_f64());
+        }
+        let run_time = start.elapsed();
+
+        if let (Some(path), Some(trace)) = (&self.record, execution.trace()) {
+            trace.write(path)?;
+        }
+
+        if let Some(failure) = execution.failure() {
+            if let Some(denied) = execution.guards.as_ref().and_then(Guards::denied) {
+                return Err(denied);
+            }
+            if let Some((quota, message)) = execution.limiter.as_ref().and_then(Limiter::exceeded) {
+                return Err(Error::QuotaExceeded {
+                    wire: failure.wire.clone(),
+                    quota,
+                    message,
+                });
+            }
+            return Err(Error::WireFailed(failure.clone()));
+        }
+
+        let info = execution.info();
+        Ok(RunOutcome {
+            output: info.final_output.unwrap_or_default(),
+            ticks: execution.ticks(),
+            parse_time: execution.parse_time,
+            eval_time: execution.eval_time,
+            run_time,
+            profile: execution.profile(),
+        })
+    }
+
+    /// Load a script file and schedule it, without ticking.
+    pub fn start_file(&self, path: impl AsRef<Path>) -> Result<Execution, Error> {
+        let path = path.as_ref();
+        self.start_source(&self.read_source(path)?, path)
+    }
+
+    /// Parse, evaluate and schedule `source`, the caller drives it with [`Execution::tick`].
+    pub fn start_source(&self, source: &str, path: &Path) -> Result<Execution, Error> {
+        let (wire, parse_time, eval_time, includes) = self.compile(source, path, "root")?;
+        self.schedule(wire, source, path, includes, parse_time, eval_time)
+    }
+
+    /// Parse, evaluate and schedule the entry script of `pack`. Its includes and the files of
+    /// the file shards are served from the [`Runtime::vfs`] if set, which also receives the
+    /// writes, then from the pack.
+    pub fn start_pack(&self, pack: Arc<SourcePack>) -> Result<Execution, Error> {
+        let entry = PathBuf::from(pack.entry());
+        let mut layers = OverlayVfs::new();
+        if let Some(vfs) = &self.vfs {
+            layers = layers.shared_layer(vfs.clone());
+        }
+        let layers = layers.shared_layer(pack);
+
+        let mut runtime = self.clone();
+        runtime.vfs = Some(Arc::new(layers));
+        runtime.start_file(entry)
+    }
+
+    /// Evaluate and schedule a script embedded with [`crate::include_shards!`].
+    ///
+    /// The parse time is the time to deserialize the program.
+    pub fn start_embedded(&self, script: &EmbeddedScript) -> Result<Execution, Error> {
+        self.start_serialized(script.program, script.source, Path::new(script.path))
+    }
+
+    /// Evaluate and schedule a serialized program, the parse time is the time to deserialize it.
+    fn start_serialized(
+        &self,
+        program: &[u8],
+        source: &str,
+        path: &Path,
+    ) -> Result<Execution, Error> {
+        crate::init();
+
+        let start = Instant::now();
+        let program: Program = flexbuffers::from_slice(program).map_err(|e| {
+            Error::Binary(format!(
+                "{}: cannot deserialize the script: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let parse_time = start.elapsed();
+
+        let (wire, eval_time) = self.evaluate(&program, "root")?;
+        self.schedule(wire, source, path, Vec::new(), parse_time, eval_time)
+    }
+
+    fn schedule(
+        &self,
+        wire: Wire,
+        source: &str,
+        path: &Path,
+        includes: Vec<(PathBuf, String)>,
+        parse_time: Duration,
+        eval_time: Duration,
+    ) -> Result<Execution, Error> {
+        if self.seed.is_some() {
+            check_seedable(&wire)?;
+        }
+
+        let mesh = Mesh::default();
+        mesh.schedule(wire.0, true);
+
+        // Replacing, taping, guarding, limiting and instrumenting need the composed wire.
+        // Replaced shards are installed first so the others still apply to them
+        let determinism = (self.seed.is_some() || self.virtual_time).then(|| {
+            Determinism::apply(
+                &wire,
+                self.seed,
+                self.virtual_time.then_some(self.tick_interval),
+            )
+        });
+        #[cfg(feature = "metrics")]
+        let channels = ChannelDepths::attach(&wire);
+        #[cfg(feature = "metrics")]
+        let var_memory = VarMemory::attach(&wire);
+        let mount = self.vfs.as_ref().map(|vfs| Mount::apply(&wire, vfs));
+        let tape = match (&self.replay, &self.record) {
+            (Some(trace), _) => Some(Tape::replay(&wire, trace)),
+            (None, Some(_)) => Some(Tape::record(&wire, source, path, includes, &self.defines)),
+            (None, None) => None,
+        };
+        let guards = self.sandbox.as_ref().map(|sandbox| sandbox.guard(&wire));
+        let limiter = self.quotas.as_ref().map(|quotas| quotas.enforce(&wire));
+        let instrumentation = (self.profile || !self.hooks.is_empty())
+            .then(|| Instrumentation::attach(&wire, self.profile));
+
+        let name = WireInfo::of(&wire).name;
+        self.hooks.emit(&WireEvent::Started { wire: &name });
+
+        Ok(Execution {
+            instrumentation,
+            limiter,
+            guards,
+            tape,
+            mount,
+            #[cfg(feature = "metrics")]
+            channels,
+            #[cfg(feature = "metrics")]
+            var_memory,
+            determinism,
+            #[cfg(feature = "metrics")]
+            metrics: WireMetrics::started(&name, &wire),
+            hooks: self.hooks.clone(),
+            mesh,
+            wire,
+            ticks: 0,
+            finished: false,
+            failure: None,
+            restarts: 0,
+            parse_time,
+            eval_time,
+        })
+    }
+
+    /// Parse and evaluate `source` into a root wire called `wire`, with the parse and eval times
+    /// and the files it included if they were read through the include hook.
+    pub(crate) fn compile(
+        &self,
+        source: &str,
+        path: &Path,
+        wire: &str,
+    ) -> Result<(Wire, Duration, Duration, Vec<(PathBuf, String)>), Error> {
+        crate::init();
+
+        let (program, parse_time, includes) = self.parse_with_includes(source, path)?;
+        let (wire, eval_time) = self.evaluate(&program, wire)?;
+        Ok((wire, parse_time, eval_time, includes))
+    }
+
+    /// Parse `source` as if it was loaded from `path`, with the parse time.
+    pub(crate) fn parse(&self, source: &str, path: &Path) -> Result<(Program, Duration), Error> {
+        self.parse_with_includes(source, path)
+            .map(|(program, parse_time, _)| (program, parse_time))
+    }
+
+    /// Parse `source` as if it was loaded from `path`, with the parse time and the files it
+    /// included. Includes go through the include hook of the reader when they are read from the
+    /// [`Runtime::vfs`] (the reader would look for them on disk), and when recording or
+    /// replaying, a trace holds them.
+    fn parse_with_includes(
+        &self,
+        source: &str,
+        path: &Path,
+    ) -> Result<(Program, Duration, Vec<(PathBuf, String)>), Error> {
+        let replayed = self.replay.as_ref().map(|trace| &trace.includes);
+        if self.vfs.is_none() && self.record.is_none() && replayed.is_none() {
+            let (program, parse_time) = parse_source(source, path, None)?;
+            return Ok((program, parse_time, Vec::new()));
+        }
+
+        let included: RefCell<Vec<(PathBuf, String)>> = RefCell::new(Vec::new());
+        // The reader only gets a message, the error is reported as is
+        let failure = RefCell::new(None);
+        let read = |include: &Path| {
+            let include = normalize(include);
+            let source = match replayed {
+                Some(includes) => includes
+                    .iter()
+                    .find(|(path, _)| *path == include)
+                    .map(|(_, source)| source.clone())
+                    .ok_or_else(|| {
+                        Error::Trace(format!("{} is not in the trace", include.display()))
+                    }),
+                None => self.read_source(&include),
+            };
+            match source {
+                Ok(source) => {
+                    let mut included = included.borrow_mut();
+                    if !included.iter().any(|(path, _)| *path == include) {
+                        included.push((include, source.clone()));
+                    }
+                    Ok(source)
+                }
+                Err(e) => {
+                    let message = e.to_string();
+                    failure.borrow_mut().get_or_insert(e);
+                    Err(message)
+                }
+            }
+        };
+
+        let parsed = parse_source(source, path, Some(&read));
+        if let Some(e) = failure.into_inner() {
+            return Err(e);
+        }
+        let (program, parse_time) = parsed?;
+        Ok((program, parse_time, included.into_inner()))
+    }
+
+    /// Evaluate a parsed `program` into a root wire called `wire`, with the eval time.
+    pub(crate) fn evaluate(
+        &self,
+        program: &Program,
+        wire: &str,
+    ) -> Result<(Wire, Duration), Error> {
+        let start = Instant::now();
+        let cancellation = Arc::new(AtomicBool::new(false));
+        let wire =
+            shards_lang::eval::eval(&program.sequence, wire, self.defines.clone(), cancellation)
+                .map_err(|e| Error::Eval {
+                    message: e.message,
+                    line: e.loc.line,
+                    column: e.loc.column,
+                })?;
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.check(&wire)?;
+        }
+        Ok((wire, start.elapsed()))
+    }
+}
+
+/// Parse `source` as if it was loaded from `path`, with the parse time. Includes are read with
+/// `read` if given, from disk otherwise.
+fn parse_source(
+    source: &str,
+    path: &Path,
+    read: Option<&dyn Fn(&Path) -> Result<String, String>>,
+) -> Result<(Program, Duration), Error> {
+    let name = path.to_string_lossy();
+    let dir = path
+        .parent()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut env = ReadEnv::new(&name, &dir, &dir);
+    if let Some(read) = read {
+        env = env.with_include_hook(move |include: &str| read(Path::new(include)));
+    }
+
+    let start = Instant::now();
+    let program = read_with_env(source, env).map_err(|e| Error::Parse {
+        message: e.message,
+        line: e.loc.line,
+        column: e.loc.column,
+    })?;
+    Ok((program, start.elapsed()))
+}
+
+/// A script scheduled on its own mesh, ticked by the caller.
+pub struct Execution {
+    // Declared first, restores the shards while they are still alive
+    instrumentation: Option<Instrumentation>,
+    limiter: Option<Limiter>,
+    guards: Option<Guards>,
+    tape: Option<Tape>,
+    mount: Option<Mount>,
+    #[cfg(feature = "metrics")]
+    channels: ChannelDepths,
+    #[cfg(feature = "metrics")]
+    var_memory: VarMemory,
+    determinism: Option<Determinism>,
+    #[cfg(feature = "metrics")]
+    metrics: WireMetrics,
+    hooks: Hooks,
+    // Declared before the wire so the mesh is dropped first
+    mesh: Mesh,
+    wire: Wire,
+    ticks: u64,
+    finished: bool,
+    failure: Option<WireFailure>,
+    restarts: u32,
+    parse_time: Duration,
+    eval_time: Duration,
+}
+
+impl Execution {
+    /// Tick the mesh once, returns `false` once the root wire finished, failed or exceeded a
+    /// quota.
+    pub fn tick(&mut self) -> bool {
+        if self.finished {
+            return false;
+        }
+
+        if let Some(limiter) = &self.limiter {
+            limiter.begin_tick();
+            if limiter.exceeded().is_some() {
+                self.finish();
+                return false;
+            }
+        }
+
+        let start = Instant::now();
+        let ok = {
+            #[cfg(feature = "tracy")]
+            let _zone = tracy_client::span!("mesh tick");
+            self.mesh.tick()
+        };
+        let duration = start.elapsed();
+
+        if let Some(instrumentation) = &self.instrumentation {
+            instrumentation.record_tick(start, duration);
+        }
+        #[cfg(feature = "tracy")]
+        if let Some(client) = tracy_client::Client::running() {
+            client.frame_mark();
+        }
+        #[cfg(feature = "metrics")]
+        self.metrics.tick(duration);
+
+        self.ticks += 1;
+        if let Some(determinism) = &self.determinism {
+            determinism.advance();
+        }
+        let exceeded = self
+            .limiter
+            .as_ref()
+            .is_some_and(|l| l.exceeded().is_some());
+        if !ok || self.mesh.is_empty() || exceeded {
+            self.finish();
+        }
+        !self.finished
+    }
+
+    fn finish(&mut self) {
+        self.finished = true;
+
+        let info = self.info();
+        let exceeded = self.limiter.as_ref().and_then(Limiter::exceeded);
+        let failed = info.failed || exceeded.is_some();
+        #[cfg(feature = "metrics")]
+        self.metrics.finished(failed);
+
+        if !failed {
+            self.hooks.emit(&WireEvent::Stopped { wire: &info.name });
+            return;
+        }
+
+        let mut failure = failure_report(info, self.instrumentation.as_ref());
+        if let Some((quota, message)) = exceeded {
+            // The wire might still be running, the execution stops ticking it
+            failure.message = format!("{} quota exceeded: {}", quota, message);
+        }
+        self.hooks.emit(&WireEvent::Failed(&failure));
+        self.failure = Some(failure);
+    }
+
+    /// Schedule the root wire again once it finished or failed, does nothing while it runs.
+    pub fn restart(&mut self) {
+        if !self.finished {
+            return;
+        }
+
+        // Already composed, composing again would also undo the instrumentation
+        self.mesh.schedule(self.wire.0, false);
+        self.finished = false;
+        self.failure = None;
+        self.restarts += 1;
+        if let Some(instrumentation) = &self.instrumentation {
+            instrumentation.take_failure();
+        }
+        if let Some(limiter) = &mut self.limiter {
+            limiter.reset();
+        }
+        if let Some(determinism) = &self.determinism {
+            determinism.reset();
+        }
+
+        let name = self.info().name;
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics = WireMetrics::started(&name, &self.wire);
+        }
+        self.hooks.emit(&WireEvent::Restarted {
+            wire: &name,
+            restarts: self.restarts,
+        });
+    }
+
+    /// The inputs recorded so far, if the runtime records, see [`Runtime::record`].
+    pub fn trace(&self) -> Option<Trace> {
+        self.tape.as_ref().and_then(Tape::trace)
+    }
+
+    /// Failure report of the root wire, if it stopped with a failure.
+    pub fn failure(&self) -> Option<&WireFailure> {
+        self.failure.as_ref()
+    }
+
+    /// Number of [`Execution::restart`] calls that rescheduled the wire.
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub(crate) fn wire(&self) -> &Wire {
+        &self.wire
+    }
+
+    /// Number of ticks so far.
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Time it took to parse the script, or to deserialize the program.
+    pub fn parse_time(&self) -> Duration {
+        self.parse_time
+    }
+
+    /// Time it took to evaluate the script into its root wire.
+    pub fn eval_time(&self) -> Duration {
+        self.eval_time
+    }
+
+    /// Timings collected so far, if profiling is enabled.
+    pub fn profile(&self) -> Option<Profile> {
+        self.instrumentation
+            .as_ref()
+            .and_then(Instrumentation::profile)
+    }
+
+    /// Current state of the root wire.
+    pub fn info(&self) -> WireInfo {
+        WireInfo::of(&self.wire)
+    }
+}
+
+/// Failure report of a wire that failed, `info` being its final state.
+pub(crate) fn failure_report(
+    info: WireInfo,
+    instrumentation: Option<&Instrumentation>,
+) -> WireFailure {
+    let shard = instrumentation.and_then(Instrumentation::take_failure);
+    WireFailure {
+        wire: info.name,
+        shard: shard.as_ref().map(|s| s.site.name.clone()),
+        location: shard.as_ref().map(|s| SourceLocation {
+            line: s.site.line,
+            column: s.site.column,
+        }),
+        message: info.failure_message,
+        input: shard.map(|s| s.input),
+    }
+}
+
+/// Snapshot of a wire's state as reported by the core.
+#[derive(Debug, Clone)]
+pub struct WireInfo {
+    pub name: String,
+    pub running: bool,
+    pub failed: bool,
+    pub failure_message: String,
+    /// Output of the last activation, printed.
+    pub final_output: Option<String>,
+}
+
+impl WireInfo {
+    pub(crate) fn of(wire: &Wire) -> Self {
+        Self::of_ref(wire.0 .0)
+    }
+
+    pub(crate) fn of_ref(wire: SHWireRef) -> Self {
+        unsafe {
+            let info = (*shards::core::Core).getWireInfo.unwrap()(wire);
+            let final_output = if info.finalOutput.is_null() {
+                None
+            } else {
+                Some(format!("{:?}", *info.finalOutput))
+            };
+            WireInfo {
+                name: string_with_len(info.name),
+                running: info.isRunning,
+                failed: info.failed,
+                failure_message: string_with_len(info.failureMessage),
+                final_output,
+            }
+        }
+    }
+}
+
+pub(crate) unsafe fn string_with_len(s: shards::shardsc::SHStringWithLen) -> String {
+    if s.string.is_null() {
+        return String::new();
+    }
+    let bytes = std::slice::from_raw_parts(s.string as *const u8, s.len as usize);
+    String::from_utf8_lossy(bytes).into_owned()
+}
@@ -0,0 +1,538 @@
+//! Capability policy for running untrusted scripts.
+//!
+//! A [`Sandbox`] set with [`crate::Runtime::sandbox`] is checked when a script is loaded: every
+//! shard of the evaluated wires, including the ones nested in parameters and in referenced
+//! wires, must be allowed by name or by module, otherwise loading fails with
+//! [`crate::Error::CapabilityDenied`].
+//!
+//! The module of a shard is the lowercased prefix of its name (`Http.Get` is in `http`, shards
+//! without prefix are in `core`), except for the names listed in [`MODULE_OVERRIDES`].
+//!
+//! With filesystem roots configured, the shards listed in [`FS_SHARDS`] are also checked when
+//! they run: a path input or path parameter outside the roots stops the wire with a failure.
+//! Path parameters set from variables are rejected when loading, since their value is only
+//! known at run time, and so are the other `fs` and `fileops` shards.
+//!
+//! Only the wires known when loading are checked. A sandbox restricting anything therefore
+//! rejects the shards in [`DYNAMIC_WIRE_SHARDS`], which load wires at run time, and the shards
+//! running a wire given by a variable, since the shards of those wires could not be checked.
+
+use std::ffi::CStr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use shards::shardsc::{SHContext, SHVar, Shard};
+use shards::types::Wire;
+
+use crate::instrument::{abort, param, shard_name, shards_of, Hook, Interposition, Next};
+use crate::Error;
+
+/// Modules of the shards whose module is not their name prefix, entries ending with `.` match
+/// name prefixes. The first matching entry wins.
+pub const MODULE_OVERRIDES: &[(&str, &str)] = &[
+    ("FS.FileDialog", "fs"),
+    ("FS.SaveFileDialog", "fs"),
+    ("FS.", "fileops"),
+    ("Process.", "localshell"),
+    ("Shell.", "localshell"),
+];
+
+/// Modules giving scripts access to the host, denied by [`Sandbox::untrusted`].
+pub const HOST_MODULES: &[&str] = &["fs", "fileops", "localshell", "http", "network", "os"];
+
+const FS_MODULES: &[&str] = &["fs", "fileops"];
+
+/// Shards accessing files allowed with filesystem roots, with whether their input is a path
+/// and their parameters holding paths. The other `fs` and `fileops` shards are denied with
+/// filesystem roots, the listed shards of other modules are checked like them.
+pub const FS_SHARDS: &[(&str, bool, &[&str])] = &[
+    ("FS.Read", true, &[]),
+    ("FS.Write", true, &[]),
+    ("FS.IsFile", true, &[]),
+    ("FS.IsDirectory", true, &[]),
+    ("FS.Iterate", true, &[]),
+    ("FS.LastWriteTime", true, &[]),
+    ("FS.Extension", true, &[]),
+    ("FS.Filename", true, &[]),
+    ("FS.Remove", true, &[]),
+    ("FS.Copy", true, &["Destination"]),
+    ("LoadImage", false, &["File"]),
+    ("WritePNG", false, &["File"]),
+    ("Audio.ReadFile", false, &["Source"]),
+    ("DB.Query", false, &["Database"]),
+    ("DB.RawQuery", false, &["Database"]),
+    ("DB.Transaction", false, &["Database"]),
+];
+
+/// Shards loading or building wires at run time, rejected by restricting sandboxes.
+pub const DYNAMIC_WIRE_SHARDS: &[&str] = &["WireLoader", "WireRunner"];
+
+/// Whether the input of the filesystem shard `name` is a path and its path parameters, `None`
+/// if it is not in [`FS_SHARDS`].
+fn path_params(name: &str) -> Option<(bool, &'static [&'static str])> {
+    FS_SHARDS
+        .iter()
+        .find(|(shard, _, _)| *shard == name)
+        .map(|(_, input, params)| (*input, *params))
+}
+
+/// Module of a shard, see the [module documentation](self).
+pub fn module_of(shard: &str) -> String {
+    for (pattern, module) in MODULE_OVERRIDES {
+        let matches = match pattern.strip_suffix('.') {
+            Some(_) => shard.starts_with(pattern),
+            None => shard == *pattern,
+        };
+        if matches {
+            return module.to_string();
+        }
+    }
+
+    match shard.split_once('.') {
+        Some((prefix, _)) => prefix.to_lowercase(),
+        None => "core".to_string(),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Rule {
+    Module(String),
+    Shard(String),
+}
+
+impl Rule {
+    fn matches(&self, shard: &str, module: &str) -> bool {
+        match self {
+            Rule::Module(m) => m == module,
+            Rule::Shard(s) => s == shard,
+        }
+    }
+}
+
+/// Which shards a script may use and which paths the filesystem shards may access.
+///
+/// Deny rules take precedence over allow rules.
+///
+/// ```rust,ignore
+/// let sandbox = shards_embed::Sandbox::untrusted()
+///     .allow_module("fileops")
+///     .fs_root("data/");
+/// let runtime = shards_embed::Runtime::new().sandbox(sandbox);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    allow_by_default: bool,
+    allowed: Vec<Rule>,
+    denied: Vec<Rule>,
+    fs_roots: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Allows every shard, restrict it with [`Sandbox::deny_module`] and [`Sandbox::deny_shard`].
+    pub fn allow_all() -> Self {
+        Self {
+            allow_by_default: true,
+            allowed: Vec::new(),
+            denied: Vec::new(),
+            fs_roots: Vec::new(),
+        }
+    }
+
+    /// Denies every shard not allowed with [`Sandbox::allow_module`] or [`Sandbox::allow_shard`].
+    pub fn deny_all() -> Self {
+        Self {
+            allow_by_default: false,
+            ..Self::allow_all()
+        }
+    }
+
+    /// Allows every shard except the ones of the [`HOST_MODULES`].
+    pub fn untrusted() -> Self {
+        HOST_MODULES
+            .iter()
+            .fold(Self::allow_all(), |sandbox, module| {
+                sandbox.deny_module(*module)
+            })
+    }
+
+    pub fn allow_module(mut self, module: impl Into<String>) -> Self {
+        let module = module.into();
+        self.denied
+            .retain(|rule| !matches!(rule, Rule::Module(m) if *m == module));
+        self.allowed.push(Rule::Module(module));
+        self
+    }
+
+    pub fn allow_shard(mut self, shard: impl Into<String>) -> Self {
+        let shard = shard.into();
+        self.denied
+            .retain(|rule| !matches!(rule, Rule::Shard(s) if *s == shard));
+        self.allowed.push(Rule::Shard(shard));
+        self
+    }
+
+    pub fn deny_module(mut self, module: impl Into<String>) -> Self {
+        self.denied.push(Rule::Module(module.into()));
+        self
+    }
+
+    pub fn deny_shard(mut self, shard: impl Into<String>) -> Self {
+        self.denied.push(Rule::Shard(shard.into()));
+        self
+    }
+
+    /// Only let the filesystem shards access paths under `root`, can be called several times.
+    ///
+    /// Relative paths are resolved against the current directory.
+    pub fn fs_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.fs_roots.push(root.into());
+        self
+    }
+
+    /// Whether the shard `name` may be used, regardless of the paths it accesses.
+    pub fn allows(&self, name: &str) -> bool {
+        self.denial(name).is_none()
+    }
+
+    /// Why the shard `name` may not be used.
+    fn denial(&self, name: &str) -> Option<String> {
+        let module = module_of(name);
+        for rule in &self.denied {
+            match rule {
+                Rule::Shard(s) if s == name => return Some("shard is denied".to_string()),
+                Rule::Module(m) if *m == module => {
+                    return Some(format!("module '{}' is denied", module))
+                }
+                _ => {}
+            }
+        }
+
+        if self.allow_by_default || self.allowed.iter().any(|rule| rule.matches(name, &module)) {
+            None
+        } else {
+            Some(format!(
+                "neither the shard nor its module '{}' is allowed",
+                module
+            ))
+        }
+    }
+
+    /// Check every shard of the evaluated `wire`.
+    pub(crate) fn check(&self, wire: &Wire) -> Result<(), Error> {
+        for shard in shards_of(wire) {
+            let site = unsafe { Site::of(shard) };
+            if let Some(reason) = self.denial(&site.name) {
+                return Err(site.denied(reason));
+            }
+
+            if self.restricts() {
+                if DYNAMIC_WIRE_SHARDS.contains(&site.name.as_str()) {
+                    return Err(site.denied(
+                        "it loads wires at run time, their shards cannot be checked".to_string(),
+                    ));
+                }
+                if let Some(name) = unsafe { variable_wire_param(shard) } {
+                    return Err(site.denied(format!(
+                        "parameter '{}' is set from a variable, the shards of its wire cannot be checked",
+                        name
+                    )));
+                }
+            }
+
+            if !self.guards_fs(&site.name) {
+                continue;
+            }
+            let Some((_, params)) = path_params(&site.name) else {
+                return Err(site.denied(
+                    "the paths it accesses cannot be checked, not allowed with restricted filesystem roots"
+                        .to_string(),
+                ));
+            };
+            for name in params {
+                let value = unsafe { param(shard, name) };
+                if value.is_some_and(|v| v.valueType == shards::shardsc::SHType_ContextVar) {
+                    return Err(site.denied(format!(
+                        "parameter '{}' is set from a variable, paths must be literal with restricted filesystem roots",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Guard the filesystem shards of `wire`, must happen after it was composed.
+    pub(crate) fn guard(&self, wire: &Wire) -> Guards {
+        let roots: Arc<[PathBuf]> = self.fs_roots.iter().map(|root| resolve(root)).collect();
+        let denied = Arc::new(Mutex::new(None));
+        let hooks = shards_of(wire).into_iter().filter_map(|shard| {
+            let site = unsafe { Site::of(shard) };
+            self.guards_fs(&site.name).then(|| {
+                let guard = Guard {
+                    site,
+                    roots: roots.clone(),
+                    denied: denied.clone(),
+                };
+                (shard, guard)
+            })
+        });
+
+        Guards {
+            _interposition: Interposition::install(hooks),
+            denied,
+        }
+    }
+
+    fn guards_fs(&self, shard: &str) -> bool {
+        !self.fs_roots.is_empty()
+            && (FS_MODULES.contains(&module_of(shard).as_str()) || path_params(shard).is_some())
+    }
+
+    /// Whether the sandbox denies anything, an unrestricted one checks nothing.
+    fn restricts(&self) -> bool {
+        !self.allow_by_default || !self.denied.is_empty() || !self.fs_roots.is_empty()
+    }
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Site {
+    name: String,
+    line: u32,
+    column: u32,
+}
+
+impl Site {
+    unsafe fn of(shard: *mut Shard) -> Self {
+        Site {
+            name: shard_name(shard),
+            line: (*shard).line,
+            column: (*shard).column,
+        }
+    }
+
+    fn denied(&self, reason: String) -> Error {
+        Error::CapabilityDenied {
+            shard: self.name.clone(),
+            line: self.line,
+            column: self.column,
+            reason,
+        }
+    }
+}
+
+struct Guard {
+    site: Site,
+    roots: Arc<[PathBuf]>,
+    denied: Arc<Mutex<Option<Error>>>,
+}
+
+/// Runtime checks installed by [`Sandbox::guard`], removed when dropped.
+pub(crate) struct Guards {
+    _interposition: Interposition,
+    denied: Arc<Mutex<Option<Error>>>,
+}
+
+impl Guards {
+    /// The first access the guards stopped, as a [`Error::CapabilityDenied`].
+    pub(crate) fn denied(&self) -> Option<Error> {
+        self.denied.lock().unwrap().take()
+    }
+}
+
+impl Guard {
+    /// Stop the wire of `context` with `error`, remembered if it is the first.
+    unsafe fn deny(&self, context: *mut SHContext, error: Error) -> SHVar {
+        let message = error.to_string();
+        self.denied.lock().unwrap().get_or_insert(error);
+        abort(context, &message);
+        SHVar::default()
+    }
+}
+
+impl Hook for Guard {
+    unsafe fn activate(
+        &self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        input: *const SHVar,
+        next: Next,
+    ) -> SHVar {
+        // Denied when loading, unless the shard was reached without being checked
+        let Some((path_input, params)) = path_params(&self.site.name) else {
+            return self.deny(
+                context,
+                self.site.denied(
+                    "the paths it accesses cannot be checked, not allowed with restricted filesystem roots"
+                        .to_string(),
+                ),
+            );
+        };
+        let mut paths: Vec<String> = if path_input {
+            var_string(&*input).into_iter().collect()
+        } else {
+            Vec::new()
+        };
+        paths.extend(
+            params
+                .iter()
+                .filter_map(|name| param(shard, name))
+                .filter_map(|value| var_string(&value)),
+        );
+
+        if let Some(path) = paths
+            .iter()
+            .find(|path| !is_within(Path::new(path), &self.roots))
+        {
+            return self.deny(
+                context,
+                self.site.denied(format!(
+                    "'{}' is outside the allowed filesystem roots",
+                    path
+                )),
+            );
+        }
+
+        next.activate(shard, context, input)
+    }
+}
+
+/// Name of the first parameter of `shard` taking a wire that is set from a variable.
+unsafe fn variable_wire_param(shard: *mut Shard) -> Option<String> {
+    let (Some(parameters), Some(get_param)) = ((*shard).parameters, (*shard).getParam) else {
+        return None;
+    };
+
+    let params = parameters(shard);
+    (0..params.len).find_map(|i| {
+        let info = &*params.elements.add(i as usize);
+        let types = info.valueTypes;
+        let takes_wire = (0..types.len)
+            .any(|t| (*types.elements.add(t as usize)).basicType == shards::shardsc::SHType_Wire);
+        let from_variable =
+            get_param(shard, i as i32).valueType == shards::shardsc::SHType_ContextVar;
+        (takes_wire && from_variable)
+            .then(|| CStr::from_ptr(info.name).to_string_lossy().into_owned())
+    })
+}
+
+pub(crate) unsafe fn var_string(var: &SHVar) -> Option<String> {
+    if var.valueType != shards::shardsc::SHType_String
+        && var.valueType != shards::shardsc::SHType_Path
+    {
+        return None;
+    }
+
+    let s = &var.payload.__bindgen_anon_1.__bindgen_anon_1;
+    if s.stringValue.is_null() {
+        return None;
+    }
+    let bytes = std::slice::from_raw_parts(s.stringValue as *const u8, s.stringLen as usize);
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn is_within(path: &Path, roots: &[PathBuf]) -> bool {
+    let path = resolve(path);
+    roots.iter().any(|root| path.starts_with(root))
+}
+
+/// Absolute form of `path` without `.` and `..`, following the symlinks of its existing part.
+fn resolve(path: &Path) -> PathBuf {
+    let absolute = std::env::current_dir()
+        .map(|cwd| cwd.join(path))
+        .unwrap_or_else(|_| path.to_path_buf());
+
+    // Existing components are canonicalized as they come, so that a `..` after a symlink
+    // leaves its target. The rest might not exist yet and cannot hold symlinks
+    let mut resolved = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            c => {
+                resolved.push(c);
+                if let Ok(canonical) = resolved.canonicalize() {
+                    resolved = canonical;
+                }
+            }
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "shards-embed-sandbox-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn guards_listed_file_shards_of_other_modules() {
+        let sandbox = Sandbox::untrusted().fs_root("data");
+        assert!(sandbox.guards_fs("FS.Read"));
+        assert!(sandbox.guards_fs("FS.Rename"));
+        assert!(sandbox.guards_fs("LoadImage"));
+        assert!(!sandbox.guards_fs("Math.Add"));
+        assert!(!Sandbox::untrusted().guards_fs("LoadImage"));
+        assert_eq!(path_params("LoadImage"), Some((false, &["File"][..])));
+    }
+
+    #[test]
+    fn only_restricting_sandboxes_check_dynamic_wires() {
+        assert!(!Sandbox::allow_all().restricts());
+        assert!(Sandbox::allow_all().deny_shard("Log").restricts());
+        assert!(Sandbox::allow_all().fs_root("data").restricts());
+        assert!(Sandbox::deny_all().restricts());
+        assert!(Sandbox::untrusted().restricts());
+    }
+
+    #[test]
+    fn resolves_missing_paths_lexically() {
+        let dir = scratch_dir("missing");
+        assert_eq!(resolve(&dir.join("a/./b/../c")), dir.join("a/c"));
+        assert_eq!(resolve(&dir.join("a/../..")), dir.parent().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolves_relative_paths_from_the_working_directory() {
+        let cwd = std::env::current_dir().unwrap().canonicalize().unwrap();
+        assert_eq!(resolve(Path::new("missing/file")), cwd.join("missing/file"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_links_before_parent_components() {
+        let dir = scratch_dir("links");
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+        std::fs::create_dir_all(dir.join("outside/nested")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside/nested"), dir.join("root/link")).unwrap();
+
+        // Lexically this would be root/secret, but the parent of the link target is outside
+        assert_eq!(
+            resolve(&dir.join("root/link/../secret")),
+            dir.join("outside/secret")
+        );
+        assert_eq!(
+            resolve(&dir.join("root/link/file")),
+            dir.join("outside/nested/file")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
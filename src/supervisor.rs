@@ -0,0 +1,366 @@
+//! Erlang-style supervision of independent wires sharing one mesh.
+//!
+//! Every wire is registered with a [`RestartPolicy`]. When a wire finishes, the supervisor
+//! schedules it again as its policy says, backing off exponentially between restarts and giving
+//! up once the wire restarted too often within the policy window. Restarts are reported to the
+//! [`crate::Runtime::on_wire_event`] callbacks and by [`Supervisor::status`].
+//!
+//! ```rust,ignore
+//! let mut supervisor = shards_embed::Supervisor::new(shards_embed::Runtime::new());
+//! supervisor.supervise_file("ingest", "ingest.shs", RestartPolicy::on_failure())?;
+//! supervisor.supervise_file("report", "report.shs", RestartPolicy::always())?;
+//! supervisor.run();
+//! ```
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use shards::types::{Mesh, Wire};
+
+use crate::hooks::Hooks;
+use crate::instrument::Instrumentation;
+#[cfg(feature = "metrics")]
+use crate::metrics::{ChannelDepths, WireMetrics};
+use crate::runtime::failure_report;
+use crate::sandbox::Guards;
+use crate::{Error, Runtime, WireEvent, WireFailure, WireInfo};
+
+/// When a supervised wire is restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    Never,
+    /// After it finished, with or without failure.
+    Always,
+    /// Only after it failed.
+    OnFailure,
+}
+
+/// Restart policy of a supervised wire.
+///
+/// The first restart waits `initial_backoff`, the delay doubles with each further restart
+/// within `window`, up to `max_backoff`. A wire that would restart more than `max_restarts`
+/// times within `window` is given up instead.
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    pub restart: Restart,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_restarts: u32,
+    pub window: Duration,
+}
+
+impl RestartPolicy {
+    pub fn never() -> Self {
+        Self::new(Restart::Never)
+    }
+
+    pub fn always() -> Self {
+        Self::new(Restart::Always)
+    }
+
+    pub fn on_failure() -> Self {
+        Self::new(Restart::OnFailure)
+    }
+
+    fn new(restart: Restart) -> Self {
+        Self {
+            restart,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: 5,
+            window: Duration::from_secs(60),
+        }
+    }
+
+    /// Delay before the first restart and cap of the exponential backoff.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Restart at most `restarts` times within `window`.
+    pub fn max_restarts(mut self, restarts: u32, window: Duration) -> Self {
+        self.max_restarts = restarts;
+        self.window = window;
+        self
+    }
+
+    fn delay(&self, recent_restarts: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(recent_restarts))
+            .min(self.max_backoff)
+    }
+}
+
+/// Handle of a wire registered on a [`Supervisor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WireId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireState {
+    Running,
+    /// Waiting for its backoff to elapse before being restarted.
+    Restarting {
+        at: Instant,
+    },
+    /// Finished without failure and not restarted by its policy.
+    Stopped,
+    /// Failed and not restarted by its policy.
+    Failed,
+    /// Restarted too often within the policy window, not restarted anymore.
+    GaveUp,
+}
+
+/// State of a supervised wire, see [`Supervisor::status`].
+#[derive(Debug, Clone)]
+pub struct WireStatus {
+    pub name: String,
+    pub state: WireState,
+    /// Restarts since the wire was registered.
+    pub restarts: u32,
+    pub last_failure: Option<WireFailure>,
+}
+
+struct Child {
+    instrumentation: Option<Instrumentation>,
+    guards: Option<Guards>,
+    #[cfg(feature = "metrics")]
+    channels: ChannelDepths,
+    #[cfg(feature = "metrics")]
+    metrics: WireMetrics,
+    wire: Wire,
+    name: String,
+    policy: RestartPolicy,
+    state: WireState,
+    restarts: u32,
+    // Restart times within the policy window, oldest first
+    recent: VecDeque<Instant>,
+    last_failure: Option<WireFailure>,
+}
+
+impl Child {
+    fn finished(&mut self, info: WireInfo, hooks: &Hooks) {
+        let failed = info.failed;
+        #[cfg(feature = "metrics")]
+        self.metrics.finished(failed);
+
+        if failed {
+            let failure = failure_report(info, self.instrumentation.as_ref());
+            hooks.emit(&WireEvent::Failed(&failure));
+            self.last_failure = Some(failure);
+        } else {
+            hooks.emit(&WireEvent::Stopped { wire: &self.name });
+        }
+
+        let restart = match self.policy.restart {
+            Restart::Never => false,
+            Restart::Always => true,
+            Restart::OnFailure => failed,
+        };
+        if !restart {
+            self.state = if failed {
+                WireState::Failed
+            } else {
+                WireState::Stopped
+            };
+            return;
+        }
+
+        let now = Instant::now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > self.policy.window)
+        {
+            self.recent.pop_front();
+        }
+
+        let recent = self.recent.len() as u32;
+        if recent >= self.policy.max_restarts {
+            self.state = WireState::GaveUp;
+            hooks.emit(&WireEvent::GaveUp {
+                wire: &self.name,
+                restarts: self.restarts,
+            });
+            return;
+        }
+
+        self.state = WireState::Restarting {
+            at: now + self.policy.delay(recent),
+        };
+    }
+
+    fn restart(&mut self, mesh: &Mesh, hooks: &Hooks) {
+        // Already composed, composing again would also undo the instrumentation
+        mesh.schedule(self.wire.0, false);
+        if let Some(instrumentation) = &self.instrumentation {
+            instrumentation.take_failure();
+        }
+
+        self.state = WireState::Running;
+        self.restarts += 1;
+        self.recent.push_back(Instant::now());
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics = WireMetrics::started(&self.name, &self.wire);
+        }
+        hooks.emit(&WireEvent::Restarted {
+            wire: &self.name,
+            restarts: self.restarts,
+        });
+    }
+}
+
+/// Runs several wires on one mesh and restarts them according to their [`RestartPolicy`].
+///
+/// Uses the defines, tick interval, sandbox and event callbacks of its [`Runtime`], profiling
+/// is not supported.
+pub struct Supervisor {
+    runtime: Runtime,
+    // Declared before the children so the mesh is dropped before their wires
+    mesh: Mesh,
+    children: Vec<Child>,
+}
+
+impl Supervisor {
+    pub fn new(runtime: Runtime) -> Self {
+        Self {
+            runtime,
+            mesh: Mesh::default(),
+            children: Vec::new(),
+        }
+    }
+
+    /// Load a script file as the wire `name` and schedule it under `policy`, from the
+    /// [`Runtime::vfs`] if set.
+    pub fn supervise_file(
+        &mut self,
+        name: &str,
+        path: impl AsRef<Path>,
+        policy: RestartPolicy,
+    ) -> Result<WireId, Error> {
+        let path = path.as_ref();
+        let source = self.runtime.read_source(path)?;
+        self.supervise_source(name, &source, path, policy)
+    }
+
+    /// Schedule `source` as the wire `name` under `policy`, `path` is used to resolve includes.
+    pub fn supervise_source(
+        &mut self,
+        name: &str,
+        source: &str,
+        path: &Path,
+        policy: RestartPolicy,
+    ) -> Result<WireId, Error> {
+        let (wire, ..) = self.runtime.compile(source, path, name)?;
+        self.mesh.schedule(wire.0, true);
+
+        #[cfg(feature = "metrics")]
+        let channels = ChannelDepths::attach(&wire);
+        let guards = self
+            .runtime
+            .sandbox
+            .as_ref()
+            .map(|sandbox| sandbox.guard(&wire));
+        let instrumentation =
+            (!self.runtime.hooks.is_empty()).then(|| Instrumentation::attach(&wire, false));
+        self.runtime.hooks.emit(&WireEvent::Started { wire: name });
+
+        self.children.push(Child {
+            instrumentation,
+            guards,
+            #[cfg(feature = "metrics")]
+            channels,
+            #[cfg(feature = "metrics")]
+            metrics: WireMetrics::started(name, &wire),
+            wire,
+            name: name.to_string(),
+            policy,
+            state: WireState::Running,
+            restarts: 0,
+            recent: VecDeque::new(),
+            last_failure: None,
+        });
+        Ok(WireId(self.children.len() - 1))
+    }
+
+    /// Restart the wires whose backoff elapsed and tick the mesh once.
+    ///
+    /// Returns `false` once no wire is running or waiting to be restarted.
+    pub fn tick(&mut self) -> bool {
+        let now = Instant::now();
+        for child in &mut self.children {
+            if matches!(child.state, WireState::Restarting { at } if at <= now) {
+                child.restart(&self.mesh, &self.runtime.hooks);
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        if !self.mesh.is_empty() {
+            #[cfg(feature = "tracy")]
+            let _zone = tracy_client::span!("supervisor tick");
+            self.mesh.tick();
+        }
+        #[cfg(feature = "metrics")]
+        let duration = start.elapsed();
+        #[cfg(feature = "tracy")]
+        if let Some(client) = tracy_client::Client::running() {
+            client.frame_mark();
+        }
+
+        for child in &mut self.children {
+            if child.state != WireState::Running {
+                continue;
+            }
+            #[cfg(feature = "metrics")]
+            child.metrics.tick(duration);
+
+            let info = WireInfo::of(&child.wire);
+            if !info.running {
+                child.finished(info, &self.runtime.hooks);
+            }
+        }
+
+        self.children.iter().any(|child| {
+            matches!(
+                child.state,
+                WireState::Running | WireState::Restarting { .. }
+            )
+        })
+    }
+
+    /// Tick until no wire is running or waiting to be restarted.
+    pub fn run(&mut self) {
+        while self.tick() {
+            shards::core::sleep(self.runtime.tick_interval.as_secs_f64());
+        }
+    }
+
+    /// The wires registered so far.
+    pub fn wires(&self) -> impl Iterator<Item = WireId> {
+        (0..self.children.len()).map(WireId)
+    }
+
+    pub fn status(&self, id: WireId) -> WireStatus {
+        let child = &self.children[id.0];
+        WireStatus {
+            name: child.name.clone(),
+            state: child.state,
+            restarts: child.restarts,
+            last_failure: child.last_failure.clone(),
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        // Restore the shards while the mesh still holds the wires
+        for child in &mut self.children {
+            child.instrumentation = None;
+            child.guards = None;
+        }
+    }
+}
@@ -0,0 +1,479 @@
+//! Virtual filesystems for script includes and the file shards.
+//!
+//! A runtime built with [`crate::Runtime::vfs`] reads the scripts it loads and the files they
+//! `@include` from its [`Vfs`] instead of the disk, and serves the shards listed in
+//! [`VFS_SHARDS`] from it, so tests can run against in-memory fixtures and apps can ship their
+//! assets packed. The other file shards (e.g. `FS.Iterate`, `FS.Copy`, `FS.Remove`) are not
+//! served, they fail when activated instead of reaching the disk.
+//!
+//! Paths are relative, `/` separated and normalized (`.` and `..` resolved) before reaching
+//! the filesystem, paths escaping its root are not found. [`DirVfs`] also rejects paths that
+//! leave its root through a symbolic link.
+//!
+//! ```rust,ignore
+//! let fixtures = MemoryVfs::new()
+//!     .with_file("main.shs", r#"@include("lib.shs") "data.json" | FS.Read | Log"#)
+//!     .with_file("lib.shs", "...")
+//!     .with_file("data.json", "{}");
+//! shards_embed::Runtime::new().vfs(fixtures).run_file("main.shs")?;
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use shards::shardsc::{SHContext, SHVar, Shard};
+use shards::types::{Var, Wire};
+
+use crate::includes::normalize;
+use crate::instrument::{abort, resolved_param, shard_name, shards_of, Hook, Interposition, Next};
+use crate::pack::SourcePack;
+use crate::sandbox::{module_of, var_string};
+
+/// Shards served by the [`Vfs`] of a runtime.
+pub const VFS_SHARDS: &[&str] = &["FS.Read", "FS.Write", "FS.IsFile", "FS.IsDirectory"];
+
+// Module of the file shards, the ones not in `VFS_SHARDS` fail under a Vfs
+const FILE_MODULE: &str = "fileops";
+
+/// A filesystem the runtime reads scripts and files from, see the
+/// [module documentation](self).
+pub trait Vfs: fmt::Debug + Send + Sync {
+    /// Content of the file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Replace the content of the file at `path`, or append to it. Read-only by default.
+    fn write(&self, path: &Path, contents: &[u8], append: bool) -> io::Result<()> {
+        let _ = (contents, append);
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is on a read-only filesystem", path.display()),
+        ))
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not valid UTF-8"))
+    }
+}
+
+/// `path` normalized, `/` separated, `None` if it escapes the root.
+fn key(path: &Path) -> Option<String> {
+    normalize(path)
+        .components()
+        .map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(|parts| parts.join("/"))
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+/// Whether a file of `files` is under the directory `dir` (the root if empty).
+fn contains_dir<'a>(mut files: impl Iterator<Item = &'a str>, dir: &str) -> bool {
+    files.any(|file| dir.is_empty() || file.strip_prefix(dir).is_some_and(|r| r.starts_with('/')))
+}
+
+/// Files held in memory, writable.
+#[derive(Debug, Default)]
+pub struct MemoryVfs {
+    files: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryVfs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_file(self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) -> Self {
+        self.insert(path, contents);
+        self
+    }
+
+    /// Add or replace the file at `path`, ignored if `path` escapes the root.
+    pub fn insert(&self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        if let Some(key) = key(path.as_ref()) {
+            self.files.write().unwrap().insert(key, contents.into());
+        }
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        key(path)
+            .and_then(|key| self.files.read().unwrap().get(&key).cloned())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        key(path).is_some_and(|key| self.files.read().unwrap().contains_key(&key))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        let files = self.files.read().unwrap();
+        key(path).is_some_and(|key| contains_dir(files.keys().map(String::as_str), &key))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8], append: bool) -> io::Result<()> {
+        let key = key(path).ok_or_else(|| not_found(path))?;
+        let mut files = self.files.write().unwrap();
+        let file = files.entry(key).or_default();
+        if !append {
+            file.clear();
+        }
+        file.extend_from_slice(contents);
+        Ok(())
+    }
+}
+
+/// The files under a directory of the disk.
+#[derive(Debug, Clone)]
+pub struct DirVfs {
+    root: PathBuf,
+    writable: bool,
+}
+
+impl DirVfs {
+    /// Read-only view of `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            writable: false,
+        }
+    }
+
+    pub fn writable(mut self, writable: bool) -> Self {
+        self.writable = writable;
+        self
+    }
+
+    /// `path` on the disk, `None` if it leaves the root, symbolic links included.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let joined = self.root.join(key(path)?);
+        let root = self.root.canonicalize().ok()?;
+        let resolved = match joined.canonicalize() {
+            Ok(resolved) => resolved,
+            // A dangling link would be followed when creating the file
+            Err(_) if joined.symlink_metadata().is_ok() => return None,
+            // Not created yet (writes), its directory must exist
+            Err(_) => joined
+                .parent()?
+                .canonicalize()
+                .ok()?
+                .join(joined.file_name()?),
+        };
+        resolved.starts_with(&root).then_some(resolved)
+    }
+}
+
+impl Vfs for DirVfs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(self.resolve(path).ok_or_else(|| not_found(path))?)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.resolve(path).is_some_and(|path| path.is_file())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.resolve(path).is_some_and(|path| path.is_dir())
+    }
+
+    fn write(&self, path: &Path, contents: &[u8], append: bool) -> io::Result<()> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is on a read-only filesystem", path.display()),
+            ));
+        }
+        let resolved = self.resolve(path).ok_or_else(|| not_found(path))?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(resolved)?;
+        io::Write::write_all(&mut file, contents)
+    }
+}
+
+/// Layers of filesystems, the first layer holding a file wins. Writes go to the first layer.
+#[derive(Debug, Clone, Default)]
+pub struct OverlayVfs {
+    layers: Vec<Arc<dyn Vfs>>,
+}
+
+impl OverlayVfs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a layer below the existing ones.
+    pub fn layer(self, vfs: impl Vfs + 'static) -> Self {
+        self.shared_layer(Arc::new(vfs))
+    }
+
+    pub fn shared_layer(mut self, vfs: Arc<dyn Vfs>) -> Self {
+        self.layers.push(vfs);
+        self
+    }
+}
+
+impl Vfs for OverlayVfs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.layers
+            .iter()
+            .find(|layer| layer.is_file(path))
+            .ok_or_else(|| not_found(path))?
+            .read(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.layers.iter().any(|layer| layer.is_file(path))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.layers.iter().any(|layer| layer.is_dir(path))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8], append: bool) -> io::Result<()> {
+        let top = self.layers.first().ok_or_else(|| not_found(path))?;
+        if append && !top.is_file(path) {
+            // Copy up from the layer holding the file
+            if let Ok(existing) = self.read(path) {
+                top.write(path, &existing, false)?;
+            }
+        }
+        top.write(path, contents, append)
+    }
+}
+
+/// The files of a zip archive, read-only. Needs the `zip` feature.
+#[cfg(feature = "zip")]
+pub struct ZipVfs {
+    archive: Mutex<zip::ZipArchive<std::fs::File>>,
+    names: Vec<String>,
+}
+
+#[cfg(feature = "zip")]
+impl ZipVfs {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+        let names = archive
+            .file_names()
+            .filter(|name| !name.ends_with('/'))
+            .map(str::to_string)
+            .collect();
+        Ok(Self {
+            archive: Mutex::new(archive),
+            names,
+        })
+    }
+}
+
+#[cfg(feature = "zip")]
+impl fmt::Debug for ZipVfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZipVfs")
+            .field("files", &self.names.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "zip")]
+impl Vfs for ZipVfs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let key = key(path).ok_or_else(|| not_found(path))?;
+        let mut archive = self.archive.lock().unwrap();
+        let mut file = archive.by_name(&key).map_err(|_| not_found(path))?;
+        let mut contents = Vec::with_capacity(file.size() as usize);
+        io::Read::read_to_end(&mut file, &mut contents)?;
+        Ok(contents)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        key(path).is_some_and(|key| self.names.contains(&key))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        key(path).is_some_and(|key| contains_dir(self.names.iter().map(String::as_str), &key))
+    }
+}
+
+/// The sources and assets of a pack, read-only.
+impl Vfs for SourcePack {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        key(path)
+            .and_then(|key| self.file(&key))
+            .map(|file| file.content.clone())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        key(path).is_some_and(|key| self.file(&key).is_some())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        key(path).is_some_and(|key| contains_dir(self.files().map(|(path, _)| path), &key))
+    }
+}
+
+struct Served {
+    // `None` for a file shard the Vfs does not serve
+    shard: Option<&'static str>,
+    vfs: Arc<dyn Vfs>,
+    // Output of the last activation, alive until the next one
+    output: Mutex<Vec<u8>>,
+}
+
+/// File shards of a wire served by a [`Vfs`], restored when dropped.
+pub(crate) struct Mount {
+    _interposition: Interposition,
+}
+
+impl Mount {
+    /// Serve the shards of `wire` listed in [`VFS_SHARDS`] from `vfs`, the other file shards
+    /// fail. Must happen after the wire was composed.
+    pub(crate) fn apply(wire: &Wire, vfs: &Arc<dyn Vfs>) -> Self {
+        let hooks = shards_of(wire).into_iter().filter_map(|shard| {
+            let name = unsafe { shard_name(shard) };
+            let known = VFS_SHARDS.iter().find(|known| **known == name).copied();
+            if known.is_none() && module_of(&name) != FILE_MODULE {
+                return None;
+            }
+            let served = Served {
+                shard: known,
+                vfs: vfs.clone(),
+                output: Default::default(),
+            };
+            Some((shard, served))
+        });
+
+        Mount {
+            _interposition: Interposition::install(hooks),
+        }
+    }
+}
+
+impl Hook for Served {
+    // Replaces the shard, `next` is not called
+    unsafe fn activate(
+        &self,
+        shard: *mut Shard,
+        context: *mut SHContext,
+        input: *const SHVar,
+        _next: Next,
+    ) -> SHVar {
+        let Served {
+            shard: name,
+            vfs,
+            output,
+        } = self;
+        let Some(name) = *name else {
+            let message = format!(
+                "{}: not available with a virtual filesystem, only {} are",
+                shard_name(shard),
+                VFS_SHARDS.join(", ")
+            );
+            abort(context, &message);
+            return SHVar::default();
+        };
+        let Some(path) = var_string(&*input) else {
+            abort(context, &format!("{}: expected a path as input", name));
+            return SHVar::default();
+        };
+        let path = Path::new(&path);
+
+        match name {
+            "FS.IsFile" => Var::from(vfs.is_file(path)),
+            "FS.IsDirectory" => Var::from(vfs.is_dir(path)),
+            "FS.Read" => {
+                let contents = match vfs.read(path) {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        abort(context, &format!("{}: {}", name, e));
+                        return SHVar::default();
+                    }
+                };
+                let bytes =
+                    resolved_param(shard, context, "Bytes").is_some_and(|bytes| var_bool(&bytes));
+                let mut output = output.lock().unwrap();
+                *output = contents;
+                if bytes {
+                    return Var::from(output.as_slice());
+                }
+                if std::str::from_utf8(&output).is_err() {
+                    abort(
+                        context,
+                        &format!("{}: {} is not valid UTF-8", name, path.display()),
+                    );
+                    return SHVar::default();
+                }
+                // Null terminated, the core accepts both
+                let len = output.len();
+                output.push(0);
+                let mut var = SHVar::default();
+                var.valueType = shards::shardsc::SHType_String;
+                var.payload.__bindgen_anon_1.__bindgen_anon_1.stringValue =
+                    output.as_ptr() as *const _;
+                var.payload.__bindgen_anon_1.__bindgen_anon_1.stringLen = len as _;
+                var
+            }
+            "FS.Write" => {
+                let Some(contents) = resolved_param(shard, context, "Contents") else {
+                    abort(context, &format!("{}: missing Contents", name));
+                    return SHVar::default();
+                };
+                let contents = match var_string(&contents) {
+                    Some(text) => text.into_bytes(),
+                    None => match <&[u8]>::try_from(&contents) {
+                        Ok(bytes) => bytes.to_vec(),
+                        Err(_) => {
+                            abort(
+                                context,
+                                &format!("{}: Contents must be a string or bytes", name),
+                            );
+                            return SHVar::default();
+                        }
+                    },
+                };
+                let overwrite =
+                    resolved_param(shard, context, "Overwrite").is_some_and(|v| var_bool(&v));
+                let append = resolved_param(shard, context, "Append").is_some_and(|v| var_bool(&v));
+                if vfs.is_file(path) && !overwrite && !append {
+                    abort(
+                        context,
+                        &format!("{}: {} already exists", name, path.display()),
+                    );
+                    return SHVar::default();
+                }
+                if let Err(e) = vfs.write(path, &contents, append) {
+                    abort(context, &format!("{}: {}", name, e));
+                    return SHVar::default();
+                }
+                *input
+            }
+            _ => {
+                abort(context, &format!("not a vfs shard: {}", name));
+                SHVar::default()
+            }
+        }
+    }
+}
+
+unsafe fn var_bool(var: &SHVar) -> bool {
+    var.valueType == shards::shardsc::SHType_Bool && var.payload.__bindgen_anon_1.boolValue
+}